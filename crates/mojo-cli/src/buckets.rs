@@ -1,8 +1,8 @@
 use anyhow::Error;
-use mojokv::BucketMap;
+use mojokv::{BucketMap, FileCache};
 
 pub fn cmd(kvpath: &std::path::Path, ver: u32) -> Result<(), Error> {
-    let bmap = BucketMap::load(kvpath, ver)?;    
+    let bmap = BucketMap::load(kvpath, ver, &FileCache::default())?;    
 
     for (bucket_name, ver) in bmap.map()?.iter() {
         println!("{} -> {}", bucket_name, ver);
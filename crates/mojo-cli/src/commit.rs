@@ -1,11 +1,13 @@
 use anyhow::Error;
 use mojokv::Store;
+use std::time::Duration;
 
-pub fn cmd(kvpath: &std::path::Path) -> Result<(), Error> {
-    let st = Store::writable(&kvpath, false, None, None)?;
+pub fn cmd(kvpath: &std::path::Path, wait_ms: u64) -> Result<(), Error> {
+    let st = Store::writable(kvpath, false, None, None)?;
 
     println!("active version before commit: {}", st.active_ver());
-    let new_ver = st.commit()?;
+    let mut dblock = st.db_lock()?;
+    let new_ver = st.commit_txn(&mut dblock, Duration::from_millis(wait_ms))?;
     println!("active version after commit: {}", new_ver);
     Ok(())
-}
\ No newline at end of file
+}
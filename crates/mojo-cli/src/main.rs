@@ -4,6 +4,8 @@ mod iview;
 mod state;
 mod commit;
 mod buckets;
+mod pack;
+mod txns;
 
 use anyhow::Error;
 use clap::{Parser, Subcommand};
@@ -57,6 +59,13 @@ enum Commands {
     /// Commit the store
     #[clap(name="commit")]
     Commit{
+        /// Milliseconds to wait for a write transaction to finish
+        #[clap(short, long, value_parser, default_value_t = 5000)]
+        wait: u64,
+    },
+    /// Pack the files of committed versions into a pack file
+    #[clap(name="pack")]
+    Pack{
     },
     /// List buckets
     #[clap(name="buckets")]
@@ -66,6 +75,15 @@ enum Commands {
         #[clap(value_parser)]
         ver: u32,
     },
+    /// List the transactions in the txn log of a bucket
+    #[clap(name="txns")]
+    Txns{
+        #[clap(value_parser)]
+        bucket: String,
+
+        #[clap(value_parser)]
+        ver: u32,
+    },
 }
 
 fn main() -> Result<(), Error> {
@@ -86,12 +104,18 @@ fn main() -> Result<(), Error> {
             state::cmd(&cli.kvpath, *additional)?;
         },
 
-        Commands::Commit{} => {
-            commit::cmd(&cli.kvpath)?;
+        Commands::Commit{wait} => {
+            commit::cmd(&cli.kvpath, *wait)?;
+        },
+        Commands::Pack{} => {
+            pack::cmd(&cli.kvpath)?;
         },
         Commands::Buckets{ver} => {
             buckets::cmd(&cli.kvpath, *ver)?;
         },
+        Commands::Txns{bucket, ver} => {
+            txns::cmd(&cli.kvpath, bucket.as_str(), *ver)?;
+        },
     }
 
     Ok(())
@@ -0,0 +1,19 @@
+use anyhow::Error;
+use mojokv::Store;
+
+pub fn cmd(kvpath: &std::path::Path) -> Result<(), Error> {
+    let st = Store::writable(kvpath, false, None, None)?;
+
+    let stats = st.pack()?;
+    match stats.pack_path {
+        Some(path) => {
+            println!("pack file       : {:?}", path);
+            println!("files packed    : {}", stats.files);
+            println!("bytes packed    : {}", stats.bytes);
+        },
+        None => {
+            println!("Nothing to pack");
+        }
+    }
+    Ok(())
+}
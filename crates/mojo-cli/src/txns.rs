@@ -0,0 +1,18 @@
+use anyhow::Error;
+use mojokv::Store;
+
+pub fn cmd(kvpath: &std::path::Path, bucket: &str, ver: u32) -> Result<(), Error> {
+    let st = Store::readonly(kvpath, ver)?;
+
+    let txns = st.txns(bucket, ver)?;
+    if txns.is_empty() {
+        println!("No txn log for bucket={} at ver={}", bucket, ver);
+        return Ok(());
+    }
+
+    println!("{:>8} {:>12} {:>10} {:>8}", "txn", "time", "max_key", "pages");
+    for t in txns.iter() {
+        println!("{:>8} {:>12} {:>10} {:>8}", t.seq, t.time, t.max_key, t.pages.len());
+    }
+    Ok(())
+}
@@ -0,0 +1,75 @@
+//! sqlite routines called by mojofs. Loaded as an extension mojofs runs in
+//! a host sqlite other than the bundled one, so the entry point of the
+//! extension hands over the routines of the host before registering
+//! anything.
+
+use std::os::raw::{c_char, c_int, c_uchar, c_void};
+use std::sync::OnceLock;
+use libsqlite3_sys::{sqlite3, sqlite3_context, sqlite3_int64, sqlite3_module, sqlite3_value};
+
+/// Routines of the sqlite mojofs runs in, same layout as `MojoApi` in
+/// `mojofs.h`
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct MojoApi {
+    pub malloc64: unsafe extern "C" fn(u64) -> *mut c_void,
+    pub free: unsafe extern "C" fn(*mut c_void),
+    pub create_module: unsafe extern "C" fn(*mut sqlite3, *const c_char, *const sqlite3_module, *mut c_void) -> c_int,
+    pub declare_vtab: unsafe extern "C" fn(*mut sqlite3, *const c_char) -> c_int,
+    pub result_null: unsafe extern "C" fn(*mut sqlite3_context),
+    pub result_int64: unsafe extern "C" fn(*mut sqlite3_context, sqlite3_int64),
+    pub result_text: unsafe extern "C" fn(*mut sqlite3_context, *const c_char, c_int, Option<unsafe extern "C" fn(*mut c_void)>),
+    pub value_type: unsafe extern "C" fn(*mut sqlite3_value) -> c_int,
+    pub value_int64: unsafe extern "C" fn(*mut sqlite3_value) -> sqlite3_int64,
+    pub value_text: unsafe extern "C" fn(*mut sqlite3_value) -> *const c_uchar,
+    pub file_control: unsafe extern "C" fn(*mut sqlite3, *const c_char, c_int, *mut c_void) -> c_int,
+    // Null if the host is built without the compile option diagnostics
+    pub compileoption_used: Option<unsafe extern "C" fn(*const c_char) -> c_int>,
+}
+
+static API: OnceLock<MojoApi> = OnceLock::new();
+
+/// Routines set by the extension, the ones of the bundled sqlite otherwise
+pub fn api() -> &'static MojoApi {
+    API.get_or_init(|| MojoApi {
+        malloc64: libsqlite3_sys::sqlite3_malloc64,
+        free: libsqlite3_sys::sqlite3_free,
+        create_module: libsqlite3_sys::sqlite3_create_module,
+        declare_vtab: libsqlite3_sys::sqlite3_declare_vtab,
+        result_null: libsqlite3_sys::sqlite3_result_null,
+        result_int64: libsqlite3_sys::sqlite3_result_int64,
+        result_text: libsqlite3_sys::sqlite3_result_text,
+        value_type: libsqlite3_sys::sqlite3_value_type,
+        value_int64: libsqlite3_sys::sqlite3_value_int64,
+        value_text: libsqlite3_sys::sqlite3_value_text,
+        file_control: libsqlite3_sys::sqlite3_file_control,
+        compileoption_used: Some(libsqlite3_sys::sqlite3_compileoption_used),
+    })
+}
+
+/// True if the sqlite mojofs runs in is built with
+/// `SQLITE_ENABLE_BATCH_ATOMIC_WRITE`. The bundled sqlite only is when
+/// `LIBSQLITE3_FLAGS` sets it at build time.
+pub fn batch_atomic_supported() -> bool {
+    match api().compileoption_used {
+        Some(used) => unsafe { used(c"ENABLE_BATCH_ATOMIC_WRITE".as_ptr()) != 0 },
+        None => false,
+    }
+}
+
+/// Sets the routines of the host sqlite. Only the first call counts.
+///
+/// # Safety
+/// `api` must point to a `MojoApi` filled with the routines of the host.
+#[no_mangle]
+pub unsafe extern "C" fn mojo_set_api(api: *const MojoApi) -> c_int {
+    match api.as_ref() {
+        Some(api) => {
+            if API.set(*api).is_err() {
+                log::debug!("mojo_set_api: routines already set");
+            }
+            libsqlite3_sys::SQLITE_OK
+        },
+        None => libsqlite3_sys::SQLITE_MISUSE,
+    }
+}
@@ -10,31 +10,78 @@ pub const MOJOFS_ERR_LARGE_PAGE: i32 = 8;
 pub const MOJOFS_ERR_ARG_VER_MISSING: i32 = 9;
 pub const MOJOFS_ERR_ARG_PAGESZ_MISSING: i32 = 10;
 pub const MOJOFS_ERR_ARG_PPS_MISSING: i32 = 11;
+pub const MOJOFS_ERR_ARG_LOCK: i32 = 12;
+pub const MOJOFS_ERR_SHM_NOT_OPEN: i32 = 13;
+pub const MOJOFS_ERR_ARG_FILE_POLICY: i32 = 14;
+pub const MOJOFS_ERR_NO_STORE: i32 = 15;
+pub const MOJOFS_ERR_PRAGMA: i32 = 16;
+pub const MOJOFS_ERR_BUSY: i32 = 17;
+pub const MOJOFS_ERR_NOT_MOJO: i32 = 18;
+pub const MOJOFS_ERR_SQLITE: i32 = 19;
+pub const MOJOFS_ERR_ARG_TXN: i32 = 20;
+pub const MOJOFS_ERR_BATCH: i32 = 21;
+pub const MOJOFS_ERR_PANIC: i32 = 22;
+pub const MOJOFS_ERR_FFI_ARG: i32 = 23;
+pub const MOJOFS_ERR_PATH_OUTSIDE_STORE: i32 = 24;
+pub const MOJOFS_ERR_ARG_CONFLICT: i32 = 25;
 
 #[derive(thiserror::Error, Debug)]
 pub struct Error {
     pub code: i32,
     pub msg: String,
+    // sqlite result code of the error, None to use the one of the call
+    pub rc: Option<i32>,
+    // os error behind the error, reported by xGetLastError
+    pub errno: i32,
 }
 
 impl Error {
     pub fn new(code: i32, msg: String) -> Self {
+        let rc = match code {
+            MOJOFS_ERR_BUSY => Some(libsqlite3_sys::SQLITE_BUSY),
+            _ => None,
+        };
+
         Error {
             code,
             msg,
+            rc,
+            errno: 0,
         }
     }
 
     pub fn not_impl() -> Self {
         Error::new(MOJOFS_ERR_NOT_IMPL, "Not implemented".to_owned())
     }
+
+    /// sqlite result code for the error. `default` is the code of the
+    /// failed call, used when the error does not have a code of its own.
+    pub fn sqlite_code(&self, default: i32) -> i32 {
+        self.rc.unwrap_or(default)
+    }
+}
+
+// Disk full is reported as such, other os errors keep the code of the call
+fn errno_rc(errno: i32) -> Option<i32> {
+    match nix::errno::Errno::from_i32(errno) {
+        nix::errno::Errno::ENOSPC | nix::errno::Errno::EDQUOT => Some(libsqlite3_sys::SQLITE_FULL),
+        nix::errno::Errno::EROFS => Some(libsqlite3_sys::SQLITE_READONLY),
+        _ => None,
+    }
+}
+
+fn io_errno(err: &std::io::Error) -> i32 {
+    err.raw_os_error().unwrap_or(0)
 }
 
 impl From<std::io::Error> for Error {
     fn from(err: std::io::Error) -> Self {
+        let errno = io_errno(&err);
         Error { 
             code: MOJOFS_ERR_IO,
             msg: format!("{:?}", err),
+            rc: errno_rc(errno),
+            errno,
         }
     }
 }
@@ -44,37 +91,72 @@ impl From<nix::Error> for Error {
         Error { 
             code: MOJOFS_ERR_NIX,
             msg: err.to_string(),
+            rc: errno_rc(err as i32),
+            errno: err as i32,
         }
     }
 }
 
 impl From<std::str::Utf8Error> for Error {
     fn from(err: std::str::Utf8Error) -> Self {
-        Error { 
-            code: MOJOFS_ERR_UTF8,
-            msg: err.to_string(),
-        }
+        Error::new(MOJOFS_ERR_UTF8, err.to_string())
     }
 }
 
 impl From<std::num::ParseIntError> for Error {
     fn from(err: std::num::ParseIntError) -> Self {
-        Error { 
-            code: MOJOFS_ERR_INT_PARSE,
-            msg: err.to_string(),
-        }
+        Error::new(MOJOFS_ERR_INT_PARSE, err.to_string())
     }
 }
 
 impl From<mojokv::Error> for Error {
     fn from(err: mojokv::Error) -> Self {
+        use mojokv::Error as E;
+
+        let errno = match &err {
+            E::IoErr(e) => io_errno(e),
+            E::MojoFileErr(mojoio::Error::IoErr(e)) => io_errno(e),
+            E::MojoFileErr(mojoio::Error::NixErr(e)) => *e as i32,
+            _ => 0,
+        };
+
+        let rc = match &err {
+            E::StoreNotWritableErr | E::BucketNotWritableErr | E::VerNotWritable(_, _) => Some(libsqlite3_sys::SQLITE_READONLY),
+            E::CommitLockedErr | E::DbBusyErr | E::ShmBusyErr => Some(libsqlite3_sys::SQLITE_BUSY),
+            E::StoreNotFoundErr | E::VersionNotFoundErr(_) | E::BucketNotAtVerErr(_, _) | E::TxnNotFoundErr(_, _) => Some(libsqlite3_sys::SQLITE_CANTOPEN),
+            E::CorruptErr(_) | E::RmpDecodeErr(_) | E::SerdeJsonErr(_) => Some(libsqlite3_sys::SQLITE_CORRUPT),
+            _ => errno_rc(errno),
+        };
+
+        // The messages of the wrapping variants leave out the cause
+        let msg = match std::error::Error::source(&err) {
+            Some(src) => format!("{}: {}", err, src),
+            None => err.to_string(),
+        };
+
         Error { 
             code: MOJOFS_ERR_MOJOKV,
-            msg: err.to_string(),
+            msg,
+            rc,
+            errno,
         }
     }
 }
 
+thread_local! {
+    static LAST_ERROR: std::cell::RefCell<Option<(i32, String)>> = const { std::cell::RefCell::new(None) };
+}
+
+/// Records the error as the last error of the thread, see xGetLastError
+pub fn set_last_error(err: &Error) {
+    LAST_ERROR.with(|e| *e.borrow_mut() = Some((err.errno, err.msg.clone())));
+}
+
+/// errno and message of the last error of the thread
+pub fn last_error() -> Option<(i32, String)> {
+    LAST_ERROR.with(|e| e.borrow().clone())
+}
+
 impl std::fmt::Display for Error {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "({}, {})", self.code, self.msg)
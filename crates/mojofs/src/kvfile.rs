@@ -1,102 +1,388 @@
 
-use mojokv::Bucket;
+use std::sync::{Arc, Weak};
+use parking_lot::Mutex;
+use mojokv::{Bucket, PageRef, Store};
 use crate::Error;
+use crate::open_options::OpenKind;
 
-pub struct KVFile {
-    pub bucket: Bucket,
-    opt: KVFileOpt,
+/// Bucket of a kv file. Commits made through the other files of the store
+/// sync it too.
+pub type SharedBucket = Arc<Mutex<Bucket>>;
+
+/// Buckets of the kv files open in a store
+#[derive(Clone, Default)]
+pub struct OpenBuckets(Arc<Mutex<Vec<Weak<Mutex<Bucket>>>>>);
+
+impl OpenBuckets {
+    pub fn add(&self, bucket: &SharedBucket) {
+        let mut buckets = self.0.lock();
+        buckets.retain(|b| b.strong_count() > 0);
+        buckets.push(Arc::downgrade(bucket));
+    }
+
+    /// Syncs the writable buckets other than `skip` which have writes not
+    /// synced yet. Buckets opened before the last commit are synced at the
+    /// active version.
+    pub fn sync(&self, store: &Store, skip: &SharedBucket) -> Result<(), Error> {
+        let buckets: Vec<SharedBucket> = self.0.lock().iter().filter_map(|b| b.upgrade()).collect();
+
+        for bucket in buckets.iter().filter(|b| !Arc::ptr_eq(b, skip)) {
+            let mut bucket = bucket.lock();
+            if bucket.is_stale() {
+                store.roll_forward(&mut bucket)?;
+            }else if bucket.is_write() && bucket.is_dirty() {
+                bucket.sync()?;
+            }
+        }
+
+        Ok(())
+    }
 }
 
-#[derive(Clone)]
-pub struct KVFileOpt {
-    pub page_sz: u32,
-    pub pps: u32,
-    pub ver: u32,
+// Start of a record sqlite writes in parts, e.g. the header and the page
+// of a wal frame, kept until it fills its block
+struct Pending {
+    key: u32,
+    buf: Vec<u8>,
+}
+
+/// A file kept in a bucket. The header of a journal or the wal is kept in
+/// the first block and each record after it in a block of its own, the
+/// bucket offsets of the records are past the header by the rest of that
+/// block.
+pub struct KVFile {
+    pub bucket: SharedBucket,
+    fetched: Vec<(i64, PageRef)>,
+    header_sz: u64,
+    records: bool,
+    pending: Mutex<Option<Pending>>,
 }
 
 
 impl KVFile {
-    pub fn open(bucket: Bucket, opt: KVFileOpt) -> Result<Self, Error> {
+    pub fn open(bucket: Bucket, kind: OpenKind) -> Result<Self, Error> {
         Ok(KVFile{
-            bucket,
-            opt,
+            bucket: Arc::new(Mutex::new(bucket)),
+            fetched: Vec::new(),
+            header_sz: kind.header_size(),
+            records: !kind.is_db(),
+            pending: Mutex::new(None),
         })
     }
 
+    /// Page size of the file, zero if the store has not learnt it yet
+    pub fn page_size(&self) -> u32 {
+        self.bucket.lock().page_size()
+    }
+
+    // Bytes of the header kept in the first block, none if the block is
+    // too small to hold it
+    fn header_len(&self, page_sz: u64) -> u64 {
+        if self.header_sz <= page_sz { self.header_sz } else { 0 }
+    }
+
+    // Offset in the bucket of file offset `off`. Headers sqlite writes in
+    // the middle of a journal, after syncing it while the transaction is
+    // still open, are not aligned and shift the records after them.
+    fn bucket_off(&self, off: u64, page_sz: u64) -> u64 {
+        let hdr_len = self.header_len(page_sz);
+        if hdr_len == 0 || off < hdr_len { off } else { off - hdr_len + page_sz }
+    }
+
+    // Length of the file holding `size` bytes of the bucket
+    fn file_size(&self, size: u64, page_sz: u64) -> u64 {
+        let hdr_len = self.header_len(page_sz);
+        if hdr_len == 0 || size <= hdr_len {
+            return size;
+        }
+        hdr_len.max(size.saturating_sub(page_sz - hdr_len))
+    }
+
+    // Writes the start of a record which did not fill its block
+    fn flush(&self) -> Result<(), Error> {
+        if let Some(p) = self.pending.lock().take() {
+            log::debug!("kv flush partial record key={} blen={}", p.key, p.buf.len());
+            self.bucket.lock().put(p.key, 0, &p.buf)?;
+        }
+        Ok(())
+    }
+
+    /// Reads at any offset and length. Pages never written read as zeros,
+    /// as does the part of the buffer past the end of file, which is not
+    /// counted in the returned length.
     pub fn pread(&self, buf: &mut [u8], off: i64) -> Result<usize, Error> {
         log::debug!("kv pread o={}, blen={}", off, buf.len());
 
-        if buf.len() > self.opt.page_sz as usize {
-            return Err(Error::new(crate::MOJOFS_ERR_LARGE_PAGE, 
-                format!("buf larger ({}) than page size", buf.len())));
+        // Nothing has been written without a page size
+        let page_sz = self.page_size() as u64;
+        if page_sz == 0 {
+            buf.fill(0);
+            return Ok(0);
         }
 
-        let page_off = off % self.opt.page_sz as i64;
-        let key = off / self.opt.page_sz as i64;
+        self.flush()?;
+
+        // The header and the records are apart in the bucket
+        let off = off as u64;
+        let hdr_len = self.header_len(page_sz);
+        if off < hdr_len && off + buf.len() as u64 > hdr_len {
+            let (head, tail) = buf.split_at_mut((hdr_len - off) as usize);
+            let n = self.pread_at(head, off)?;
+            return Ok(n + self.pread_at(tail, page_sz)?);
+        }
+
+        self.pread_at(buf, self.bucket_off(off, page_sz))
+    }
+
+    fn pread_at(&self, buf: &mut [u8], off: u64) -> Result<usize, Error> {
+        let size = self.bucket.lock().logical_size();
+        let n = (size.saturating_sub(off) as usize).min(buf.len());
+        buf[n..].fill(0);
+        if n == 0 {
+            return Ok(0);
+        }
+
+        let page_sz = self.page_size() as usize;
+        let mut key = (off / page_sz as u64) as u32;
+        let mut rest = &mut buf[..n];
+
+        // Leading partial page
+        let po = off as usize % page_sz;
+        if po != 0 || rest.len() < page_sz {
+            let len = rest.len().min(page_sz - po);
+            let (head, tail) = rest.split_at_mut(len);
+            self.pread_page(key, po as u64, head)?;
+            rest = tail;
+            key += 1;
+        }
+
+        // Whole pages
+        let full = rest.len() / page_sz * page_sz;
+        if full > 0 {
+            let (pages, tail) = rest.split_at_mut(full);
+            let mut bufs: Vec<&mut [u8]> = pages.chunks_mut(page_sz).collect();
+            self.bucket.lock().get_many(key, &mut bufs)?;
+            rest = tail;
+            key += (full / page_sz) as u32;
+        }
 
-        let n = match self.bucket.get(key as u32, page_off as u64, buf) {
+        // Trailing partial page
+        if !rest.is_empty() {
+            self.pread_page(key, 0, rest)?;
+        }
+
+        Ok(n)
+    }
+
+    fn pread_page(&self, key: u32, page_off: u64, buf: &mut [u8]) -> Result<(), Error> {
+        let n = match self.bucket.lock().get(key, page_off, buf) {
             Ok(n) => n,
-            Err(err) => {
-                if let mojokv::Error::KeyNotFoundErr(_) = err {
-                    0
-                }else{
-                    return Err(err.into());
-                }
-            }
+            Err(mojokv::Error::KeyNotFoundErr(_)) => 0,
+            Err(err) => return Err(err.into()),
         };
 
-        if n<buf.len() {
-            log::debug!("after kv pread o={}, blen={} n={}", off, buf.len(), n);
-            let _ = &mut buf[n..].fill(0);
+        if n < buf.len() {
+            log::debug!("kv pread page key={} po={} blen={} n={}", key, page_off, buf.len(), n);
+            buf[n..].fill(0);
         }
 
-        Ok(n)
+        Ok(())
     }
 
+    // The parts of a record written one after the other from the start of
+    // its block are put at once, a whole block does not need the old one
     fn pwrite_page(&mut self, key: u32, page_off: u32, buf: &[u8]) -> Result<(), Error> {
         log::debug!("kv pwrite page key={}, po={} blen={}", key, page_off, buf.len());
 
-        self.bucket.put(key, page_off as u64, buf)?;
+        if self.records {
+            let page_sz = self.page_size() as usize;
+            let mut pending = self.pending.lock();
+            if let Some(p) = pending.as_mut().filter(|p| p.key == key && p.buf.len() == page_off as usize) {
+                p.buf.extend_from_slice(buf);
+                if p.buf.len() == page_sz {
+                    self.bucket.lock().put(key, 0, &p.buf)?;
+                    *pending = None;
+                }
+                return Ok(());
+            }
+
+            if let Some(p) = pending.take() {
+                self.bucket.lock().put(p.key, 0, &p.buf)?;
+            }
+
+            // The header block is written once
+            if page_off == 0 && key as u64 * page_sz as u64 >= self.header_len(page_sz as u64) {
+                *pending = Some(Pending{key, buf: buf.to_vec()});
+                return Ok(());
+            }
+        }
+
+        self.bucket.lock().put(key, page_off as u64, buf)?;
 
         Ok(())
     }
 
+    /// Writes at any offset and length. Runs of whole pages are written
+    /// together, partial pages are merged with what they held.
     pub fn pwrite(&mut self, off: i64, buf: &[u8]) -> Result<(), Error> {
         log::debug!("kv pwrite o={}, blen={}", off, buf.len());
 
-        let mut po = off % self.opt.page_sz as i64;
-        let mut key = off / self.opt.page_sz as i64;
-        let mut s = 0usize;
-        let buflen = buf.len();
+        let page_sz = self.page_size() as u64;
+        if page_sz == 0 {
+            return Err(mojokv::Error::PageSizeUnknownErr.into());
+        }
+
+        // The header and the records are apart in the bucket
+        let off = off as u64;
+        let hdr_len = self.header_len(page_sz);
+        if off < hdr_len && off + buf.len() as u64 > hdr_len {
+            let (head, tail) = buf.split_at((hdr_len - off) as usize);
+            self.pwrite_at(off, head)?;
+            return self.pwrite_at(page_sz, tail);
+        }
+
+        self.pwrite_at(self.bucket_off(off, page_sz), buf)
+    }
+
+    fn pwrite_at(&mut self, off: u64, buf: &[u8]) -> Result<(), Error> {
+        if buf.is_empty() {
+            return Ok(());
+        }
+
+        let page_sz = self.page_size() as usize;
+        let mut key = (off / page_sz as u64) as u32;
+        let mut rest = buf;
 
-        while s < buflen {
-            let e = (buflen-s).min(self.opt.page_sz as usize - po as usize);
-            self.pwrite_page(key as u32, po as u32, &buf[s..s+e])?;
-            s += e;
-            po = 0;
-            key += self.opt.page_sz as i64;
+        // Leading partial page
+        let po = off as usize % page_sz;
+        if po != 0 || rest.len() < page_sz {
+            let len = rest.len().min(page_sz - po);
+            self.pwrite_page(key, po as u32, &rest[..len])?;
+            rest = &rest[len..];
+            key += 1;
+        }
+
+        // Whole pages
+        let full = rest.len() / page_sz * page_sz;
+        if full > 0 {
+            self.flush()?;
+            let bufs: Vec<&[u8]> = rest[..full].chunks(page_sz).collect();
+            self.bucket.lock().put_many(key, &bufs)?;
+            rest = &rest[full..];
+            key += (full / page_sz) as u32;
+        }
+
+        // Trailing partial page
+        if !rest.is_empty() {
+            self.pwrite_page(key, 0, rest)?;
         }
 
         Ok(())
     }
 
+    /// Publishes the writes of an atomic batch. Only whole page writes can
+    /// be batched.
+    pub fn pwrite_batch(&mut self, writes: &[(u64, Vec<u8>)]) -> Result<(), Error> {
+        log::debug!("kv pwrite batch writes={}", writes.len());
+
+        if self.page_size() == 0 {
+            return Err(mojokv::Error::PageSizeUnknownErr.into());
+        }
+
+        self.flush()?;
+        let page_sz = self.page_size() as u64;
+        let mut pages = Vec::new();
+        for (off, buf) in writes.iter() {
+            if !off.is_multiple_of(page_sz) || !(buf.len() as u64).is_multiple_of(page_sz) {
+                return Err(Error::new(crate::MOJOFS_ERR_BATCH,
+                    format!("batch write o={} blen={} is not page aligned", off, buf.len())));
+            }
+
+            for (i, page) in buf.chunks(page_sz as usize).enumerate() {
+                pages.push(((off/page_sz) as u32 + i as u32, page));
+            }
+        }
+
+        self.bucket.lock().put_batch(&pages)?;
+        Ok(())
+    }
+
+    pub fn fetch(&mut self, off: i64, amt: usize) -> Result<Option<*const u8>, Error> {
+        // sqlite only maps the db
+        let page_sz = self.page_size() as i64;
+        if page_sz == 0 || self.records {
+            return Ok(None);
+        }
+
+        let page_off = off % page_sz;
+        let key = off / page_sz;
+
+        if page_off as usize + amt > page_sz as usize {
+            return Ok(None);
+        }
+
+        let page = match self.bucket.lock().fetch(key as u32, page_off as u64, amt)? {
+            Some(page) => page,
+            None => return Ok(None),
+        };
+
+        let ptr = page.as_ptr();
+        log::debug!("kv fetch o={} amt={} ptr={:?}", off, amt, ptr);
+        self.fetched.push((off, page));
+
+        Ok(Some(ptr))
+    }
+
+    pub fn unfetch(&mut self, off: i64, ptr: *const u8) {
+        log::debug!("kv unfetch o={} ptr={:?}", off, ptr);
+
+        if ptr.is_null() {
+            self.fetched.clear();
+            return;
+        }
+
+        if let Some(i) = self.fetched.iter().position(|(o, p)| *o == off && p.as_ptr() == ptr) {
+            self.fetched.swap_remove(i);
+        }
+    }
+
     pub fn close(self) -> Result<(), Error> {
-        self.bucket.close()?;
+        self.flush()?;
+
+        // A commit syncing the bucket right now drops it once done
+        if let Ok(bucket) = Arc::try_unwrap(self.bucket) {
+            bucket.into_inner().close()?;
+        }
         Ok(())
     }
 
     pub fn sync(&mut self) -> Result<(), Error> {
-        self.bucket.sync()?;
+        self.flush()?;
+        self.bucket.lock().sync()?;
         Ok(())
     }
 
     pub fn filesize(&self) -> Result<u64, Error> {
-        Ok(self.bucket.logical_size())
+        self.flush()?;
+        let size = self.bucket.lock().logical_size();
+        Ok(self.file_size(size, self.page_size() as u64))
     }
 
     pub fn truncate(&mut self, new_sz: u64) -> Result<(), Error> {
         log::debug!("kv truncate {}", new_sz);
-        self.bucket.truncate(new_sz as usize)?;
+        let page_sz = self.page_size() as u64;
+        if page_sz == 0 {
+            return Ok(());
+        }
+        self.flush()?;
+        let mut bucket = self.bucket.lock();
+        bucket.truncate(self.bucket_off(new_sz, page_sz) as usize)?;
+
+        // sqlite does not sync the wal after a TRUNCATE checkpoint, commits
+        // made through other files have to see it empty
+        if new_sz == 0 {
+            bucket.sync()?;
+        }
         Ok(())
     }
 }
@@ -4,22 +4,30 @@ pub mod error;
 pub mod open_options;
 pub mod vfsfile;
 mod native_file;
+mod mem_file;
 mod kvfile;
+mod vtab;
+mod api;
 
 use std::ffi::CStr;
+use std::os::unix::ffi::OsStrExt;
 use std::collections::HashMap;
+use parking_lot::Mutex;
 
 use libsqlite3_sys::{
     self,
+    sqlite3,
     sqlite3_file,
     sqlite3_vfs};
 
 use std::ffi::c_void;
 use std::os::raw::{c_int, c_char};
 pub use error::*;
-pub use vfs::{VFS,AccessCheck};
+pub use vfs::{VFS,AccessCheck,AutoCommit,FilePolicy,StoreKey,DbKey};
 pub use vfsfile::VFSFile;
 pub use open_options::*;
+pub use vtab::mojo_register_vtabs;
+pub use api::{MojoApi, mojo_set_api};
 
 
 #[repr(C)]
@@ -29,24 +37,71 @@ pub struct MojoFile {
     vfs: *mut sqlite3_vfs,
 }
 
+// Unwraps the result of an entry point or returns the error as a sqlite
+// result code, `rc` if it has none of its own
+macro_rules! try_rc {
+    ($res:expr, $rc:expr) => {
+        match $res {
+            Ok(v) => v,
+            Err(err) => return sqlite_rc(&err, $rc),
+        }
+    };
+}
+
+/// Creates the mojo vfs for the sqlite extension. It still has to be
+/// registered with `sqlite3_vfs_register`.
 #[no_mangle]
 pub extern "C" fn mojo_create() -> *mut sqlite3_vfs {
-    let mut name_buf = Vec::from("mojo".as_bytes());
+    create_vfs("mojo", VFS::default())
+}
+
+/// Registers a mojo vfs called `name` with sqlite. `default_options` are
+/// URI params (e.g. `pagesz`, `journal`) used when the URI does not give
+/// them. The mojo virtual tables are registered on every new connection.
+pub fn register(name: &str, default_options: &HashMap<String, String>) -> Result<(), Error> {
+    let fs = VFS::with_defaults(default_options.clone());
+    let vfs = create_vfs(name, fs);
+
+    let rc = unsafe {libsqlite3_sys::sqlite3_vfs_register(vfs, 0)};
+    if rc != libsqlite3_sys::SQLITE_OK {
+        return Err(Error::new(MOJOFS_ERR_SQLITE, format!("failed to register vfs {} rc={}", name, rc)));
+    }
+
+    static VTAB_INIT: std::sync::Once = std::sync::Once::new();
+    let mut rc = libsqlite3_sys::SQLITE_OK;
+    VTAB_INIT.call_once(|| {
+        // sqlite declares the entry point without args
+        rc = unsafe {
+            let init = std::mem::transmute::<AutoExtInit, unsafe extern "C" fn()>(vtab_auto_init);
+            libsqlite3_sys::sqlite3_auto_extension(Some(init))
+        };
+    });
+    if rc != libsqlite3_sys::SQLITE_OK {
+        return Err(Error::new(MOJOFS_ERR_SQLITE, format!("failed to register vtabs rc={}", rc)));
+    }
+
+    log::debug!("registered vfs name={}", name);
+    Ok(())
+}
+
+type AutoExtInit = unsafe extern "C" fn(*mut sqlite3, *mut *mut c_char, *const c_void) -> c_int;
+
+unsafe extern "C" fn vtab_auto_init(db: *mut sqlite3, _err: *mut *mut c_char, _api: *const c_void) -> c_int {
+    mojo_register_vtabs(db)
+}
+
+fn create_vfs(name: &str, fs: VFS) -> *mut sqlite3_vfs {
+    let mut name_buf = Vec::from(name.as_bytes());
     name_buf.push(0);
 
     let fs_name_c  = name_buf.as_ptr();
     std::mem::forget(name_buf);
 
-    let fs = Box::new(vfs::VFS::default());
+    // sqlite calls the vfs from any thread
+    let fs = Box::new(Mutex::new(fs));
     let p_app_data = Box::into_raw(fs) as *mut c_void;
 
-    //println!("p_app_data={:?}", p_app_data);
-
-    //let sql_vfs = unsafe {
-    //    let raw_ptr = sqlite3_malloc64(std::mem::size_of::<sqlite3_vfs>() as u64);
-    //    raw_ptr as *mut sqlite3_vfs
-    //};
-    let vfs = Box::into_raw(Box::new(sqlite3_vfs{
+    Box::into_raw(Box::new(sqlite3_vfs{
         iVersion: 1,
         szOsFile: (std::mem::size_of::<MojoFile>()) as i32,
         mxPathname: 512,
@@ -69,258 +124,414 @@ pub extern "C" fn mojo_create() -> *mut sqlite3_vfs {
         xSetSystemCall: None,
         xGetSystemCall: None,
         xNextSystemCall: None,
-    }));
-
-    vfs
+    }))
 }
 
 #[no_mangle]
 extern "C" fn mojo_open(vfs: *mut sqlite3_vfs, zname: *const c_char, file: *mut sqlite3_file, flags: c_int, out_flags: *mut c_int) -> c_int {
-    let fs = getfs(vfs);
-
-    let opt = open_options::OpenOptions::from_flags(flags as i32).unwrap();
-    let mut out_opt = opt.clone();
-    
-    let file_str = if zname.is_null() {
-        ""
-    }else{
-        let file_rs = unsafe{std::ffi::CStr::from_ptr(zname)};
-        match file_rs.to_str() {
-            Ok(file_str) => file_str,
-            Err(_err) => {
-                println!("mojo_open error in filepath");
-                return libsqlite3_sys::SQLITE_CANTOPEN
-            },
-        }
-    };
-
-    if opt.kind.is_main() {
-        let query_map = match extract_query_params(zname) {
-            Ok(map) => map,
-            Err(_err) => {
-            return libsqlite3_sys::SQLITE_CANTOPEN
+    guard("mojo_open", libsqlite3_sys::SQLITE_CANTOPEN, || {
+        let fs = try_rc!(getfs(vfs), libsqlite3_sys::SQLITE_CANTOPEN);
+        let mojo_file = try_rc!(get_mojo_file(file), libsqlite3_sys::SQLITE_CANTOPEN);
+
+        let opt = match open_options::OpenOptions::from_flags(flags) {
+            Some(opt) => opt,
+            None => {
+                log::error!("mojo_open unexpected flags={:#x}", flags);
+                let err = Error::new(MOJOFS_ERR_FFI_ARG, format!("unexpected open flags {:#x}", flags));
+                return sqlite_rc(&err, libsqlite3_sys::SQLITE_CANTOPEN);
+            }
+        };
+        let mut out_opt = opt.clone();
+
+        let file_str = if zname.is_null() {
+            ""
+        }else{
+            let file_rs = unsafe{std::ffi::CStr::from_ptr(zname)};
+            match file_rs.to_str() {
+                Ok(file_str) => file_str,
+                Err(err) => {
+                    log::error!("mojo_open error in filepath err={:?}", err);
+                    return sqlite_rc(&err.into(), libsqlite3_sys::SQLITE_CANTOPEN)
+                },
             }
         };
 
-        if let Err(err) = fs.init(file_str, &query_map, opt.clone()) {
-            log::error!("mojo_open init path={} err = {:?}", file_str, err);
-            return libsqlite3_sys::SQLITE_CANTOPEN
+        // The store is opened and the main db registered under one lock so
+        // that opens on other threads do not interleave
+        let mut fs = fs.lock();
+        let mut db = None;
+        if opt.kind.is_main() {
+            let query_map = match extract_query_params(zname) {
+                Ok(map) => map,
+                Err(err) => {
+                    log::error!("mojo_open query params err={:?}", err);
+                    return sqlite_rc(&err, libsqlite3_sys::SQLITE_CANTOPEN)
+                }
+            };
+
+            let query_map = fs.params(query_map);
+            match fs.init(file_str, &query_map, opt.clone()) {
+                Ok(dbkey) => db = Some(dbkey),
+                Err(err) => {
+                    log::error!("mojo_open init path={} err = {:?}", file_str, err);
+                    return sqlite_rc(&err, libsqlite3_sys::SQLITE_CANTOPEN)
+                }
+            }
         }
-    }
 
 
-    match fs.open(file_str, opt, &mut out_opt) {
-        Ok(vfs_file) => {
-            let mojo_file = unsafe {(file as *mut MojoFile).as_mut().unwrap()};
-            let io_methods = Box::into_raw(Box::new(libsqlite3_sys::sqlite3_io_methods{
-                iVersion: 1,
-                xClose: Some(mojo_close),
-                xRead: Some(mojo_read),
-                xWrite: Some(mojo_write),
-                xTruncate: Some(mojo_truncate),
-                xSync: Some(mojo_sync),
-                xFileSize: Some(mojo_filesize),
-                xLock: Some(mojo_lock),
-                xUnlock: Some(mojo_unlock),
-                xCheckReservedLock: Some(mojo_check_reserved_lock),
-                xFileControl: Some(mojo_file_control),
-                xSectorSize: Some(mojo_sector_size),
-                xDeviceCharacteristics: Some(mojo_device_char),
-                xShmMap: None,
-                xShmLock: None,
-                xShmBarrier: None,
-                xShmUnmap: None,
-                xFetch: None,
-                xUnfetch: None,
-            }));
-            mojo_file.base.pMethods = io_methods as *const libsqlite3_sys::sqlite3_io_methods;
-            mojo_file.custom_file = Box::into_raw(vfs_file) as *mut c_void;
-            mojo_file.vfs = vfs;
-        },
-        Err(err) => {
-            log::error!("mojo_open path={} err = {:?}", file_str, err);
-            return libsqlite3_sys::SQLITE_CANTOPEN;
+        match fs.open(file_str, db.as_ref(), opt, &mut out_opt) {
+            Ok(vfs_file) => {
+                let io_methods = Box::into_raw(Box::new(libsqlite3_sys::sqlite3_io_methods{
+                    iVersion: 3,
+                    xClose: Some(mojo_close),
+                    xRead: Some(mojo_read),
+                    xWrite: Some(mojo_write),
+                    xTruncate: Some(mojo_truncate),
+                    xSync: Some(mojo_sync),
+                    xFileSize: Some(mojo_filesize),
+                    xLock: Some(mojo_lock),
+                    xUnlock: Some(mojo_unlock),
+                    xCheckReservedLock: Some(mojo_check_reserved_lock),
+                    xFileControl: Some(mojo_file_control),
+                    xSectorSize: Some(mojo_sector_size),
+                    xDeviceCharacteristics: Some(mojo_device_char),
+                    xShmMap: Some(mojo_shm_map),
+                    xShmLock: Some(mojo_shm_lock),
+                    xShmBarrier: Some(mojo_shm_barrier),
+                    xShmUnmap: Some(mojo_shm_unmap),
+                    xFetch: Some(mojo_fetch),
+                    xUnfetch: Some(mojo_unfetch),
+                }));
+                mojo_file.base.pMethods = io_methods as *const libsqlite3_sys::sqlite3_io_methods;
+                mojo_file.custom_file = Box::into_raw(vfs_file) as *mut c_void;
+                mojo_file.vfs = vfs;
+            },
+            Err(err) => {
+                log::error!("mojo_open path={} err = {:?}", file_str, err);
+                return sqlite_rc(&err, libsqlite3_sys::SQLITE_CANTOPEN);
+            }
         }
-    }
 
-    unsafe {
-        if !out_flags.is_null() {
-            *out_flags = out_opt.flags;
-        }
-    };
+        unsafe {
+            if !out_flags.is_null() {
+                *out_flags = out_opt.flags;
+            }
+        };
 
-    libsqlite3_sys::SQLITE_OK
+        libsqlite3_sys::SQLITE_OK
+    })
 }
 
 
 #[no_mangle]
 extern "C" fn mojo_close(sfile: *mut sqlite3_file) -> c_int {
-    unsafe {
-        let mojo_file = (sfile as *mut MojoFile).as_ref().unwrap();
-        let vfs_file = Box::from_raw(mojo_file.custom_file as *mut VFSFile);
-        let fs = getfs(mojo_file.vfs);
-        match fs.close(*vfs_file) {
-            Ok(_) => {},
-            Err(_err) => {
-                return libsqlite3_sys::SQLITE_IOERR_CLOSE
+    guard("mojo_close", libsqlite3_sys::SQLITE_IOERR_CLOSE, || {
+        let mojo_file = try_rc!(get_mojo_file(sfile), libsqlite3_sys::SQLITE_IOERR_CLOSE);
+        let fs = try_rc!(getfs(mojo_file.vfs), libsqlite3_sys::SQLITE_IOERR_CLOSE);
+        if mojo_file.custom_file.is_null() {
+            return sqlite_rc(&null_arg("file"), libsqlite3_sys::SQLITE_IOERR_CLOSE);
+        }
+
+        // The file is gone even if closing fails
+        let vfs_file = unsafe {Box::from_raw(mojo_file.custom_file as *mut VFSFile)};
+        mojo_file.custom_file = std::ptr::null_mut();
+
+        match fs.lock().close(*vfs_file) {
+            Ok(_) => libsqlite3_sys::SQLITE_OK,
+            Err(err) => {
+                log::error!("mojo_close err={:?}", err);
+                sqlite_rc(&err, libsqlite3_sys::SQLITE_IOERR_CLOSE)
             }
         }
-    }
-    libsqlite3_sys::SQLITE_OK
+    })
 }
 
 #[no_mangle]
 extern "C" fn mojo_read(sfile: *mut sqlite3_file, ptr: *mut c_void, n: i32, off: i64) -> c_int {
-    let file = get_file(sfile);
-    let buf = unsafe{ std::slice::from_raw_parts_mut(ptr as *mut u8, n as usize)};
-
-    let rc = match file.pread(off as u64, buf) {
-        Ok(n) => {
-            //let m = n.min(20);
-            //log::debug!("after read n={} {:?}", n, &buf[..m]);
-
-            if n == buf.len() {
-                libsqlite3_sys::SQLITE_OK
-            }else{
-                //println!("short read");
-                libsqlite3_sys::SQLITE_IOERR_SHORT_READ
+    guard("mojo_read", libsqlite3_sys::SQLITE_IOERR_READ, || {
+        let file = try_rc!(get_file(sfile), libsqlite3_sys::SQLITE_IOERR_READ);
+        let buf = try_rc!(ffi_buf_mut(ptr, n), libsqlite3_sys::SQLITE_IOERR_READ);
+
+        match file.pread(off as u64, buf) {
+            Ok(n) => {
+                //let m = n.min(20);
+                //log::debug!("after read n={} {:?}", n, &buf[..m]);
+
+                if n == buf.len() {
+                    libsqlite3_sys::SQLITE_OK
+                }else{
+                    //println!("short read");
+                    libsqlite3_sys::SQLITE_IOERR_SHORT_READ
+                }
             }
+            Err(err) => {
+                log::error!("mojo_read id={} off={} blen={} err={:?}", file.id(), off, buf.len(), err);
+                sqlite_rc(&err, libsqlite3_sys::SQLITE_IOERR_READ)
+            },
         }
-        Err(err) => {
-            log::error!("mojo_read id={} off={} blen={} err={:?}", file.id(), off, buf.len(), err);
-            libsqlite3_sys::SQLITE_IOERR_READ
-        },
-    };
-
-    rc
+    })
 }
 
 #[no_mangle]
 extern "C" fn mojo_write(sfile: *mut sqlite3_file, ptr: *const c_void, n: i32, off: i64) -> c_int {
-    let file = get_file_mut(sfile);
-    let buf = unsafe{ std::slice::from_raw_parts(ptr as *const u8, n as usize)};
-
-    let rc = match file.pwrite(off as u64, buf) {
-        Ok(_) => libsqlite3_sys::SQLITE_OK,
-        Err(err) => {
-            log::error!("mojo_write id={} off={} blen={} err={:?}", file.id(), off, buf.len(), err);
-            libsqlite3_sys::SQLITE_IOERR_WRITE
-        },
-    };
+    guard("mojo_write", libsqlite3_sys::SQLITE_IOERR_WRITE, || {
+        let file = try_rc!(get_file_mut(sfile), libsqlite3_sys::SQLITE_IOERR_WRITE);
+        let buf = try_rc!(ffi_buf(ptr, n), libsqlite3_sys::SQLITE_IOERR_WRITE);
+
+        if file.needs_page_size() {
+            let fs = try_rc!(get_mojo_file(sfile).and_then(|f| getfs(f.vfs)), libsqlite3_sys::SQLITE_IOERR_WRITE);
+            if let Err(err) = fs.lock().learn_page_size(file, off as u64, buf) {
+                log::error!("mojo_write id={} off={} blen={} err={:?}", file.id(), off, buf.len(), err);
+                return sqlite_rc(&err, libsqlite3_sys::SQLITE_IOERR_WRITE);
+            }
+        }
 
-    rc
+        match file.pwrite(off as u64, buf) {
+            Ok(_) => libsqlite3_sys::SQLITE_OK,
+            Err(err) => {
+                log::error!("mojo_write id={} off={} blen={} err={:?}", file.id(), off, buf.len(), err);
+                sqlite_rc(&err, libsqlite3_sys::SQLITE_IOERR_WRITE)
+            },
+        }
+    })
 }
 
 #[no_mangle]
 extern "C" fn mojo_truncate(sfile: *mut sqlite3_file, new_sz: i64) -> c_int {
-    let file = get_file_mut(sfile);
-
-    let rc = match file.truncate(new_sz as u64) {
-        Ok(_) => libsqlite3_sys::SQLITE_OK,
-        Err(err) => {
-            log::error!("mojo_truncate id={} new_sz={} err={:?}", file.id(), new_sz, err);
-            libsqlite3_sys::SQLITE_IOERR_WRITE
-        },
-    };
-
-    rc
+    guard("mojo_truncate", libsqlite3_sys::SQLITE_IOERR_WRITE, || {
+        let file = try_rc!(get_file_mut(sfile), libsqlite3_sys::SQLITE_IOERR_WRITE);
+
+        match file.truncate(new_sz as u64) {
+            Ok(_) => libsqlite3_sys::SQLITE_OK,
+            Err(err) => {
+                log::error!("mojo_truncate id={} new_sz={} err={:?}", file.id(), new_sz, err);
+                sqlite_rc(&err, libsqlite3_sys::SQLITE_IOERR_WRITE)
+            },
+        }
+    })
 }
 
 #[no_mangle]
 extern "C" fn mojo_sync(sfile: *mut sqlite3_file, flags: i32) -> c_int {
-    let file = get_file_mut(sfile);
-
-    let rc = match file.sync(flags) {
-        Ok(_) => libsqlite3_sys::SQLITE_OK,
-        Err(err) => {
-            log::error!("mojo_sync id={} err={:?}", file.id(), err);
-            libsqlite3_sys::SQLITE_IOERR_WRITE
-        },
-    };
-
-    rc
+    guard("mojo_sync", libsqlite3_sys::SQLITE_IOERR_WRITE, || {
+        let file = try_rc!(get_file_mut(sfile), libsqlite3_sys::SQLITE_IOERR_WRITE);
+
+        match file.sync(flags) {
+            Ok(_) => libsqlite3_sys::SQLITE_OK,
+            Err(err) => {
+                log::error!("mojo_sync id={} err={:?}", file.id(), err);
+                sqlite_rc(&err, libsqlite3_sys::SQLITE_IOERR_WRITE)
+            },
+        }
+    })
 }
 
 #[no_mangle]
 extern "C" fn mojo_filesize(sfile: *mut sqlite3_file, out_sz: *mut i64) -> c_int {
-    let file = get_file(sfile);
+    guard("mojo_filesize", libsqlite3_sys::SQLITE_IOERR_FSTAT, || {
+        let file = try_rc!(get_file(sfile), libsqlite3_sys::SQLITE_IOERR_FSTAT);
 
-    let rc = match file.filesize() {
-        Ok(sz) => {
-            unsafe{*out_sz = sz as i64;}
-            libsqlite3_sys::SQLITE_OK 
+        match file.filesize() {
+            Ok(sz) => {
+                unsafe{*out_sz = sz as i64;}
+                libsqlite3_sys::SQLITE_OK 
+            }
+            Err(err) => {
+                log::error!("mojo_filesize id={} err={:?}", file.id(), err);
+                sqlite_rc(&err, libsqlite3_sys::SQLITE_IOERR_FSTAT)
+            },
         }
-        Err(_) => libsqlite3_sys::SQLITE_IOERR_WRITE,
-    };
+    })
+}
 
-    rc
+#[no_mangle]
+extern "C" fn mojo_fetch(sfile: *mut sqlite3_file, off: i64, amt: c_int, pp: *mut *mut c_void) -> c_int {
+    guard("mojo_fetch", libsqlite3_sys::SQLITE_IOERR_MMAP, || {
+        unsafe{*pp = std::ptr::null_mut();}
+        let file = try_rc!(get_file_mut(sfile), libsqlite3_sys::SQLITE_IOERR_MMAP);
+
+        match file.fetch(off as u64, amt as usize) {
+            Ok(ptr) => {
+                unsafe{*pp = ptr.unwrap_or(std::ptr::null()) as *mut c_void;}
+                libsqlite3_sys::SQLITE_OK
+            }
+            Err(err) => {
+                log::error!("mojo_fetch id={} off={} amt={} err={:?}", file.id(), off, amt, err);
+                sqlite_rc(&err, libsqlite3_sys::SQLITE_IOERR_MMAP)
+            },
+        }
+    })
 }
 
 #[no_mangle]
-extern "C" fn mojo_access(vfs: *mut sqlite3_vfs, zname: *const c_char, flags: c_int, resout: *mut c_int) -> c_int {
-    let path = match c_to_path(zname) {
-        Ok(path) => path,
-        Err(_) => {
-            return libsqlite3_sys::SQLITE_IOERR_CONVPATH;
+extern "C" fn mojo_unfetch(sfile: *mut sqlite3_file, off: i64, ptr: *mut c_void) -> c_int {
+    guard("mojo_unfetch", libsqlite3_sys::SQLITE_IOERR_MMAP, || {
+        let file = try_rc!(get_file_mut(sfile), libsqlite3_sys::SQLITE_IOERR_MMAP);
+
+        match file.unfetch(off as u64, ptr as *const u8) {
+            Ok(_) => libsqlite3_sys::SQLITE_OK,
+            Err(err) => {
+                log::error!("mojo_unfetch id={} off={} err={:?}", file.id(), off, err);
+                sqlite_rc(&err, libsqlite3_sys::SQLITE_IOERR_MMAP)
+            },
         }
-    };
+    })
+}
 
-    let access_req = if flags == libsqlite3_sys::SQLITE_ACCESS_EXISTS {
-        AccessCheck::Exists
-    }else{
-        AccessCheck::ReadWrite
-    };
+#[no_mangle]
+extern "C" fn mojo_shm_map(sfile: *mut sqlite3_file, region: c_int, region_sz: c_int, extend: c_int, pp: *mut *mut c_void) -> c_int {
+    guard("mojo_shm_map", libsqlite3_sys::SQLITE_IOERR_SHMMAP, || {
+        unsafe{*pp = std::ptr::null_mut();}
+        let file = try_rc!(get_file_mut(sfile), libsqlite3_sys::SQLITE_IOERR_SHMMAP);
+
+        if !file.has_shm() {
+            let fs = try_rc!(get_mojo_file(sfile).and_then(|f| getfs(f.vfs)), libsqlite3_sys::SQLITE_IOERR_SHMOPEN);
+            match fs.lock().open_shm(file) {
+                Ok(shm) => file.set_shm(shm),
+                Err(err) => {
+                    log::error!("mojo_shm_map id={} open err={:?}", file.id(), err);
+                    return sqlite_rc(&err, libsqlite3_sys::SQLITE_IOERR_SHMOPEN);
+                }
+            }
+        }
 
-    let fs = getfs(vfs);
-    match fs.access(&path, access_req) {
-        Ok(status) => {
-            unsafe{*resout = if status {1}else{0}}
-        },
-        Err(_) => {
-            return libsqlite3_sys::SQLITE_IOERR_ACCESS;
+        match file.shm_map(region as usize, region_sz as usize, extend != 0) {
+            Ok(ptr) => {
+                unsafe{*pp = ptr.unwrap_or(std::ptr::null_mut()) as *mut c_void;}
+                libsqlite3_sys::SQLITE_OK
+            },
+            Err(err) => {
+                log::error!("mojo_shm_map id={} region={} err={:?}", file.id(), region, err);
+                sqlite_rc(&err, libsqlite3_sys::SQLITE_IOERR_SHMMAP)
+            },
         }
-    }
+    })
+}
 
-    libsqlite3_sys::SQLITE_OK
+#[no_mangle]
+extern "C" fn mojo_shm_lock(sfile: *mut sqlite3_file, ofst: c_int, n: c_int, flags: c_int) -> c_int {
+    guard("mojo_shm_lock", libsqlite3_sys::SQLITE_IOERR_SHMLOCK, || {
+        let file = try_rc!(get_file_mut(sfile), libsqlite3_sys::SQLITE_IOERR_SHMLOCK);
+
+        match file.shm_lock(ofst as usize, n as usize, flags) {
+            Ok(true) => libsqlite3_sys::SQLITE_OK,
+            Ok(false) => libsqlite3_sys::SQLITE_BUSY,
+            Err(err) => {
+                log::error!("mojo_shm_lock id={} ofst={} n={} flags={} err={:?}", file.id(), ofst, n, flags, err);
+                sqlite_rc(&err, libsqlite3_sys::SQLITE_IOERR_SHMLOCK)
+            },
+        }
+    })
 }
 
 #[no_mangle]
-extern "C" fn mojo_delete(vfs: *mut sqlite3_vfs, zname: *const c_char, _syncdir: c_int) -> c_int {
-    let path = match c_to_path(zname) {
-        Ok(path) => path,
-        Err(_) => {
-            return libsqlite3_sys::SQLITE_IOERR_DELETE;
+extern "C" fn mojo_shm_barrier(sfile: *mut sqlite3_file) {
+    guard("mojo_shm_barrier", (), || {
+        match get_file(sfile) {
+            Ok(file) => file.shm_barrier(),
+            Err(err) => log::error!("mojo_shm_barrier err={:?}", err),
         }
-    };
+    })
+}
 
-    let fs = getfs(vfs);
-    match fs.delete(&path) {
-        Ok(_) => {
-            libsqlite3_sys::SQLITE_OK
+#[no_mangle]
+extern "C" fn mojo_shm_unmap(sfile: *mut sqlite3_file, delete: c_int) -> c_int {
+    guard("mojo_shm_unmap", libsqlite3_sys::SQLITE_IOERR_SHMMAP, || {
+        let file = try_rc!(get_file_mut(sfile), libsqlite3_sys::SQLITE_IOERR_SHMMAP);
+
+        match file.shm_unmap(delete != 0) {
+            Ok(_) => libsqlite3_sys::SQLITE_OK,
+            Err(err) => {
+                log::error!("mojo_shm_unmap id={} err={:?}", file.id(), err);
+                sqlite_rc(&err, libsqlite3_sys::SQLITE_IOERR_SHMMAP)
+            },
         }
-        Err(err) => {
-            log::error!("mojo_delete path={:?} err={:?}", path, err);
-            libsqlite3_sys::SQLITE_IOERR_DELETE
+    })
+}
+
+#[no_mangle]
+extern "C" fn mojo_access(vfs: *mut sqlite3_vfs, zname: *const c_char, flags: c_int, resout: *mut c_int) -> c_int {
+    guard("mojo_access", libsqlite3_sys::SQLITE_IOERR_ACCESS, || {
+        let path = try_rc!(c_to_path(zname), libsqlite3_sys::SQLITE_IOERR_CONVPATH);
+        let fs = try_rc!(getfs(vfs), libsqlite3_sys::SQLITE_IOERR_ACCESS);
+
+        let access_req = if flags == libsqlite3_sys::SQLITE_ACCESS_EXISTS {
+            AccessCheck::Exists
+        }else{
+            AccessCheck::ReadWrite
+        };
+
+        match fs.lock().access(&path, access_req) {
+            Ok(status) => {
+                unsafe{*resout = if status {1}else{0}}
+            },
+            Err(err) => {
+                log::error!("mojo_access path={:?} err={:?}", path, err);
+                return sqlite_rc(&err, libsqlite3_sys::SQLITE_IOERR_ACCESS);
+            }
         }
-    }
+
+        libsqlite3_sys::SQLITE_OK
+    })
 }
 
 #[no_mangle]
-extern "C" fn mojo_fullname(vfs: *mut sqlite3_vfs, zname: *const c_char, _nout: c_int, _zout: *mut c_char) -> c_int {
-    let fs = getfs(vfs);
+extern "C" fn mojo_delete(vfs: *mut sqlite3_vfs, zname: *const c_char, _syncdir: c_int) -> c_int {
+    guard("mojo_delete", libsqlite3_sys::SQLITE_IOERR_DELETE, || {
+        let path = try_rc!(c_to_path(zname), libsqlite3_sys::SQLITE_IOERR_DELETE);
+        let fs = try_rc!(getfs(vfs), libsqlite3_sys::SQLITE_IOERR_DELETE);
+
+        match fs.lock().delete(&path) {
+            Ok(_) => {
+                libsqlite3_sys::SQLITE_OK
+            }
+            Err(err) => {
+                log::error!("mojo_delete path={:?} err={:?}", path, err);
+                sqlite_rc(&err, libsqlite3_sys::SQLITE_IOERR_DELETE)
+            }
+        }
+    })
+}
+
+#[no_mangle]
+extern "C" fn mojo_fullname(vfs: *mut sqlite3_vfs, zname: *const c_char, nout: c_int, zout: *mut c_char) -> c_int {
+    guard("mojo_fullname", libsqlite3_sys::SQLITE_CANTOPEN, || {
+        let fs = try_rc!(getfs(vfs), libsqlite3_sys::SQLITE_CANTOPEN);
+        if zname.is_null() || zout.is_null() {
+            return sqlite_rc(&null_arg("path"), libsqlite3_sys::SQLITE_CANTOPEN);
+        }
 
-    let file_rs = unsafe{std::ffi::CStr::from_ptr(zname)};
-    let file_str = match file_rs.to_str() {
-        Ok(file_str) => file_str,
-        Err(_err) => {
-            log::error!("mojo_fullname error in filepath");
+        let file_rs = unsafe{std::ffi::CStr::from_ptr(zname)};
+        let file_str = match file_rs.to_str() {
+            Ok(file_str) => file_str,
+            Err(err) => {
+                log::error!("mojo_fullname error in filepath err={:?}", err);
+                return sqlite_rc(&err.into(), libsqlite3_sys::SQLITE_CANTOPEN);
+            }
+        };
+
+        let path = match fs.lock().fullpath(file_str) {
+            Ok(path) => path,
+            Err(err) => {
+                log::error!("mojo_fullname path={} err={:?}", file_str, err);
+                return sqlite_rc(&err, libsqlite3_sys::SQLITE_IOERR);
+            }
+        };
+
+        let path = path.as_os_str().as_bytes();
+        if nout < 0 || path.len() >= nout as usize {
+            log::error!("mojo_fullname path={} is too long", file_str);
             return libsqlite3_sys::SQLITE_CANTOPEN;
         }
-    };
 
-    let _ = fs.fullpath(file_str);
+        unsafe {
+            std::ptr::copy_nonoverlapping(path.as_ptr(), zout as *mut u8, path.len());
+            *zout.add(path.len()) = 0;
+        }
 
-    libsqlite3_sys::SQLITE_OK
+        libsqlite3_sys::SQLITE_OK
+    })
 }
 
 #[no_mangle]
@@ -356,7 +567,7 @@ extern "C" fn mojo_sleep(_arg1: *mut sqlite3_vfs, microseconds: c_int) -> c_int
 
 #[no_mangle]
 extern "C" fn mojo_current_time(_arg1: *mut sqlite3_vfs, p: *mut f64) -> c_int {
-    let now = (std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap()).as_secs() as f64;
+    let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap_or_default().as_secs() as f64;
     unsafe {
         *p = (2440587.5 + now / 864.0e5) * 864.0e5;
     }
@@ -366,7 +577,7 @@ extern "C" fn mojo_current_time(_arg1: *mut sqlite3_vfs, p: *mut f64) -> c_int {
 
 #[no_mangle]
 extern "C" fn mojo_current_time64(_arg1: *mut sqlite3_vfs, p: *mut i64) -> c_int {
-    let now = (std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap()).as_secs() as f64;
+    let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap_or_default().as_secs() as f64;
     unsafe {
         *p = ((2440587.5 + now / 864.0e5) * 864.0e5) as i64;
     }
@@ -375,29 +586,167 @@ extern "C" fn mojo_current_time64(_arg1: *mut sqlite3_vfs, p: *mut i64) -> c_int
 }
 
 #[no_mangle]
-extern "C" fn mojo_getlasterr(_arg1: *mut sqlite3_vfs, _arg2: c_int, _arg3: *mut c_char) -> c_int {
-    libsqlite3_sys::SQLITE_OK
+extern "C" fn mojo_getlasterr(_arg1: *mut sqlite3_vfs, nbuf: c_int, zbuf: *mut c_char) -> c_int {
+    guard("mojo_getlasterr", 0, || {
+        let (errno, msg) = match error::last_error() {
+            Some(e) => e,
+            None => return 0,
+        };
+
+        // The message is cut to fit the buffer
+        if !zbuf.is_null() && nbuf > 0 {
+            let n = msg.len().min(nbuf as usize - 1);
+            unsafe {
+                std::ptr::copy_nonoverlapping(msg.as_ptr(), zbuf as *mut u8, n);
+                *zbuf.add(n) = 0;
+            }
+        }
+
+        errno
+    })
+}
+
+// Records the error for xGetLastError and returns its sqlite result code,
+// `default` if it has none of its own
+fn sqlite_rc(err: &Error, default: c_int) -> c_int {
+    error::set_last_error(err);
+    err.sqlite_code(default)
+}
+
+// Runs the body of an entry point. A panic must not unwind into sqlite, it
+// is logged, kept as the last error and `on_panic` is returned instead.
+pub(crate) fn guard<T>(name: &str, on_panic: T, f: impl FnOnce() -> T) -> T {
+    match std::panic::catch_unwind(std::panic::AssertUnwindSafe(f)) {
+        Ok(res) => res,
+        Err(payload) => {
+            let msg = match payload.downcast_ref::<&str>() {
+                Some(s) => s.to_string(),
+                None => payload.downcast_ref::<String>().cloned().unwrap_or_else(|| "unknown cause".to_owned()),
+            };
+
+            log::error!("{} panicked: {}", name, msg);
+            error::set_last_error(&Error::new(MOJOFS_ERR_PANIC, format!("{} panicked: {}", name, msg)));
+            on_panic
+        }
+    }
 }
 
 #[no_mangle]
-extern "C" fn mojo_lock(_sfile: *mut sqlite3_file, _flags: c_int) -> c_int {
-    libsqlite3_sys::SQLITE_OK
+extern "C" fn mojo_lock(sfile: *mut sqlite3_file, flags: c_int) -> c_int {
+    guard("mojo_lock", libsqlite3_sys::SQLITE_IOERR_LOCK, || {
+        let file = try_rc!(get_file_mut(sfile), libsqlite3_sys::SQLITE_IOERR_LOCK);
+
+        match file.lock(flags) {
+            Ok(true) => libsqlite3_sys::SQLITE_OK,
+            Ok(false) => libsqlite3_sys::SQLITE_BUSY,
+            Err(err) => {
+                log::error!("mojo_lock id={} flags={} err={:?}", file.id(), flags, err);
+                sqlite_rc(&err, libsqlite3_sys::SQLITE_IOERR_LOCK)
+            },
+        }
+    })
 }
 
 #[no_mangle]
-extern "C" fn mojo_unlock(_sfile: *mut sqlite3_file, _flags: c_int) -> c_int {
-    libsqlite3_sys::SQLITE_OK
+extern "C" fn mojo_unlock(sfile: *mut sqlite3_file, flags: c_int) -> c_int {
+    guard("mojo_unlock", libsqlite3_sys::SQLITE_IOERR_UNLOCK, || {
+        let file = try_rc!(get_file_mut(sfile), libsqlite3_sys::SQLITE_IOERR_UNLOCK);
+
+        match file.unlock(flags) {
+            Ok(_) => libsqlite3_sys::SQLITE_OK,
+            Err(err) => {
+                log::error!("mojo_unlock id={} flags={} err={:?}", file.id(), flags, err);
+                sqlite_rc(&err, libsqlite3_sys::SQLITE_IOERR_UNLOCK)
+            },
+        }
+    })
 }
 
 #[no_mangle]
-extern "C" fn mojo_check_reserved_lock(_sfile: *mut sqlite3_file, res_out: *mut c_int) -> c_int {
-    unsafe{*res_out = 0;}
-    libsqlite3_sys::SQLITE_OK
+extern "C" fn mojo_check_reserved_lock(sfile: *mut sqlite3_file, res_out: *mut c_int) -> c_int {
+    guard("mojo_check_reserved_lock", libsqlite3_sys::SQLITE_IOERR_CHECKRESERVEDLOCK, || {
+        unsafe{*res_out = 0;}
+        let file = try_rc!(get_file(sfile), libsqlite3_sys::SQLITE_IOERR_CHECKRESERVEDLOCK);
+
+        match file.check_reserved_lock() {
+            Ok(res) => {
+                unsafe{*res_out = res;}
+                libsqlite3_sys::SQLITE_OK
+            },
+            Err(err) => {
+                log::error!("mojo_check_reserved_lock id={} err={:?}", file.id(), err);
+                sqlite_rc(&err, libsqlite3_sys::SQLITE_IOERR_CHECKRESERVEDLOCK)
+            },
+        }
+    })
 }
 
 #[no_mangle]
-extern "C" fn mojo_file_control(_sfile: *mut sqlite3_file, _op: c_int, _arg: *mut c_void) -> c_int {
-    libsqlite3_sys::SQLITE_NOTFOUND
+extern "C" fn mojo_file_control(sfile: *mut sqlite3_file, op: c_int, arg: *mut c_void) -> c_int {
+    guard("mojo_file_control", libsqlite3_sys::SQLITE_IOERR, || {
+        let file = try_rc!(get_file_mut(sfile), libsqlite3_sys::SQLITE_IOERR);
+
+        let res = match op {
+            libsqlite3_sys::SQLITE_FCNTL_BEGIN_ATOMIC_WRITE => file.begin_atomic_write(),
+            libsqlite3_sys::SQLITE_FCNTL_COMMIT_ATOMIC_WRITE => file.commit_atomic_write(),
+            libsqlite3_sys::SQLITE_FCNTL_ROLLBACK_ATOMIC_WRITE => file.rollback_atomic_write(),
+            libsqlite3_sys::SQLITE_FCNTL_PRAGMA => return file_control_pragma(file, arg),
+            _ => return libsqlite3_sys::SQLITE_NOTFOUND,
+        };
+
+        // An io error makes sqlite fall back to the journal
+        match res {
+            Ok(_) => libsqlite3_sys::SQLITE_OK,
+            Err(err) => {
+                log::error!("mojo_file_control id={} op={} err={:?}", file.id(), op, err);
+                sqlite_rc(&err, libsqlite3_sys::SQLITE_IOERR_WRITE)
+            },
+        }
+    })
+}
+
+fn file_control_pragma(file: &mut VFSFile, arg: *mut c_void) -> c_int {
+
+    // arg is char*[3]: result or error message, pragma name and value
+    let az_arg = arg as *mut *mut c_char;
+    if az_arg.is_null() {
+        return libsqlite3_sys::SQLITE_NOTFOUND;
+    }
+
+    let (name, value) = unsafe {
+        let name = CStr::from_ptr(*az_arg.add(1)).to_str();
+        let value = *az_arg.add(2);
+        let value = if value.is_null() { Ok(None) } else { CStr::from_ptr(value).to_str().map(Some) };
+        match (name, value) {
+            (Ok(n), Ok(v)) => (n, v),
+            _ => return libsqlite3_sys::SQLITE_NOTFOUND,
+        }
+    };
+
+    let (rc, msg) = match file.pragma(name, value) {
+        Ok(None) => return libsqlite3_sys::SQLITE_NOTFOUND,
+        Ok(Some(res)) => (libsqlite3_sys::SQLITE_OK, res),
+        Err(err) => {
+            log::error!("mojo_file_control id={} pragma={} err={:?}", file.id(), name, err);
+            (sqlite_rc(&err, libsqlite3_sys::SQLITE_ERROR), err.msg)
+        },
+    };
+
+    unsafe {*az_arg = sqlite_str(&msg);}
+    rc
+}
+
+// Copies the string into memory allocated by sqlite, which sqlite frees
+fn sqlite_str(s: &str) -> *mut c_char {
+    unsafe {
+        let p = (api::api().malloc64)(s.len() as u64 + 1) as *mut u8;
+        if p.is_null() {
+            return std::ptr::null_mut();
+        }
+        std::ptr::copy_nonoverlapping(s.as_ptr(), p, s.len());
+        *p.add(s.len()) = 0;
+        p as *mut c_char
+    }
 }
 
 #[no_mangle]
@@ -406,37 +755,81 @@ extern "C" fn mojo_sector_size(_sfile: *mut sqlite3_file) -> c_int {
 }
 
 #[no_mangle]
-extern "C" fn mojo_device_char(_sfile: *mut sqlite3_file) -> c_int {
-    libsqlite3_sys::SQLITE_OK
+extern "C" fn mojo_device_char(sfile: *mut sqlite3_file) -> c_int {
+    guard("mojo_device_char", 0, || {
+        let file = match get_file(sfile) {
+            Ok(file) => file,
+            Err(err) => {
+                log::error!("mojo_device_char err={:?}", err);
+                return 0;
+            }
+        };
+
+        match file.device_char() {
+            Ok(flags) => flags,
+            Err(err) => {
+                log::error!("mojo_device_char id={} err={:?}", file.id(), err);
+                0
+            },
+        }
+    })
 }
 
-fn getfs(vfs: *mut sqlite3_vfs) -> &'static mut VFS {
-    let fs = unsafe{
-        ((*vfs).pAppData as *mut VFS).as_mut().unwrap()
-    };
+fn null_arg(what: &str) -> Error {
+    Error::new(MOJOFS_ERR_FFI_ARG, format!("{} passed by sqlite is null", what))
+}
 
-    fs
+fn getfs(vfs: *mut sqlite3_vfs) -> Result<&'static Mutex<VFS>, Error> {
+    unsafe {
+        vfs.as_ref()
+            .and_then(|vfs| (vfs.pAppData as *const Mutex<VFS>).as_ref())
+            .ok_or_else(|| null_arg("vfs"))
+    }
 }
 
-fn get_file_mut(sfile: *mut sqlite3_file) -> &'static mut VFSFile {
-    let file = unsafe{
-        let mojo_file = (sfile as *mut MojoFile).as_mut().unwrap();
-        (mojo_file.custom_file as *mut VFSFile).as_mut().unwrap()
-    };
+fn get_mojo_file(sfile: *mut sqlite3_file) -> Result<&'static mut MojoFile, Error> {
+    unsafe {(sfile as *mut MojoFile).as_mut()}.ok_or_else(|| null_arg("file"))
+}
 
-    file
+fn get_file_mut(sfile: *mut sqlite3_file) -> Result<&'static mut VFSFile, Error> {
+    let mojo_file = get_mojo_file(sfile)?;
+    unsafe {(mojo_file.custom_file as *mut VFSFile).as_mut()}.ok_or_else(|| null_arg("file"))
 }
 
-fn get_file(sfile: *mut sqlite3_file) -> &'static VFSFile {
-    let file = unsafe{
-        let mojo_file = (sfile as *mut MojoFile).as_ref().unwrap();
-        (mojo_file.custom_file as *mut VFSFile).as_ref().unwrap()
-    };
+// Files opened by other vfs have other io methods
+fn is_mojo_file(sfile: *mut sqlite3_file) -> bool {
+    unsafe {
+        let methods = (*sfile).pMethods;
+        !methods.is_null() && (*methods).xFileControl.map(|f| f as *const ()) == Some(mojo_file_control as *const ())
+    }
+}
 
-    file
+fn get_file(sfile: *mut sqlite3_file) -> Result<&'static VFSFile, Error> {
+    get_file_mut(sfile).map(|file| &*file)
+}
+
+// Buffers of sqlite may be null when they are empty
+fn ffi_buf<'a>(ptr: *const c_void, n: c_int) -> Result<&'a [u8], Error> {
+    match n {
+        0 => Ok(&[]),
+        n if n > 0 && !ptr.is_null() => Ok(unsafe {std::slice::from_raw_parts(ptr as *const u8, n as usize)}),
+        _ => Err(Error::new(MOJOFS_ERR_FFI_ARG, format!("bad buffer of len={}", n))),
+    }
+}
+
+fn ffi_buf_mut<'a>(ptr: *mut c_void, n: c_int) -> Result<&'a mut [u8], Error> {
+    match n {
+        0 => Ok(&mut []),
+        n if n > 0 && !ptr.is_null() => Ok(unsafe {std::slice::from_raw_parts_mut(ptr as *mut u8, n as usize)}),
+        _ => Err(Error::new(MOJOFS_ERR_FFI_ARG, format!("bad buffer of len={}", n))),
+    }
 }
 
 fn c_to_path(cpath: *const c_char) -> Result<std::path::PathBuf, Error> {
+    if cpath.is_null() {
+        return Err(null_arg("path"));
+    }
+
     let file_rs = unsafe{std::ffi::CStr::from_ptr(cpath)};
     let file_str = file_rs.to_str()?;
 
@@ -445,7 +838,8 @@ fn c_to_path(cpath: *const c_char) -> Result<std::path::PathBuf, Error> {
 
 #[no_mangle]
 pub extern "C" fn mojofs_init_log() {
-    env_logger::init();
+    // A logger set by the host is kept
+    let _ = env_logger::try_init();
 }
 
 
@@ -461,7 +855,7 @@ fn extract_query_params(filepath: *const c_char) -> Result<HashMap<String, Strin
     let mut value: &str;
 
     unsafe {
-        while key.len() > 0 {
+        while !key.is_empty() {
             while *itr != 0 {
                 itr = itr.add(1);
             }
@@ -0,0 +1,59 @@
+use crate::Error;
+
+/// File kept entirely in memory. Used for temporary files which never
+/// outlive the connection.
+#[derive(Default)]
+pub struct MemFile {
+    data: Vec<u8>,
+}
+
+impl MemFile {
+    pub fn new() -> Self {
+        MemFile::default()
+    }
+
+    pub fn pread(&self, buf: &mut [u8], off: i64) -> Result<usize, Error> {
+        log::debug!("mem pread o={}, blen={}", off, buf.len());
+
+        let off = (off as usize).min(self.data.len());
+        let n = buf.len().min(self.data.len() - off);
+        buf[..n].copy_from_slice(&self.data[off..off+n]);
+
+        if n<buf.len() {
+            let _ = &mut buf[n..].fill(0);
+        }
+
+        Ok(n)
+    }
+
+    pub fn pwrite(&mut self, off: i64, buf: &[u8]) -> Result<(), Error> {
+        log::debug!("mem pwrite o={}, blen={}", off, buf.len());
+
+        let off = off as usize;
+        let end = off + buf.len();
+        if end > self.data.len() {
+            self.data.resize(end, 0);
+        }
+        self.data[off..end].copy_from_slice(buf);
+
+        Ok(())
+    }
+
+    pub fn close(self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    pub fn sync(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    pub fn filesize(&self) -> Result<u64, Error> {
+        Ok(self.data.len() as u64)
+    }
+
+    pub fn truncate(&mut self, new_sz: u64) -> Result<(), Error> {
+        log::debug!("mem truncate {}", new_sz);
+        self.data.truncate(new_sz as usize);
+        Ok(())
+    }
+}
@@ -27,10 +27,91 @@ pub enum OpenKind {
     Wal,
 }
 
+/// Page size of the db assumed by statement and super journals written
+/// before it is known
+pub const DEFAULT_DB_PAGE_SZ: u32 = 4096;
+
+/// Bytes a wal frame adds to a db page: page number, db size, salts and
+/// checksums
+pub const WAL_FRAME_HDR_SZ: u32 = 24;
+
+/// Bytes a rollback journal record adds to a db page: page number and
+/// checksum
+pub const JOURNAL_RECORD_HDR_SZ: u32 = 8;
+
+/// Bytes of the header in front of the wal frames
+pub const WAL_HDR_SZ: u64 = 32;
+
+/// Bytes of the header in front of the rollback journal records. sqlite
+/// pads it to the sector size of the db, 512 as mojo files report none.
+pub const JOURNAL_HDR_SZ: u64 = 512;
+
+const JOURNAL_MAGIC: [u8; 8] = [0xd9, 0xd5, 0x05, 0xf9, 0x20, 0xa1, 0x63, 0xd7];
+const WAL_MAGIC: u32 = 0x377f0682;
+
 impl OpenKind {
     pub fn is_main(&self) -> bool {
         *self == OpenKind::MainDb
     }
+
+    /// True for the kinds of db, the other files are written as records
+    pub fn is_db(&self) -> bool {
+        matches!(self, OpenKind::MainDb | OpenKind::TempDb | OpenKind::TransientDb)
+    }
+
+    /// Page size of the bucket of a kv file, None for the page size of the
+    /// store. Journals and the wal are written as records of a db page and
+    /// a header of their own, a block holds one record. `db_page_sz` is
+    /// the page size of the db, 0 if it is not known yet which leaves the
+    /// bucket to learn it from its first write.
+    pub fn page_size(&self, db_page_sz: u32) -> Option<u32> {
+        let record_hdr_sz = match self {
+            OpenKind::MainDb | OpenKind::TempDb | OpenKind::TransientDb => return None,
+            OpenKind::MainJournal | OpenKind::TempJournal => JOURNAL_RECORD_HDR_SZ,
+            // Statement journal records have no checksum
+            OpenKind::SubJournal => 4,
+            // Only holds the names of the journals of the dbs
+            OpenKind::SuperJournal => 0,
+            OpenKind::Wal => WAL_FRAME_HDR_SZ,
+        };
+
+        match db_page_sz {
+            0 => Some(0),
+            sz => Some(sz + record_hdr_sz),
+        }
+    }
+
+    /// Bytes of the header sqlite writes in front of the records
+    pub fn header_size(&self) -> u64 {
+        match self {
+            OpenKind::MainJournal | OpenKind::TempJournal => JOURNAL_HDR_SZ,
+            OpenKind::Wal => WAL_HDR_SZ,
+            _ => 0,
+        }
+    }
+
+    /// Page size of the db stored in the header of a journal or the wal if
+    /// `buf` is a write of that header at offset 0. sqlite leaves the magic
+    /// of a journal zero until its records are synced.
+    pub fn header_page_size(&self, off: u64, buf: &[u8]) -> Option<u32> {
+        if off != 0 {
+            return None;
+        }
+
+        let be32 = |i: usize| u32::from_be_bytes([buf[i], buf[i+1], buf[i+2], buf[i+3]]);
+        let page_sz = match self {
+            OpenKind::MainJournal | OpenKind::TempJournal if buf.len() >= 28
+                && (buf[..8] == JOURNAL_MAGIC || buf[..8] == [0; 8]) => be32(24),
+            OpenKind::Wal if buf.len() >= 12 && be32(0) & !1 == WAL_MAGIC => be32(8),
+            _ => return None,
+        };
+
+        if !(512..=65536).contains(&page_sz) || !page_sz.is_power_of_two() {
+            return None;
+        }
+
+        Some(page_sz)
+    }
 }
 
 /// The access an object is opened with.
@@ -1,11 +1,13 @@
 
-use std::path::{PathBuf, Path};
+use std::path::{PathBuf, Path, Component};
 use crate::{error, Error};
 use crate::open_options::*;
 use std::collections::HashMap;
-use crate::kvfile::{KVFile, KVFileOpt};
+use crate::kvfile::{KVFile, OpenBuckets};
 use crate::vfsfile::FileImpl;
-use mojokv::{Store, BucketOpenMode};
+use crate::native_file::NativeFile;
+use crate::mem_file::MemFile;
+use mojokv::{Store, BucketOpenMode, Shm};
 
 use crate::vfsfile::VFSFile;
 
@@ -15,70 +17,339 @@ pub enum AccessCheck {
     ReadWrite,
 }
 
+/// Identifies a store in the registry. Writable stores are always at the
+/// active version and have no `ver`. `txn` is set for a read-only store
+/// opened at a transaction of the txn log.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct StoreKey {
+    pub root_path: PathBuf,
+    pub ver: Option<u32>,
+    pub txn: Option<u32>,
+}
+
+struct StoreEntry {
+    store: Store,
+    fopt: FSOptions,
+    nfiles: usize,
+    // Buckets of the kv files open in a writable store
+    buckets: OpenBuckets,
+}
+
+/// Main db an open goes to, returned by `init`: the store it lives in
+/// and its name in the store
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DbKey {
+    pub key: StoreKey,
+    /// Name given with `db`, None for the db of a store opened without one
+    pub db: Option<String>,
+}
+
 #[derive(Default)]
 pub struct VFS {
-    store: Option<Store>,
+    stores: HashMap<StoreKey, StoreEntry>,
+    // Full path of each main db opened to the db it is. Journals, wal and
+    // shm files are named after the main db.
+    db_paths: HashMap<String, DbKey>,
     file_counter: usize,
-    fopt: FSOptions,
+    // URI params used when the open does not give them
+    default_params: HashMap<String, String>,
 }
 
 impl VFS {
+    pub fn with_defaults(default_params: HashMap<String, String>) -> Self {
+        VFS {
+            default_params,
+            ..Default::default()
+        }
+    }
+
     pub fn name(&self) -> String {
-        return "mojo".to_owned()
+        "mojo".to_owned()
     }
 
-    pub fn fs_options(&self) -> FSOptions {
-        self.fopt.clone()
+    /// URI params of an open merged over the default params
+    pub fn params(&self, uri_params: HashMap<String, String>) -> HashMap<String, String> {
+        let mut params = self.default_params.clone();
+        params.extend(uri_params);
+        params
     }
 
-    pub fn active_ver(&self) -> u32 {
-        self.store.as_ref().unwrap().active_ver()
+    /// Options the store `key` is open with
+    pub fn fs_options(&self, key: &StoreKey) -> FSOptions {
+        self.stores.get(key).map(|e| e.fopt.clone()).unwrap_or_default()
     }
 
-    pub fn init(&mut self, root_path: &str, params: &HashMap<String, String>, opt: OpenOptions) -> Result<(), Error> {
+    /// Active version of the store `key`
+    pub fn active_ver(&self, key: &StoreKey) -> Result<u32, Error> {
+        Ok(self.entry(key)?.store.active_ver())
+    }
+
+    pub fn stores(&self) -> impl Iterator<Item=&StoreKey> {
+        self.stores.keys()
+    }
+
+    /// Opens the store at `root_path`, or the one given by the `store`
+    /// param, or reuses it if it is already open. Returns the db to pass to
+    /// `open` for the main db.
+    pub fn init(&mut self, root_path: &str, params: &HashMap<String, String>, opt: OpenOptions) -> Result<DbKey, Error> {
         log::debug!("init: root_path={} params={:?} opt={:?}", root_path, params, opt);
 
-        self.fopt = FSOptions::parse(params)?;
+        let mut fopt = FSOptions::parse(params)?;
+        let is_read = opt.access == OpenAccess::Read;
+        if fopt.txn.is_some() && !is_read {
+            return Err(Error::new(error::MOJOFS_ERR_ARG_TXN, "txn can only be opened with mode=ro".to_owned()));
+        }
+
+        // A db opened in a store named by `store` is named after its file
+        // unless `db` gives the name. A relative store is taken as relative
+        // to the directory of the db.
+        let (root_path, db) = match fopt.store.clone() {
+            Some(store) => {
+                let db_path = Path::new(root_path);
+                let db = fopt.db.clone().or_else(|| db_path.file_name()
+                    .and_then(|n| n.to_str())
+                    .map(|n| n.to_owned()));
+                let store = match db_path.parent() {
+                    Some(dir) if Path::new(&store).is_relative() => normalize(&dir.join(&store)).to_string_lossy().into_owned(),
+                    _ => store,
+                };
+                (store, db)
+            },
+            None => (root_path.to_owned(), fopt.db.clone()),
+        };
+        let root_path = root_path.as_str();
+
+        if let Some(db) = &db {
+            mojokv::check_bucket_name(db)?;
+        }
+
+        let key = StoreKey {
+            root_path: PathBuf::from(root_path),
+            ver: if is_read { Some(fopt.ver) } else { None },
+            txn: fopt.txn,
+        };
+
+        // Files still open keep the store, otherwise it is reloaded to pick
+        // up changes made by others
+        if let Some(entry) = self.stores.get(&key) {
+            if entry.nfiles > 0 {
+                if fopt.pagesz != 0 && fopt.pagesz != entry.store.page_size() {
+                    return Err(mojokv::Error::PageSizeMismatchErr(entry.store.page_size(), fopt.pagesz).into());
+                }
+                if let Some(param) = entry.fopt.conflict(&fopt) {
+                    return Err(Error::new(error::MOJOFS_ERR_ARG_CONFLICT,
+                        format!("{} differs from the one store {:?} is open with", param, key.root_path)));
+                }
+
+                log::debug!("init: reusing store key={:?}", key);
+                return Ok(DbKey { key, db });
+            }
+        }
+
         let root_path = Path::new(root_path);
-        if opt.access == OpenAccess::Read {
-            self.store = Some(Store::readonly(root_path, self.fopt.ver)?);
+        let page_sz = if fopt.pagesz == 0 { None } else { Some(fopt.pagesz) };
+
+        let store = if is_read {
+            let store = Store::readonly(root_path, fopt.ver)?;
+            if let Some(page_sz) = page_sz {
+                if page_sz != store.page_size() {
+                    return Err(mojokv::Error::PageSizeMismatchErr(store.page_size(), page_sz).into());
+                }
+            }
             log::debug!("store opened in readonly mode");
+            store
         }else{
-            self.store = Some(Store::writable(root_path, true, Some(self.fopt.pagesz), Some(self.fopt.pps))?);
+            let store = Store::writable(root_path, true, page_sz, Some(fopt.pps))?;
             log::debug!("store opened writable mode");
+            store
+        };
+
+        // Stores of the same root share the open version files
+        let shared = self.stores.iter()
+            .find(|(k, _)| k.root_path == key.root_path && *k != &key)
+            .map(|(_, e)| e.store.file_cache());
+        if let Some(fcache) = shared {
+            log::debug!("init: sharing file cache of root={:?}", key.root_path);
+            store.set_file_cache(fcache);
         }
 
-        Ok(())
+        store.set_fd_limit(fopt.fdlimit);
+        store.set_prealloc_sz(fopt.prealloc);
+        fopt.pagesz = store.page_size();
+
+        self.stores.insert(key.clone(), StoreEntry {
+            store,
+            fopt,
+            nfiles: 0,
+            buckets: OpenBuckets::default(),
+        });
+
+        Ok(DbKey { key, db })
     }
 
-    pub fn open(&mut self, filepath: &str, opt: OpenOptions, _out_opt: &mut OpenOptions) -> Result<Box<VFSFile>, Error> {
-        log::debug!("open: file={} opt={:?}", filepath, opt);
+    // Finds the store of a file. Main dbs go to `db`, other files to the
+    // store of the main db they are named after, or to `db` if there is
+    // none. Also returns the bucket of a file of a named db: the db name
+    // followed by the suffix sqlite adds to the path of the main db. None
+    // if the file is not tied to any store.
+    fn resolve(&self, filepath: &str, kind: OpenKind, db: Option<&DbKey>) -> Result<Option<(StoreKey, Option<String>)>, Error> {
+        if kind == OpenKind::MainDb {
+            let db = db.ok_or_else(|| Error::new(error::MOJOFS_ERR_NO_STORE, format!("no store for file {}", filepath)))?;
+            return Ok(Some((db.key.clone(), db.db.clone())));
+        }
+
+        let found = match filepath {
+            "" => None,
+            _ => {
+                let filepath = Self::db_path_key(filepath);
+                self.db_paths.iter()
+                    .filter(|(db_path, _)| filepath.starts_with(db_path.as_str())
+                        && (filepath.len() == db_path.len() || filepath[db_path.len()..].starts_with('-')))
+                    .max_by_key(|(db_path, _)| db_path.len())
+                    .map(|(db_path, entry)| {
+                        let name = entry.db.as_ref().map(|db| format!("{}{}", db, &filepath[db_path.len()..]));
+                        (entry.key.clone(), name)
+                    })
+            },
+        };
+
+        Ok(found.or_else(|| db.map(|db| (db.key.clone(), None))))
+    }
+
+    // Main dbs are looked up by the path they are opened at, sqlite names
+    // the other files after it
+    fn db_path_key(filepath: &str) -> String {
+        normalize(Path::new(filepath)).to_string_lossy().into_owned()
+    }
+
+    fn entry(&self, key: &StoreKey) -> Result<&StoreEntry, Error> {
+        self.stores.get(key)
+            .ok_or_else(|| Error::new(error::MOJOFS_ERR_NO_STORE, format!("store {:?} not open", key)))
+    }
+
+    /// Opens a file. `db` is the db returned by `init` for a main db, for
+    /// other files it is only used when sqlite did not name them after an
+    /// open main db.
+    pub fn open(&mut self, filepath: &str, db: Option<&DbKey>, opt: OpenOptions, _out_opt: &mut OpenOptions) -> Result<Box<VFSFile>, Error> {
+        log::debug!("open: file={} db={:?} opt={:?}", filepath, db, opt);
 
         self.file_counter += 1;
         let id = self.file_counter;
-        let file_path = if filepath.len() == 0{
-            std::path::PathBuf::from(format!("mojo.tmp.{}", id))
-        }else{
-            std::path::PathBuf::from(filepath)
+
+        // Files sqlite opens without a name, e.g. temp files, are not tied
+        // to a db and take the options of the vfs
+        let target = self.resolve(filepath, opt.kind, db)?;
+        let policy = match &target {
+            Some((key, _)) => {
+                let entry = self.entry(key)?;
+
+                // Commits need an empty wal, which autocommit cannot wait for
+                if opt.kind == OpenKind::Wal && entry.fopt.autocommit.is_enabled() {
+                    return Err(Error::new(error::MOJOFS_ERR_ARG_CONFLICT,
+                        format!("autocommit does not work with the wal of {}", filepath)));
+                }
+
+                entry.fopt.policy(opt.kind)
+            },
+            None => FSOptions::parse(&self.default_params)?.policy(opt.kind),
         };
 
-        let store = self.store.as_mut().unwrap();
+        match policy {
+            FilePolicy::Native => {
+                let file_path = if filepath.is_empty() {
+                    std::env::temp_dir().join(format!("mojo.tmp.{}.{}", std::process::id(), id))
+                }else{
+                    PathBuf::from(filepath)
+                };
+
+                let f = NativeFile::open(&file_path, &opt)?;
+                log::debug!("open: native file={:?} id={} done", file_path, id);
+                let name = file_path.to_string_lossy();
+                return Ok(Box::new(VFSFile::new(id, &name, opt, FileImpl::Reg(f))));
+            },
+            FilePolicy::Mem => {
+                log::debug!("open: mem file={} id={} done", filepath, id);
+                return Ok(Box::new(VFSFile::new(id, filepath, opt, FileImpl::Mem(MemFile::new()))));
+            },
+            FilePolicy::Kv => {},
+        }
+
+        let (key, db_bucket) = target.ok_or_else(|| Error::new(error::MOJOFS_ERR_NO_STORE,
+            format!("no store for file {}", filepath)))?;
+        let entry = self.entry(&key)?;
 
-        let bucket_name = Self::bucket_name(&file_path);
+        let bucket_name = match db_bucket.clone() {
+            Some(name) => name,
+            None if filepath.is_empty() => format!("mojo.tmp.{}", id),
+            None => Self::bucket_name(&key.root_path, Path::new(filepath))?,
+        };
+        let bucket_name = bucket_name.as_str();
+
+        let store = entry.store.clone();
         let bmode = if let OpenAccess::Read = opt.access {
             BucketOpenMode::Read
         }else{
             BucketOpenMode::Write
         };
 
-        let b = store.open(bucket_name, bmode)?;
-        let kvfileopt = self.fopt.to_kvfile_opt();
+        let is_main = opt.kind == OpenKind::MainDb;
+        let is_write = opt.access != OpenAccess::Read;
+
+        let mut b = match (key.txn, opt.kind.page_size(store.page_size())) {
+            (Some(txn), _) if is_main => store.open_at_txn(bucket_name, entry.fopt.ver, txn)?,
+            (_, Some(page_sz)) => store.open_with_page_size(bucket_name, bmode, page_sz)?,
+            _ => store.open(bucket_name, bmode)?,
+        };
+
+        if is_main && is_write && entry.fopt.txnlog {
+            b.enable_txn_log()?;
+        }
+
+        let f = KVFile::open(b, opt.kind)?;
+        if is_write {
+            entry.buckets.add(&f.bucket);
+        }
+        let fimpl = FileImpl::KV(Box::new(f));
+        let ver = entry.fopt.ver;
+
+        let mut vfs_file = VFSFile::new(id, bucket_name, opt, fimpl);
+
+        // Older versions and transactions never change so only the active
+        // version is locked
+        // The bucket of the main db of a named db is the name
+        let db_name = if is_main { db_bucket } else { None };
+        if is_main && (is_write || (ver >= store.active_ver() && key.txn.is_none())) {
+            vfs_file.set_db_lock(store.db_lock_of(db_name.as_deref())?);
+            if is_write && entry.fopt.autocommit.is_enabled() {
+                vfs_file.set_autocommit(entry.fopt.autocommit.clone());
+            }
+            vfs_file.set_batch_atomic(is_write && entry.fopt.atomic && Self::batch_atomic());
+        }else{
+            vfs_file.set_immutable(true);
+        }
+
+        if is_main && is_write {
+            vfs_file.set_open_buckets(entry.buckets.clone());
+        }
+        vfs_file.set_store(key.clone(), store);
+        vfs_file.set_db(db_name.clone());
+
+        // Journals of a db attached at several versions belong to the
+        // writable one
+        if is_main {
+            let entry = DbKey { key: key.clone(), db: db_name };
+            let db_entry = self.db_paths.entry(Self::db_path_key(filepath)).or_insert_with(|| entry.clone());
+            if key.ver.is_none() {
+                *db_entry = entry;
+            }
+        }
 
-        let f = KVFile::open(b, kvfileopt)?;
-        let fimpl = FileImpl::KV(f);
+        if let Some(entry) = self.stores.get_mut(&key) {
+            entry.nfiles += 1;
+        }
 
         log::debug!("open: file={} id={} done", filepath, id);
-        Ok(Box::new(VFSFile::new(id, bucket_name, opt, fimpl)))
+        Ok(Box::new(vfs_file))
     }
 
     pub fn fullpath(&mut self, filepath: &str) -> Result<PathBuf, Error> {
@@ -94,17 +365,127 @@ impl VFS {
         }
     }
 
-    fn bucket_name(path: &Path) -> &str {
-        path.file_name().unwrap().to_str().unwrap()
+    /// Learns the page size of a new store from the first write of the
+    /// sqlite header. Journals and the wal opened before it was known learn
+    /// theirs from the first write of their header.
+    pub fn learn_page_size(&mut self, f: &mut VFSFile, off: u64, buf: &[u8]) -> Result<(), Error> {
+        let kind = f.opt().kind;
+        if !kind.is_db() {
+            let store = f.store()?;
+            let db_page_sz = match (kind.header_page_size(off, buf), store.page_size()) {
+                (Some(sz), _) => sz,
+                (None, 0) => crate::open_options::DEFAULT_DB_PAGE_SZ,
+                (None, sz) => sz,
+            };
+
+            let page_sz = kind.page_size(db_page_sz).unwrap_or(db_page_sz);
+            log::debug!("learnt page size={} of bucket={} id={}", page_sz, f.bucket, f.id());
+            store.set_bucket_page_size(&f.bucket, page_sz)?;
+            return Ok(());
+        }
+
+        if !kind.is_main() {
+            return Err(mojokv::Error::PageSizeUnknownErr.into());
+        }
+
+        let page_sz = match crate::vfsfile::header_page_size(off, buf) {
+            Some(sz) => sz,
+            None => return Err(mojokv::Error::PageSizeUnknownErr.into()),
+        };
+
+        log::debug!("learnt page size={} from header of id={}", page_sz, f.id());
+        let store = f.store()?;
+        store.set_page_size(page_sz)?;
+
+        if let Some(entry) = f.store_key().and_then(|k| self.stores.get_mut(k)) {
+            entry.fopt.pagesz = page_sz;
+        }
+
+        Ok(())
+    }
+
+    /// Opens the shared memory for the version the file belongs to
+    pub fn open_shm(&self, f: &VFSFile) -> Result<Shm, Error> {
+        let store = f.store()?;
+        let ver = match f.store_key().and_then(|k| k.ver) {
+            Some(ver) => ver,
+            None => store.active_ver(),
+        };
+
+        Ok(store.shm_of(f.db(), ver)?)
+    }
+
+    // Without batch atomic writes in the sqlite the fs runs in, sqlite
+    // never sends a batch and every transaction uses the journal
+    fn batch_atomic() -> bool {
+        let supported = crate::api::batch_atomic_supported();
+        if !supported {
+            static WARN: std::sync::Once = std::sync::Once::new();
+            WARN.call_once(|| log::warn!("sqlite is built without SQLITE_ENABLE_BATCH_ATOMIC_WRITE, atomic writes are off"));
+        }
+        supported
+    }
+
+    // Bucket of a file is named by its path relative to the directory the
+    // store is in, which is where sqlite puts the journals and the wal of
+    // the main db. Relative paths are taken as relative to that directory.
+    // Files outside of it are not kept in the store.
+    fn bucket_name(root_path: &Path, path: &Path) -> Result<String, Error> {
+        let root_path = normalize(&std::env::current_dir()?.join(root_path));
+        let base = root_path.parent().unwrap_or(&root_path);
+        let path = normalize(&base.join(path));
+
+        let rel = match path.strip_prefix(base) {
+            Ok(rel) if !rel.as_os_str().is_empty() => rel,
+            _ => return Err(Error::new(error::MOJOFS_ERR_PATH_OUTSIDE_STORE,
+                    format!("file {:?} is outside the directory of store {:?}", path, root_path))),
+        };
+
+        let mut parts = Vec::new();
+        for c in rel.components() {
+            let part = c.as_os_str().to_str()
+                .ok_or_else(|| Error::new(error::MOJOFS_ERR_UTF8, format!("file {:?} is not utf8", path)))?;
+            parts.push(part);
+        }
+
+        Ok(parts.join("/"))
+    }
+
+    // Store and bucket of a file, None if it is not named after an open
+    // main db or is outside the store
+    fn bucket_of_path(&self, path: &Path) -> Result<Option<(&Store, String)>, Error> {
+        let (key, db_bucket) = match self.resolve(path.to_str().unwrap_or_default(), OpenKind::MainJournal, None)? {
+            Some(target) => target,
+            None => return Ok(None),
+        };
+
+        let store = &self.entry(&key)?.store;
+        if let Some(name) = db_bucket {
+            return Ok(Some((store, name)));
+        }
+
+        match Self::bucket_name(&key.root_path, path) {
+            Ok(name) => Ok(Some((store, name))),
+            Err(err) if err.code == error::MOJOFS_ERR_PATH_OUTSIDE_STORE => Ok(None),
+            Err(err) => Err(err),
+        }
     }
 
     //TODO: add sync dir
     pub fn delete(&mut self, path: &std::path::Path) -> Result<(), Error> {
         log::debug!("delete path={:?}", path);
 
-        let name = Self::bucket_name(path);
-        let store = self.store.as_mut().unwrap();
-        store.delete(name)?;
+        // Journals may be native files next to the store
+        if path.is_absolute() && path.exists() {
+            log::debug!("delete native file={:?}", path);
+            std::fs::remove_file(path)?;
+        }
+
+        if let Some((store, name)) = self.bucket_of_path(path)? {
+            if store.exists(&name) {
+                store.delete(&name)?;
+            }
+        }
 
         Ok(())
     }
@@ -112,9 +493,8 @@ impl VFS {
     pub fn access(&self, path: &std::path::Path, req: AccessCheck) -> Result<bool, Error> {
         log::debug!("access path={:?} req={:?}", path, req);
 
-        let name = Self::bucket_name(path);
-        let store = self.store.as_ref().unwrap();
-        let status = store.exists(name);
+        let bucket = self.bucket_of_path(path)?;
+        let status = (path.is_absolute() && path.exists()) || bucket.is_some_and(|(store, n)| store.exists(&n));
 
         log::debug!("access path={:?} status={}", path, status);
         Ok(status)
@@ -126,60 +506,239 @@ impl VFS {
 
         let bucket_name = f.bucket.clone();
         let opt = f.opt();
+        let store = f.store().ok().cloned();
+        let key = f.store_key().cloned();
 
         f.close()?;
 
-        let store = self.store.as_mut().unwrap();
-        if opt.delete_on_close {
-            log::debug!("close_on_delete is set for id={}", fid);
-            store.delete(bucket_name.as_str())?;
+        if let Some(store) = store {
+            if opt.delete_on_close {
+                log::debug!("close_on_delete is set for id={}", fid);
+                store.delete(bucket_name.as_str())?;
+            }
+        }
+
+        // The last file of a store drops it so the next init reloads it
+        if let Some(key) = key {
+            let closed = match self.stores.get_mut(&key) {
+                Some(entry) => {
+                    entry.nfiles = entry.nfiles.saturating_sub(1);
+                    entry.nfiles == 0
+                },
+                None => false,
+            };
+            if closed {
+                log::debug!("close: dropping store key={:?}", key);
+                self.stores.remove(&key);
+                self.db_paths.retain(|_, e| e.key != key);
+            }
         }
 
         Ok(())
     }
 
-    pub fn commit(&mut self) -> Result<(), Error> {
-        let store = self.store.as_mut().unwrap();
-        store.commit()?;
+    /// Commits the store `key`
+    pub fn commit(&mut self, key: &StoreKey) -> Result<(), Error> {
+        self.entry(key)?.store.commit()?;
         Ok(())
     }
 
 }
 
 
+// Resolves the `.` and `..` components without looking at the file system
+fn normalize(path: &Path) -> PathBuf {
+    let mut out = PathBuf::new();
+    for c in path.components() {
+        match c {
+            Component::CurDir => {},
+            Component::ParentDir => { out.pop(); },
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// Where the files other than the main db are stored
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum FilePolicy {
+    /// Versioned bucket in the store
+    #[default]
+    Kv,
+    /// Regular file at the path given by sqlite
+    Native,
+    /// In memory, gone once the file is closed
+    Mem,
+}
+
+impl FilePolicy {
+    fn parse(name: &str, s: &str, allow_mem: bool) -> Result<Self, Error> {
+        match s {
+            "kv" => Ok(FilePolicy::Kv),
+            "native" => Ok(FilePolicy::Native),
+            "mem" if allow_mem => Ok(FilePolicy::Mem),
+            _ => Err(Error::new(error::MOJOFS_ERR_ARG_FILE_POLICY,
+                    format!("invalid value for {}: {}", name, s))),
+        }
+    }
+}
+
+/// When the vfs commits the store on its own. Zero disables a limit, the
+/// store is committed when any of the limits is reached.
+#[derive(Default, Clone, Debug, PartialEq)]
+pub struct AutoCommit {
+    /// Write transactions since the last commit
+    pub txns: u64,
+    /// Seconds since the last commit
+    pub secs: u64,
+    /// Distinct pages written since the last commit
+    pub pages: u64,
+}
+
+impl AutoCommit {
+    pub fn is_enabled(&self) -> bool {
+        self.txns > 0 || self.secs > 0 || self.pages > 0
+    }
+}
+
 #[derive(Default, Clone)]
 pub struct FSOptions {
     pub ver: u32,
     pub pagesz: u32,
     pub pps: u32,
+    pub fdlimit: usize,
+    pub prealloc: u64,
+    pub journal: FilePolicy,
+    pub temp: FilePolicy,
+    pub wal: FilePolicy,
+    pub autocommit: AutoCommit,
+    pub txnlog: bool,
+    pub txn: Option<u32>,
+    pub atomic: bool,
+    pub store: Option<String>,
+    pub db: Option<String>,
 }
 
 impl FSOptions {
     fn parse(map: &HashMap<String, String>) -> Result<FSOptions, Error> {
-        let mut opt = FSOptions {ver: 0, pagesz: 4096, pps: 0};
+        let mut opt = FSOptions {
+            ver: 0,
+            pagesz: 0,
+            pps: 0,
+            fdlimit: mojokv::DEFAULT_FD_LIMIT,
+            prealloc: 0,
+            journal: FilePolicy::Native,
+            temp: FilePolicy::Mem,
+            wal: FilePolicy::Kv,
+            autocommit: AutoCommit::default(),
+            txnlog: false,
+            txn: None,
+            atomic: true,
+            store: None,
+            db: None,
+        };
 
         opt.ver = match map.get("ver") {
             Some(s) => s.parse()?,
             None => 1
         };
 
-        let s = map.get("pagesz").ok_or(Error::new(error::MOJOFS_ERR_ARG_PAGESZ_MISSING, 
-                "fs arg page size missing".to_owned()))?;
-        opt.pagesz = s.parse()?;
+        // Without pagesz the page size recorded in the store is used
+        opt.pagesz = match map.get("pagesz") {
+            Some(s) => s.parse()?,
+            None => 0,
+        };
 
         opt.pps = match map.get("pps") {
             Some(s) => s.parse()?,
             None => 65536
         };
 
+        if let Some(s) = map.get("fdlimit") {
+            opt.fdlimit = s.parse()?;
+        }
+
+        if let Some(s) = map.get("prealloc") {
+            opt.prealloc = s.parse()?;
+        }
+
+        // Hot journals and the wal have to survive a crash
+        if let Some(s) = map.get("journal") {
+            opt.journal = FilePolicy::parse("journal", s, false)?;
+        }
+
+        if let Some(s) = map.get("temp") {
+            opt.temp = FilePolicy::parse("temp", s, true)?;
+        }
+
+        if let Some(s) = map.get("wal") {
+            opt.wal = FilePolicy::parse("wal", s, false)?;
+        }
+
+        if let Some(s) = map.get("autocommit_txns") {
+            opt.autocommit.txns = s.parse()?;
+        }
+
+        if let Some(s) = map.get("autocommit_secs") {
+            opt.autocommit.secs = s.parse()?;
+        }
+
+        if let Some(s) = map.get("autocommit_pages") {
+            opt.autocommit.pages = s.parse()?;
+        }
+
+        if let Some(s) = map.get("txnlog") {
+            opt.txnlog = s.parse::<u32>()? != 0;
+        }
+
+        if let Some(s) = map.get("txn") {
+            opt.txn = Some(s.parse()?);
+        }
+
+        if let Some(s) = map.get("atomic") {
+            opt.atomic = s.parse::<u32>()? != 0;
+        }
+
+        // Several dbs can live in one store, each under its own name
+        opt.store = map.get("store").cloned();
+        opt.db = map.get("db").cloned();
+
         Ok(opt)
     }
 
-    fn to_kvfile_opt(&self) -> KVFileOpt {
-        KVFileOpt {
-            ver: self.ver,
-            page_sz: self.pagesz,
-            pps: self.pps,
+    // First param of `other` that differs from these options. The version,
+    // txn and store pick the store, the db the file in it, and a page size
+    // of 0 takes the one of the store, so these are not compared.
+    fn conflict(&self, other: &FSOptions) -> Option<&'static str> {
+        if self.pps != other.pps {
+            Some("pps")
+        }else if self.fdlimit != other.fdlimit {
+            Some("fdlimit")
+        }else if self.prealloc != other.prealloc {
+            Some("prealloc")
+        }else if self.journal != other.journal {
+            Some("journal")
+        }else if self.temp != other.temp {
+            Some("temp")
+        }else if self.wal != other.wal {
+            Some("wal")
+        }else if self.autocommit != other.autocommit {
+            Some("autocommit")
+        }else if self.txnlog != other.txnlog {
+            Some("txnlog")
+        }else if self.atomic != other.atomic {
+            Some("atomic")
+        }else{
+            None
+        }
+    }
+
+    pub fn policy(&self, kind: OpenKind) -> FilePolicy {
+        match kind {
+            OpenKind::MainDb => FilePolicy::Kv,
+            OpenKind::MainJournal | OpenKind::SuperJournal => self.journal,
+            OpenKind::Wal => self.wal,
+            OpenKind::TempDb | OpenKind::TempJournal | OpenKind::TransientDb | OpenKind::SubJournal => self.temp,
         }
     }
 }
\ No newline at end of file
@@ -1,12 +1,39 @@
-use crate::error::Error;
+use std::collections::HashSet;
+use std::time::{Duration, Instant};
+use mojokv::{DbLock, LockLevel, Shm, Store};
+use crate::vfs::{AutoCommit, StoreKey};
+use crate::error::{self, Error};
 
 use crate::native_file::NativeFile;
-use crate::kvfile::KVFile;
+use crate::mem_file::MemFile;
+use crate::kvfile::{KVFile, OpenBuckets};
 use crate::open_options::OpenOptions;
 
+const SQLITE_HEADER: &[u8] = b"SQLite format 3\0";
+
+/// Page size stored in bytes 16-17 of the sqlite header if `buf` is a
+/// write of the header at offset 0. The value 1 stands for 65536.
+pub fn header_page_size(off: u64, buf: &[u8]) -> Option<u32> {
+    if off != 0 || buf.len() < 18 || &buf[..16] != SQLITE_HEADER {
+        return None;
+    }
+
+    let page_sz = match u16::from_be_bytes([buf[16], buf[17]]) {
+        1 => 65536,
+        sz => sz as u32,
+    };
+
+    if !(512..=65536).contains(&page_sz) || !page_sz.is_power_of_two() {
+        return None;
+    }
+
+    Some(page_sz)
+}
+
 pub enum FileImpl {
     Reg(NativeFile),
-    KV(KVFile)
+    Mem(MemFile),
+    KV(Box<KVFile>)
 }
 
 pub struct VFSFile {
@@ -14,6 +41,30 @@ pub struct VFSFile {
     id: usize,
     fimpl: FileImpl,
     opt: OpenOptions,
+    dblock: Option<DbLock>,
+    shm: Option<Shm>,
+    store: Option<(StoreKey, Store)>,
+    // Kv files of the store, synced before a commit made through this file
+    open_buckets: Option<OpenBuckets>,
+    // Name of the db of a main db file opened with one
+    db: Option<String>,
+    immutable: bool,
+    autocommit: Option<AutoCommitState>,
+    batch_atomic: bool,
+    // Writes buffered between begin and commit of an atomic write
+    batch: Option<Vec<(u64, Vec<u8>)>>,
+}
+
+// Progress towards the next automatic commit
+struct AutoCommitState {
+    policy: AutoCommit,
+    txns: u64,
+    // Pages written since the last commit, a page written again is
+    // counted once
+    pages: HashSet<u64>,
+    last_commit: Instant,
+    // Written to in the current transaction
+    dirty: bool,
 }
 
 
@@ -24,13 +75,101 @@ impl VFSFile {
             id,
             fimpl,
             opt,
+            dblock: None,
+            shm: None,
+            store: None,
+            open_buckets: None,
+            db: None,
+            immutable: false,
+            autocommit: None,
+            batch_atomic: false,
+            batch: None,
         }
     }
 
+    /// Lets sqlite write transactions as atomic batches instead of using
+    /// the rollback journal
+    pub fn set_batch_atomic(&mut self, batch_atomic: bool) {
+        self.batch_atomic = batch_atomic;
+    }
+
+    pub fn set_immutable(&mut self, immutable: bool) {
+        self.immutable = immutable;
+    }
+
+    pub fn is_immutable(&self) -> bool {
+        self.immutable
+    }
+
+    /// Sets the store a kv file lives in
+    pub fn set_store(&mut self, key: StoreKey, store: Store) {
+        self.store = Some((key, store));
+    }
+
+    pub fn set_open_buckets(&mut self, buckets: OpenBuckets) {
+        self.open_buckets = Some(buckets);
+    }
+
+    pub fn store(&self) -> Result<&Store, Error> {
+        self.store.as_ref().map(|(_, s)| s).ok_or_else(|| Error::new(error::MOJOFS_ERR_NO_STORE,
+                format!("file id={} has no store", self.id)))
+    }
+
+    pub fn store_key(&self) -> Option<&StoreKey> {
+        self.store.as_ref().map(|(k, _)| k)
+    }
+
+    pub fn set_db(&mut self, db: Option<String>) {
+        self.db = db;
+    }
+
+    /// Name of the db in its store, None for the db of a store opened
+    /// without a db name
+    pub fn db(&self) -> Option<&str> {
+        self.db.as_deref()
+    }
+
+    /// Version of the store the file is opened at
+    pub fn version(&self) -> Result<u32, Error> {
+        match self.store_key().and_then(|k| k.ver) {
+            Some(ver) => Ok(ver),
+            None => Ok(self.store()?.active_ver()),
+        }
+    }
+
+    /// Commits the store on its own at the end of write transactions
+    pub fn set_autocommit(&mut self, policy: AutoCommit) {
+        self.autocommit = Some(AutoCommitState {
+            policy,
+            txns: 0,
+            pages: HashSet::new(),
+            last_commit: Instant::now(),
+            dirty: false,
+        });
+    }
+
+    /// Sets the lock used for the sqlite lock calls. Files without a lock,
+    /// e.g. immutable older versions, accept any lock request.
+    pub fn set_db_lock(&mut self, dblock: DbLock) {
+        self.dblock = Some(dblock);
+    }
+
     pub fn id(&self) -> usize {
         self.id
     }
 
+    pub fn is_kv(&self) -> bool {
+        matches!(self.fimpl, FileImpl::KV(_))
+    }
+
+    /// True for a kv file whose store has not learnt the page size yet
+    pub fn needs_page_size(&self) -> bool {
+        match &self.fimpl {
+            FileImpl::KV(f) => f.page_size() == 0,
+            _ => false,
+        }
+    }
+
     pub fn opt(&self) -> OpenOptions {
         self.opt.clone()
     }
@@ -42,6 +181,9 @@ impl VFSFile {
             FileImpl::Reg(f) => {
                 f.pread(buf, off as i64)?
             },
+            FileImpl::Mem(f) => {
+                f.pread(buf, off as i64)?
+            },
             FileImpl::KV(f) => {
                 f.pread(buf, off as i64)?
             }
@@ -53,13 +195,35 @@ impl VFSFile {
 
     pub fn pwrite(&mut self, off: u64, buf: &[u8]) -> Result<(), Error> {
         log::debug!("pwrite id={} o={}, blen={}", self.id, off, buf.len());
+        self.roll_forward()?;
 
         match &mut self.fimpl {
             FileImpl::Reg(f) => {
                 f.pwrite(off as i64, buf)?;
             },
-            FileImpl::KV(f) => {
+            FileImpl::Mem(f) => {
                 f.pwrite(off as i64, buf)?;
+            },
+            FileImpl::KV(f) => {
+                if self.opt.kind.is_main() {
+                    if let Some(page_sz) = header_page_size(off, buf) {
+                        if page_sz != f.page_size() {
+                            return Err(mojokv::Error::PageSizeMismatchErr(f.page_size(), page_sz).into());
+                        }
+                    }
+                }
+
+                match self.batch.as_mut() {
+                    Some(batch) => batch.push((off, buf.to_vec())),
+                    None => f.pwrite(off as i64, buf)?,
+                }
+
+                if let Some(ac) = self.autocommit.as_mut() {
+                    ac.dirty = true;
+                    let page_sz = f.page_size().max(1) as u64;
+                    let end = off + buf.len() as u64;
+                    ac.pages.extend(off / page_sz..end.div_ceil(page_sz));
+                }
             }
         };
 
@@ -73,6 +237,9 @@ impl VFSFile {
             FileImpl::Reg(f) => {
                 f.close()?;
             },
+            FileImpl::Mem(f) => {
+                f.close()?;
+            },
             FileImpl::KV(f) => {
                 f.close()?;
             }
@@ -83,16 +250,36 @@ impl VFSFile {
 
     pub fn sync(&mut self, flags: i32) -> Result<(), Error> {
         log::debug!("sync id={} flags={}", self.id, flags);
+        self.roll_forward()?;
 
         match &mut self.fimpl {
             FileImpl::Reg(f) => {
                 f.sync()?;
             },
+            FileImpl::Mem(f) => {
+                f.sync()?;
+            },
             FileImpl::KV(f) => {
                 f.sync()?;
             }
         };
 
+        // Checkpoints in wal mode write the db without a write lock
+        if self.dblock.as_ref().map(|l| l.level() <= LockLevel::Shared).unwrap_or(false) {
+            self.end_txn()?;
+        }
+
+        Ok(())
+    }
+
+    // Logs the pages written by the transaction which just ended, done
+    // while it still holds its lock
+    fn end_txn(&mut self) -> Result<(), Error> {
+        if let FileImpl::KV(f) = &mut self.fimpl {
+            if let Some(seq) = f.bucket.lock().end_txn()? {
+                log::debug!("txn id={} logged seq={}", self.id, seq);
+            }
+        }
         Ok(())
     }
 
@@ -101,6 +288,9 @@ impl VFSFile {
             FileImpl::Reg(f) => {
                 f.filesize()?
             },
+            FileImpl::Mem(f) => {
+                f.filesize()?
+            },
             FileImpl::KV(f) => {
                 f.filesize()?
             }
@@ -111,11 +301,15 @@ impl VFSFile {
 
     pub fn truncate(&mut self, new_sz: u64) -> Result<(), Error> {
         log::debug!("truncate id={} {}", self.id, new_sz);
+        self.roll_forward()?;
 
         match &mut self.fimpl {
             FileImpl::Reg(f) => {
                 f.truncate(new_sz)?;
             },
+            FileImpl::Mem(f) => {
+                f.truncate(new_sz)?;
+            },
             FileImpl::KV(f) => {
                 f.truncate(new_sz)?;
             }
@@ -124,19 +318,296 @@ impl VFSFile {
         Ok(())
     }
 
-    pub fn lock(&mut self, _flag: i32) -> Result<(), Error> {
+    pub fn fetch(&mut self, off: u64, amt: usize) -> Result<Option<*const u8>, Error> {
+        log::debug!("fetch id={} o={}, amt={}", self.id, off, amt);
+
+        let ptr = match &mut self.fimpl {
+            FileImpl::Reg(_) | FileImpl::Mem(_) => None,
+            FileImpl::KV(f) => {
+                f.fetch(off as i64, amt)?
+            }
+        };
+
+        Ok(ptr)
+    }
+
+    pub fn unfetch(&mut self, off: u64, ptr: *const u8) -> Result<(), Error> {
+        match &mut self.fimpl {
+            FileImpl::Reg(_) | FileImpl::Mem(_) => {},
+            FileImpl::KV(f) => {
+                f.unfetch(off as i64, ptr);
+            }
+        };
+
         Ok(())
     }
 
-    pub fn unlock(&mut self, _flag: i32) -> Result<(), Error> {
+    fn lock_level(flag: i32) -> Result<LockLevel, Error> {
+        LockLevel::from_i32(flag).ok_or_else(|| Error::new(error::MOJOFS_ERR_ARG_LOCK,
+                format!("invalid lock level {}", flag)))
+    }
+
+    /// Returns false if the lock is held by another connection
+    pub fn lock(&mut self, flag: i32) -> Result<bool, Error> {
+        log::debug!("lock id={} flag={}", self.id, flag);
+
+        let level = Self::lock_level(flag)?;
+        let (prev, ok) = match self.dblock.as_mut() {
+            Some(l) => (l.level(), l.lock(level)?),
+            None => return Ok(true),
+        };
+
+        // Another process may have committed since the last transaction
+        if ok && prev == LockLevel::None && self.is_kv() {
+            self.store()?.refresh()?;
+        }
+
+        Ok(ok)
+    }
+
+    pub fn unlock(&mut self, flag: i32) -> Result<(), Error> {
+        log::debug!("unlock id={} flag={}", self.id, flag);
+
+        let level = Self::lock_level(flag)?;
+        if level == LockLevel::None {
+            self.end_txn()?;
+        }
+
+        if let Some(l) = self.dblock.as_mut() {
+            l.unlock(level)?;
+        }
+
+        if level == LockLevel::None {
+            self.maybe_autocommit()?;
+        }
+
         Ok(())
     }
 
     pub fn check_reserved_lock(&self) -> Result<i32, Error> {
-        Ok(0)
+        let reserved = match self.dblock.as_ref() {
+            Some(l) => l.check_reserved()?,
+            None => false,
+        };
+
+        Ok(reserved as i32)
+    }
+
+    pub fn has_shm(&self) -> bool {
+        self.shm.is_some()
+    }
+
+    /// Sets the shared memory used in wal mode. It is opened on the first
+    /// shm_map call.
+    pub fn set_shm(&mut self, shm: Shm) {
+        self.shm = Some(shm);
+    }
+
+    fn shm_mut(&mut self) -> Result<&mut Shm, Error> {
+        self.shm.as_mut().ok_or_else(|| Error::new(error::MOJOFS_ERR_SHM_NOT_OPEN,
+                "shm not open".to_owned()))
+    }
+
+    pub fn shm_map(&mut self, region: usize, region_sz: usize, extend: bool) -> Result<Option<*mut u8>, Error> {
+        log::debug!("shm map id={} region={} sz={} extend={}", self.id, region, region_sz, extend);
+
+        let ptr = self.shm_mut()?.map(region, region_sz, extend)?;
+        Ok(ptr)
+    }
+
+    /// Returns false if the lock is held by another connection
+    pub fn shm_lock(&mut self, ofst: usize, n: usize, flags: i32) -> Result<bool, Error> {
+        log::debug!("shm lock id={} ofst={} n={} flags={}", self.id, ofst, n, flags);
+
+        let exclusive = flags & libsqlite3_sys::SQLITE_SHM_EXCLUSIVE != 0;
+        let shm = self.shm_mut()?;
+
+        if flags & libsqlite3_sys::SQLITE_SHM_UNLOCK != 0 {
+            shm.unlock(ofst, n, exclusive)?;
+            return Ok(true);
+        }
+
+        Ok(shm.lock(ofst, n, exclusive)?)
+    }
+
+    pub fn shm_barrier(&self) {
+        if let Some(shm) = self.shm.as_ref() {
+            shm.barrier();
+        }
+    }
+
+    pub fn shm_unmap(&mut self, delete: bool) -> Result<(), Error> {
+        log::debug!("shm unmap id={} delete={}", self.id, delete);
+
+        if let Some(shm) = self.shm.take() {
+            shm.unmap(delete)?;
+        }
+
+        Ok(())
+    }
+
+    // Called once a transaction is over. A store which cannot be committed
+    // now is committed at the end of a later transaction.
+    fn maybe_autocommit(&mut self) -> Result<(), Error> {
+        let ac = match self.autocommit.as_mut() {
+            Some(ac) if ac.dirty => ac,
+            _ => return Ok(()),
+        };
+
+        ac.dirty = false;
+        ac.txns += 1;
+
+        let p = &ac.policy;
+        let due = (p.txns > 0 && ac.txns >= p.txns)
+            || (p.pages > 0 && ac.pages.len() as u64 >= p.pages)
+            || (p.secs > 0 && ac.last_commit.elapsed().as_secs() >= p.secs);
+
+        if !due {
+            return Ok(());
+        }
+
+        match self.commit() {
+            Ok(ver) => {
+                log::debug!("autocommit id={} new ver={}", self.id, ver);
+                if let Some(ac) = self.autocommit.as_mut() {
+                    ac.txns = 0;
+                    ac.pages.clear();
+                    ac.last_commit = Instant::now();
+                }
+                Ok(())
+            },
+            Err(err) if err.code == error::MOJOFS_ERR_BUSY => {
+                log::debug!("autocommit id={} skipped, store is busy", self.id);
+                Ok(())
+            },
+            Err(err) => {
+                // The transaction itself is done, so it does not fail
+                log::error!("autocommit id={} failed err={:?}", self.id, err);
+                Ok(())
+            },
+        }
+    }
+
+    // Buckets opened before a commit move to the new active version on
+    // their next write
+    fn roll_forward(&mut self) -> Result<(), Error> {
+        if let (FileImpl::KV(f), Some((_, store))) = (&mut self.fimpl, self.store.as_ref()) {
+            let mut bucket = f.bucket.lock();
+            if bucket.is_stale() {
+                store.roll_forward(&mut bucket)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Handles the mojo pragmas. Returns None for pragmas which are not
+    /// ours so that sqlite handles them.
+    pub fn pragma(&mut self, name: &str, value: Option<&str>) -> Result<Option<String>, Error> {
+        log::debug!("pragma id={} name={} value={:?}", self.id, name, value);
+
+        let name = name.to_ascii_lowercase();
+
+        // Commits need an empty wal, which autocommit cannot wait for
+        let to_wal = value.map(|v| v.eq_ignore_ascii_case("wal")).unwrap_or(false);
+        if name == "journal_mode" && to_wal && self.autocommit.is_some() {
+            return Err(Error::new(error::MOJOFS_ERR_ARG_CONFLICT, "autocommit does not work in wal mode".to_owned()));
+        }
+
+        if !name.starts_with("mojo_") {
+            return Ok(None);
+        }
+
+        if value.is_some() {
+            return Err(Error::new(error::MOJOFS_ERR_PRAGMA, format!("pragma {} takes no value", name)));
+        }
+
+        let res = match name.as_str() {
+            "mojo_version" => {
+                self.version()?.to_string()
+            },
+            "mojo_versions" => {
+                let vers: Vec<String> = self.store()?.versions().iter().map(|v| v.to_string()).collect();
+                vers.join(",")
+            },
+            "mojo_commit" => {
+                self.commit()?.to_string()
+            },
+            _ => {
+                return Err(Error::new(error::MOJOFS_ERR_PRAGMA, format!("unknown pragma {}", name)));
+            }
+        };
+
+        Ok(Some(res))
+    }
+
+    // Commits the store of the file. The file must not be in a transaction
+    // and the commit holds an exclusive lock so no other connection is in
+    // one either.
+    fn commit(&mut self) -> Result<u32, Error> {
+        let store = self.store()?.clone();
+
+        let is_write = matches!(self.store_key(), Some(StoreKey { ver: None, .. }));
+        let level = match self.dblock.as_ref() {
+            Some(dblock) if is_write => dblock.level(),
+            _ => return Err(mojokv::Error::StoreNotWritableErr.into()),
+        };
+
+        // Nothing is synced while a write transaction is open, its pages
+        // must not end up on disk half written
+        if level > LockLevel::Shared {
+            return Err(mojokv::Error::TxnOpenErr.into());
+        }
+
+        self.sync(0)?;
+
+        // Pages written through the other files of the store, e.g. the
+        // other dbs in it, go in the version too
+        if let (Some(buckets), FileImpl::KV(f)) = (self.open_buckets.as_ref(), &self.fimpl) {
+            buckets.sync(&store, &f.bucket)?;
+        }
+
+        // Other connections are not waited for, sqlite retries on busy
+        let dblock = self.dblock.as_mut().unwrap();
+        let ver = match store.commit_txn(dblock, Duration::ZERO) {
+            Ok(ver) => ver,
+            Err(mojokv::Error::DbBusyErr) => {
+                return Err(Error::new(error::MOJOFS_ERR_BUSY, "database is locked".to_owned()));
+            },
+            Err(err) => return Err(err.into()),
+        };
+
+        log::debug!("pragma commit id={} new ver={}", self.id, ver);
+        Ok(ver)
+    }
+
+    pub fn begin_atomic_write(&mut self) -> Result<(), Error> {
+        log::debug!("begin atomic write id={}", self.id);
+
+        if !self.batch_atomic {
+            return Err(Error::new(error::MOJOFS_ERR_BATCH, "atomic writes are not enabled".to_owned()));
+        }
+
+        self.roll_forward()?;
+        self.batch = Some(Vec::new());
+        Ok(())
     }
 
-    pub fn file_control(&mut self, _op: i32) -> Result<(), Error> {
+    /// Publishes the buffered writes. On failure sqlite rolls the batch
+    /// back and writes the transaction again with the journal.
+    pub fn commit_atomic_write(&mut self) -> Result<(), Error> {
+        let batch = self.batch.take().ok_or_else(|| Error::new(error::MOJOFS_ERR_BATCH,
+                "no atomic write in progress".to_owned()))?;
+        log::debug!("commit atomic write id={} writes={}", self.id, batch.len());
+
+        match &mut self.fimpl {
+            FileImpl::KV(f) => f.pwrite_batch(&batch),
+            _ => Err(Error::new(error::MOJOFS_ERR_BATCH, "atomic writes need a kv file".to_owned())),
+        }
+    }
+
+    pub fn rollback_atomic_write(&mut self) -> Result<(), Error> {
+        log::debug!("rollback atomic write id={}", self.id);
+        self.batch = None;
         Ok(())
     }
 
@@ -144,7 +615,18 @@ impl VFSFile {
         Ok(0)
     }
 
-    pub fn device_char(&self) -> Result<(), Error> {
-        Ok(())
+    /// Files of committed versions never change, sqlite then skips locks,
+    /// journals and the wal for them. The writable main db takes atomic
+    /// batches so sqlite can skip the rollback journal.
+    pub fn device_char(&self) -> Result<i32, Error> {
+        let flags = if self.immutable {
+            libsqlite3_sys::SQLITE_IOCAP_IMMUTABLE
+        }else if self.batch_atomic {
+            libsqlite3_sys::SQLITE_IOCAP_BATCH_ATOMIC
+        }else{
+            0
+        };
+
+        Ok(flags)
     }
 }
@@ -0,0 +1,421 @@
+//! Eponymous virtual tables which expose the metadata of the store behind
+//! a mojo database:
+//!
+//! * `mojo_versions(version, buckets, pages)`: buckets and pages changed per version
+//! * `mojo_buckets(version, bucket, bucket_ver)`: bucket map of every version
+//! * `mojo_pages(bucket, key, version, offset)`: index of every bucket at a version
+//!
+//! The tables read the store of the `main` database unless the hidden
+//! `schema` column names another one, e.g. `select * from mojo_versions('aux')`.
+//! `mojo_pages` also takes the version to show as its first argument and
+//! defaults to the version the database is opened at.
+
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_int, c_void};
+
+use libsqlite3_sys::{
+    self,
+    sqlite3,
+    sqlite3_context,
+    sqlite3_file,
+    sqlite3_index_info,
+    sqlite3_int64,
+    sqlite3_module,
+    sqlite3_value,
+    sqlite3_vtab,
+    sqlite3_vtab_cursor};
+
+use mojokv::Store;
+use crate::api::api;
+use crate::error::{self, Error};
+
+#[derive(Clone, Copy)]
+enum Kind {
+    Versions,
+    Buckets,
+    Pages,
+}
+
+impl Kind {
+    fn from_ptr(p: *mut c_void) -> Kind {
+        match p as usize {
+            0 => Kind::Versions,
+            1 => Kind::Buckets,
+            _ => Kind::Pages,
+        }
+    }
+
+    fn schema(&self) -> &'static str {
+        match self {
+            Kind::Versions => "CREATE TABLE x(version INTEGER, buckets INTEGER, pages INTEGER, schema HIDDEN)",
+            Kind::Buckets => "CREATE TABLE x(version INTEGER, bucket TEXT, bucket_ver INTEGER, schema HIDDEN)",
+            Kind::Pages => "CREATE TABLE x(bucket TEXT, key INTEGER, version INTEGER, offset INTEGER, at HIDDEN, schema HIDDEN)",
+        }
+    }
+
+    // Index of the first hidden column
+    fn nvisible(&self) -> usize {
+        match self {
+            Kind::Versions | Kind::Buckets => 3,
+            Kind::Pages => 4,
+        }
+    }
+
+    fn nhidden(&self) -> usize {
+        match self {
+            Kind::Versions | Kind::Buckets => 1,
+            Kind::Pages => 2,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+enum Cell {
+    Null,
+    Int(i64),
+    Text(String),
+}
+
+#[repr(C)]
+struct MojoVTab {
+    base: sqlite3_vtab,
+    db: *mut sqlite3,
+    kind: Kind,
+}
+
+#[repr(C)]
+struct MojoCursor {
+    base: sqlite3_vtab_cursor,
+    rows: Vec<Vec<Cell>>,
+    row: usize,
+    // Values of the hidden columns
+    args: Vec<Cell>,
+}
+
+static MODULE: sqlite3_module = sqlite3_module {
+    iVersion: 0,
+    xCreate: None,
+    xConnect: Some(vtab_connect),
+    xBestIndex: Some(vtab_best_index),
+    xDisconnect: Some(vtab_disconnect),
+    xDestroy: None,
+    xOpen: Some(vtab_open),
+    xClose: Some(vtab_close),
+    xFilter: Some(vtab_filter),
+    xNext: Some(vtab_next),
+    xEof: Some(vtab_eof),
+    xColumn: Some(vtab_column),
+    xRowid: Some(vtab_rowid),
+    xUpdate: None,
+    xBegin: None,
+    xSync: None,
+    xCommit: None,
+    xRollback: None,
+    xFindFunction: None,
+    xRename: None,
+    xSavepoint: None,
+    xRelease: None,
+    xRollbackTo: None,
+    xShadowName: None,
+};
+
+/// Registers the mojo virtual tables on the connection
+///
+/// # Safety
+/// `db` must be an open sqlite connection.
+#[no_mangle]
+pub unsafe extern "C" fn mojo_register_vtabs(db: *mut sqlite3) -> c_int {
+    crate::guard("mojo_register_vtabs", libsqlite3_sys::SQLITE_ERROR, || {
+        let tables = [("mojo_versions", Kind::Versions), ("mojo_buckets", Kind::Buckets), ("mojo_pages", Kind::Pages)];
+
+        for (name, kind) in tables {
+            let cname = match CString::new(name) {
+                Ok(cname) => cname,
+                Err(_) => return libsqlite3_sys::SQLITE_ERROR,
+            };
+            let rc = (api().create_module)(db, cname.as_ptr(), &MODULE, kind as usize as *mut c_void);
+
+            if rc != libsqlite3_sys::SQLITE_OK {
+                log::error!("failed to register vtab={} rc={}", name, rc);
+                return rc;
+            }
+        }
+
+        libsqlite3_sys::SQLITE_OK
+    })
+}
+
+unsafe extern "C" fn vtab_connect(db: *mut sqlite3, aux: *mut c_void, _argc: c_int, _argv: *const *const c_char,
+    pp_vtab: *mut *mut sqlite3_vtab, _err: *mut *mut c_char) -> c_int {
+
+    crate::guard("vtab_connect", libsqlite3_sys::SQLITE_ERROR, || {
+        let kind = Kind::from_ptr(aux);
+        let schema = match CString::new(kind.schema()) {
+            Ok(schema) => schema,
+            Err(_) => return libsqlite3_sys::SQLITE_ERROR,
+        };
+
+        let rc = (api().declare_vtab)(db, schema.as_ptr());
+        if rc != libsqlite3_sys::SQLITE_OK {
+            return rc;
+        }
+
+        let vtab = Box::new(MojoVTab {
+            base: std::mem::zeroed(),
+            db,
+            kind,
+        });
+        *pp_vtab = Box::into_raw(vtab) as *mut sqlite3_vtab;
+
+        libsqlite3_sys::SQLITE_OK
+    })
+}
+
+unsafe extern "C" fn vtab_disconnect(vtab: *mut sqlite3_vtab) -> c_int {
+    crate::guard("vtab_disconnect", libsqlite3_sys::SQLITE_ERROR, || {
+        drop(Box::from_raw(vtab as *mut MojoVTab));
+        libsqlite3_sys::SQLITE_OK
+    })
+}
+
+// The hidden columns are arguments. idxNum has a bit set for each hidden
+// column with an equality constraint, the values are passed to xFilter in
+// column order.
+unsafe extern "C" fn vtab_best_index(vtab: *mut sqlite3_vtab, info: *mut sqlite3_index_info) -> c_int {
+    crate::guard("vtab_best_index", libsqlite3_sys::SQLITE_ERROR, || {
+        let kind = (*(vtab as *mut MojoVTab)).kind;
+        let info = &mut *info;
+
+        let mut arg_cons = vec![None; kind.nhidden()];
+        for i in 0..info.nConstraint as usize {
+            let cons = &*info.aConstraint.add(i);
+            let col = cons.iColumn;
+
+            if cons.usable == 0 || cons.op as c_int != libsqlite3_sys::SQLITE_INDEX_CONSTRAINT_EQ || col < kind.nvisible() as c_int {
+                continue;
+            }
+
+            arg_cons[col as usize - kind.nvisible()] = Some(i);
+        }
+
+        let mut idx_num = 0;
+        let mut argv_index = 1;
+        for (arg, cons) in arg_cons.iter().enumerate() {
+            if let Some(i) = cons {
+                let usage = &mut *info.aConstraintUsage.add(*i);
+                usage.argvIndex = argv_index;
+                usage.omit = 1;
+                argv_index += 1;
+                idx_num |= 1 << arg;
+            }
+        }
+
+        info.idxNum = idx_num;
+        info.estimatedCost = 1000.0 / argv_index as f64;
+
+        libsqlite3_sys::SQLITE_OK
+    })
+}
+
+unsafe extern "C" fn vtab_open(_vtab: *mut sqlite3_vtab, pp_cursor: *mut *mut sqlite3_vtab_cursor) -> c_int {
+    crate::guard("vtab_open", libsqlite3_sys::SQLITE_ERROR, || {
+        let cursor = Box::new(MojoCursor {
+            base: std::mem::zeroed(),
+            rows: Vec::new(),
+            row: 0,
+            args: Vec::new(),
+        });
+        *pp_cursor = Box::into_raw(cursor) as *mut sqlite3_vtab_cursor;
+
+        libsqlite3_sys::SQLITE_OK
+    })
+}
+
+unsafe extern "C" fn vtab_close(cursor: *mut sqlite3_vtab_cursor) -> c_int {
+    crate::guard("vtab_close", libsqlite3_sys::SQLITE_ERROR, || {
+        drop(Box::from_raw(cursor as *mut MojoCursor));
+        libsqlite3_sys::SQLITE_OK
+    })
+}
+
+unsafe extern "C" fn vtab_filter(cursor: *mut sqlite3_vtab_cursor, idx_num: c_int, _idx_str: *const c_char,
+    _argc: c_int, argv: *mut *mut sqlite3_value) -> c_int {
+
+    crate::guard("vtab_filter", libsqlite3_sys::SQLITE_ERROR, || {
+        let cursor = &mut *(cursor as *mut MojoCursor);
+        let vtab = &mut *(cursor.base.pVtab as *mut MojoVTab);
+        let kind = vtab.kind;
+
+        let mut args = vec![Cell::Null; kind.nhidden()];
+        let mut argi = 0;
+        for (i, arg) in args.iter_mut().enumerate() {
+            if idx_num & (1 << i) != 0 {
+                *arg = value_cell(*argv.add(argi));
+                argi += 1;
+            }
+        }
+
+        // The schema is the last hidden column, the version to show the first
+        let schema = match &args[kind.nhidden() - 1] {
+            Cell::Text(s) => s.clone(),
+            _ => "main".to_owned(),
+        };
+        let at = match &args[0] {
+            Cell::Int(v) if kind.nhidden() > 1 => Some(*v as u32),
+            _ => None,
+        };
+
+        let res = file_store(vtab.db, &schema).and_then(|(store, ver)| {
+            match kind {
+                Kind::Versions => versions_rows(&store),
+                Kind::Buckets => buckets_rows(&store),
+                Kind::Pages => pages_rows(&store, at.unwrap_or(ver)),
+            }
+        });
+
+        match res {
+            Ok(rows) => {
+                cursor.rows = rows;
+                cursor.row = 0;
+                cursor.args = args;
+                libsqlite3_sys::SQLITE_OK
+            },
+            Err(err) => {
+                log::error!("vtab filter schema={} err={:?}", schema, err);
+                (api().free)(vtab.base.zErrMsg as *mut c_void);
+                vtab.base.zErrMsg = crate::sqlite_str(&err.msg);
+                libsqlite3_sys::SQLITE_ERROR
+            },
+        }
+    })
+}
+
+unsafe extern "C" fn vtab_next(cursor: *mut sqlite3_vtab_cursor) -> c_int {
+    crate::guard("vtab_next", libsqlite3_sys::SQLITE_ERROR, || {
+        let cursor = &mut *(cursor as *mut MojoCursor);
+        cursor.row += 1;
+        libsqlite3_sys::SQLITE_OK
+    })
+}
+
+unsafe extern "C" fn vtab_eof(cursor: *mut sqlite3_vtab_cursor) -> c_int {
+    crate::guard("vtab_eof", 0, || {
+        let cursor = &*(cursor as *mut MojoCursor);
+        (cursor.row >= cursor.rows.len()) as c_int
+    })
+}
+
+unsafe extern "C" fn vtab_column(cursor: *mut sqlite3_vtab_cursor, ctx: *mut sqlite3_context, col: c_int) -> c_int {
+    crate::guard("vtab_column", libsqlite3_sys::SQLITE_ERROR, || {
+        let cursor = &*(cursor as *mut MojoCursor);
+        let row = &cursor.rows[cursor.row];
+        let col = col as usize;
+
+        let cell = if col < row.len() {
+            &row[col]
+        }else{
+            &cursor.args[col - row.len()]
+        };
+
+        match cell {
+            Cell::Null => (api().result_null)(ctx),
+            Cell::Int(v) => (api().result_int64)(ctx, *v),
+            Cell::Text(s) => (api().result_text)(ctx, s.as_ptr() as *const c_char, s.len() as c_int,
+                libsqlite3_sys::SQLITE_TRANSIENT()),
+        }
+
+        libsqlite3_sys::SQLITE_OK
+    })
+}
+
+unsafe extern "C" fn vtab_rowid(cursor: *mut sqlite3_vtab_cursor, rowid: *mut sqlite3_int64) -> c_int {
+    crate::guard("vtab_rowid", libsqlite3_sys::SQLITE_ERROR, || {
+        let cursor = &*(cursor as *mut MojoCursor);
+        *rowid = cursor.row as sqlite3_int64;
+        libsqlite3_sys::SQLITE_OK
+    })
+}
+
+unsafe fn value_cell(val: *mut sqlite3_value) -> Cell {
+    match (api().value_type)(val) {
+        libsqlite3_sys::SQLITE_INTEGER => Cell::Int((api().value_int64)(val)),
+        libsqlite3_sys::SQLITE_TEXT => {
+            let s = CStr::from_ptr((api().value_text)(val) as *const c_char);
+            Cell::Text(s.to_string_lossy().into_owned())
+        },
+        _ => Cell::Null,
+    }
+}
+
+// Store and version of the database file of `schema`
+fn file_store(db: *mut sqlite3, schema: &str) -> Result<(Store, u32), Error> {
+    let not_mojo = || Error::new(error::MOJOFS_ERR_NOT_MOJO, format!("{} is not a mojo database", schema));
+
+    let cschema = CString::new(schema).map_err(|_| not_mojo())?;
+    let mut sfile: *mut sqlite3_file = std::ptr::null_mut();
+
+    let rc = unsafe {
+        (api().file_control)(db, cschema.as_ptr(), libsqlite3_sys::SQLITE_FCNTL_FILE_POINTER,
+            &mut sfile as *mut *mut sqlite3_file as *mut c_void)
+    };
+
+    if rc != libsqlite3_sys::SQLITE_OK || sfile.is_null() || !crate::is_mojo_file(sfile) {
+        return Err(not_mojo());
+    }
+
+    let file = crate::get_file(sfile)?;
+    Ok((file.store()?.clone(), file.version()?))
+}
+
+fn versions_rows(store: &Store) -> Result<Vec<Vec<Cell>>, Error> {
+    let mut rows = Vec::new();
+
+    for ver in store.versions() {
+        let bmap = store.bucket_map(ver)?.map()?;
+
+        // Only the buckets changed at the version have pages of it
+        let mut pages = 0;
+        for (name, _) in bmap.iter().filter(|(_, bver)| **bver == ver) {
+            if let Some(index) = store.get_index_at(name, ver)? {
+                pages += index.iter(0, 0).filter(|(_, v)| v.get_ver() == ver).count();
+            }
+        }
+
+        rows.push(vec![Cell::Int(ver as i64), Cell::Int(bmap.len() as i64), Cell::Int(pages as i64)]);
+    }
+
+    Ok(rows)
+}
+
+fn buckets_rows(store: &Store) -> Result<Vec<Vec<Cell>>, Error> {
+    let mut rows = Vec::new();
+
+    for ver in store.versions() {
+        let mut bmap: Vec<(String, u32)> = store.bucket_map(ver)?.map()?.into_iter().collect();
+        bmap.sort();
+
+        for (name, bver) in bmap {
+            rows.push(vec![Cell::Int(ver as i64), Cell::Text(name), Cell::Int(bver as i64)]);
+        }
+    }
+
+    Ok(rows)
+}
+
+fn pages_rows(store: &Store, ver: u32) -> Result<Vec<Vec<Cell>>, Error> {
+    let mut rows = Vec::new();
+
+    let mut names: Vec<String> = store.bucket_map(ver)?.map()?.into_keys().collect();
+    names.sort();
+
+    for name in names {
+        if let Some(index) = store.get_index_at(&name, ver)? {
+            for (key, val) in index.iter(0, 0) {
+                rows.push(vec![Cell::Text(name.clone()), Cell::Int(key as i64),
+                    Cell::Int(val.get_ver() as i64), Cell::Int(val.get_off() as i64)]);
+            }
+        }
+    }
+
+    Ok(rows)
+}
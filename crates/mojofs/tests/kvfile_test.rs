@@ -0,0 +1,210 @@
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+use std::collections::HashMap;
+use proptest::prelude::*;
+use mojofs::{VFS, VFSFile, DbKey};
+
+const PAGESZ: u64 = 16;
+const MAX_OFF: u64 = 40 * PAGESZ;
+const MAX_LEN: usize = 5 * PAGESZ as usize;
+
+#[derive(Debug, Clone)]
+enum Op {
+    Write(u64, Vec<u8>),
+    Read(u64, usize),
+    Truncate(u64),
+    Commit,
+}
+
+fn op() -> impl Strategy<Value = Op> {
+    prop_oneof![
+        4 => (0..MAX_OFF, proptest::collection::vec(any::<u8>(), 1..MAX_LEN)).prop_map(|(off, buf)| Op::Write(off, buf)),
+        4 => (0..MAX_OFF + PAGESZ, 0..MAX_LEN).prop_map(|(off, len)| Op::Read(off, len)),
+        1 => (0..MAX_OFF).prop_map(Op::Truncate),
+        1 => Just(Op::Commit),
+    ]
+}
+
+fn params() -> HashMap<String, String> {
+    let mut h = HashMap::new();
+    h.insert("ver".to_owned(), "1".to_owned());
+    h.insert("pagesz".to_owned(), format!("{}", PAGESZ));
+    h.insert("pps".to_owned(), "4".to_owned());
+    h
+}
+
+fn open(fs: &mut VFS, db: &DbKey) -> Box<VFSFile> {
+    let mut opt = mojofs::OpenOptions::from_flags(326).unwrap();
+    fs.open("a", Some(db), opt.clone(), &mut opt).unwrap()
+}
+
+fn check_read(file: &VFSFile, plain: &std::fs::File, off: u64, len: usize) {
+    let mut expected = vec![0u8; len];
+    let mut n = 0;
+    while n < len {
+        let m = plain.read_at(&mut expected[n..], off + n as u64).unwrap();
+        if m == 0 {
+            break;
+        }
+        n += m;
+    }
+
+    let mut buf = vec![0xffu8; len];
+    let m = file.pread(off, &mut buf).unwrap();
+    assert_eq!(m, n, "read off={} len={}", off, len);
+    assert_eq!(buf, expected, "read off={} len={}", off, len);
+}
+
+// Runs the ops on the kv file and on a plain file, both must read the same
+fn run(name: &str, ops: &[Op]) {
+    let path = Path::new("./testfs_all").join(name);
+    let _ = std::fs::remove_dir_all(&path);
+    std::fs::create_dir_all(&path).unwrap();
+    let fspath = path.join("kv").to_str().unwrap().to_owned();
+
+    let plain = std::fs::OpenOptions::new().read(true).write(true).create(true).truncate(true)
+        .open(path.join("plain")).unwrap();
+
+    let mut fs = VFS::default();
+    let opt = mojofs::OpenOptions::from_flags(326).unwrap();
+    let db = fs.init(&fspath, &params(), opt).unwrap();
+    let mut file = open(&mut fs, &db);
+
+    for op in ops {
+        match op {
+            Op::Write(off, buf) => {
+                file.pwrite(*off, buf).unwrap();
+                plain.write_all_at(buf, *off).unwrap();
+            },
+            Op::Read(off, len) => check_read(&file, &plain, *off, *len),
+            Op::Truncate(sz) => {
+                file.truncate(*sz).unwrap();
+                plain.set_len(*sz).unwrap();
+            },
+            Op::Commit => {
+                file.sync(0).unwrap();
+                file.close().unwrap();
+                fs.commit(&db.key).unwrap();
+                file = open(&mut fs, &db);
+            },
+        }
+        assert_eq!(file.filesize().unwrap(), plain.metadata().unwrap().len(), "after {:?}", op);
+    }
+
+    let len = plain.metadata().unwrap().len();
+    check_read(&file, &plain, 0, len as usize + PAGESZ as usize);
+    file.close().unwrap();
+}
+
+#[test]
+fn unaligned_rw() {
+    let _ = env_logger::try_init();
+    run("unaligned_rw", &[
+        Op::Write(5, vec![1; 40]),
+        Op::Read(0, 50),
+        Op::Write(20, vec![2; 3]),
+        Op::Read(3, 30),
+        Op::Commit,
+        Op::Write(30, vec![3; 20]),
+        Op::Truncate(37),
+        Op::Write(60, vec![4; 2]),
+        Op::Read(30, 40),
+    ]);
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(64))]
+
+    #[test]
+    fn kvfile_matches_plain_file(ops in proptest::collection::vec(op(), 1..40)) {
+        let _ = env_logger::try_init();
+        run("kvfile_matches_plain_file", &ops);
+    }
+}
+
+// Writes the records of a journal or the wal after its header in the parts
+// sqlite writes them in
+fn write_records(file: &mut VFSFile, parts: &[usize], records: &[Vec<u8>]) {
+    let hdr_sz = file.opt().kind.header_size();
+    for (i, rec) in records.iter().enumerate() {
+        let mut off = hdr_sz + (i * rec.len()) as u64;
+        let mut rest = &rec[..];
+        for n in parts {
+            file.pwrite(off, &rest[..*n]).unwrap();
+            off += *n as u64;
+            rest = &rest[*n..];
+        }
+    }
+    assert_eq!(file.filesize().unwrap(), hdr_sz + (records.len() * records[0].len()) as u64);
+    file.sync(0).unwrap();
+}
+
+// Each record is in a block of its own, after the one of the header
+fn check_blocks(fspath: &str, ver: u32, name: &str, records: &[Vec<u8>]) {
+    let store = mojokv::Store::readonly(Path::new(fspath), ver).unwrap();
+    let bucket = store.open(name, mojokv::BucketOpenMode::Read).unwrap();
+    assert_eq!(bucket.page_size() as usize, records[0].len(), "{}", name);
+
+    for (i, rec) in records.iter().enumerate() {
+        let mut buf = vec![0u8; rec.len()];
+        assert_eq!(bucket.get(i as u32 + 1, 0, &mut buf).unwrap(), rec.len());
+        assert_eq!(&buf, rec, "{} record {}", name, i);
+    }
+}
+
+#[test]
+fn records_fill_blocks() {
+    let _ = env_logger::try_init();
+    let path = Path::new("./testfs_all/records_fill_blocks");
+    let _ = std::fs::remove_dir_all(path);
+    std::fs::create_dir_all(path).unwrap();
+    let fspath = path.join("kv").to_str().unwrap().to_owned();
+
+    let page_sz = 512;
+    let mut params = params();
+    params.insert("pagesz".to_owned(), format!("{}", page_sz));
+    params.insert("journal".to_owned(), "kv".to_owned());
+    let mut fs = VFS::default();
+    let opt = mojofs::OpenOptions::from_flags(326).unwrap();
+    let db = fs.init(&fspath, &params, opt).unwrap();
+    let open = |fs: &mut VFS, name: &str, kind: i32| {
+        let flags = kind | rusqlite::ffi::SQLITE_OPEN_READWRITE | rusqlite::ffi::SQLITE_OPEN_CREATE;
+        let mut opt = mojofs::OpenOptions::from_flags(flags).unwrap();
+        fs.open(name, Some(&db), opt.clone(), &mut opt).unwrap()
+    };
+    let records = |sz: usize, v: u8| -> Vec<Vec<u8>> { (0..5u8).map(|i| vec![v + i; sz]).collect() };
+
+    // A wal frame is written as its header and its page
+    let mut wal = open(&mut fs, "a-wal", rusqlite::ffi::SQLITE_OPEN_WAL);
+    let mut hdr = vec![0u8; 32];
+    hdr[..4].copy_from_slice(&0x377f0682u32.to_be_bytes());
+    hdr[8..12].copy_from_slice(&(page_sz as u32).to_be_bytes());
+    wal.pwrite(0, &hdr).unwrap();
+    write_records(&mut wal, &[24, page_sz], &records(page_sz + 24, 1));
+    check_blocks(&fspath, 1, "a-wal", &records(page_sz + 24, 1));
+    wal.truncate(0).unwrap();
+    wal.close().unwrap();
+
+    // A journal record as its page number, page and checksum
+    let parts = [4, page_sz, 4];
+    let mut journal = open(&mut fs, "a-journal", rusqlite::ffi::SQLITE_OPEN_MAIN_JOURNAL);
+    let mut hdr = vec![0u8; 512];
+    hdr[..8].copy_from_slice(&[0xd9, 0xd5, 0x05, 0xf9, 0x20, 0xa1, 0x63, 0xd7]);
+    hdr[24..28].copy_from_slice(&(page_sz as u32).to_be_bytes());
+    journal.pwrite(0, &hdr).unwrap();
+    write_records(&mut journal, &parts, &records(page_sz + 8, 1));
+    check_blocks(&fspath, 1, "a-journal", &records(page_sz + 8, 1));
+
+    // A persisted journal is written over in the next version. Whole blocks
+    // do not need the old ones, which are gone.
+    journal.pwrite(0, &[0u8; 28]).unwrap();
+    journal.sync(0).unwrap();
+    journal.close().unwrap();
+    fs.commit(&db.key).unwrap();
+    std::fs::remove_file(Path::new(&fspath).join("a-journal_d.1")).unwrap();
+
+    let mut journal = open(&mut fs, "a-journal", rusqlite::ffi::SQLITE_OPEN_MAIN_JOURNAL);
+    write_records(&mut journal, &parts, &records(page_sz + 8, 10));
+    check_blocks(&fspath, 2, "a-journal", &records(page_sz + 8, 10));
+    journal.close().unwrap();
+}
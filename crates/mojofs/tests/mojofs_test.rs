@@ -1,7 +1,7 @@
 use std::path::Path;
 use anyhow::Error;
 use std::collections::HashMap;
-use mojofs::{self, VFS, VFSFile};
+use mojofs::{self, VFS, VFSFile, AccessCheck};
 
 fn remove_fs(rootpath: &Path) -> Result<(), Error> {
     if let Err(err) = std::fs::remove_dir_all(rootpath) {
@@ -18,6 +18,12 @@ fn setup() -> Result<String, Error> {
     Ok(path.to_owned().to_str().unwrap().to_owned())
 }
 
+fn setup_at(name: &str) -> Result<String, Error> {
+    let path = Path::new("./testfs_all").join(name);
+    remove_fs(&path)?;
+    Ok(path.to_str().unwrap().to_owned())
+}
+
 fn default_params(pagesz: u32) -> HashMap<String, String> {
     let mut h = HashMap::new();
 
@@ -59,7 +65,7 @@ fn write_read(a: &mut VFSFile, nitems: usize, pagesz: u64, f: fn(usize) -> usize
 
 #[test]
 fn rw_same_version() -> Result<(), Error> {
-    env_logger::init();
+    let _ = env_logger::try_init();
     let fspath = setup()?;    
     let mut fs_uri_opt = default_params(8);
     let mut opt = mojofs::OpenOptions::from_flags(326).unwrap();
@@ -67,34 +73,35 @@ fn rw_same_version() -> Result<(), Error> {
 
     {
         let mut fs = VFS::default();
-        fs.init(&fspath, &fs_uri_opt, opt.clone())?;
-        let fsopt = fs.fs_options();
+        let db = fs.init(&fspath, &fs_uri_opt, opt.clone())?;
+        assert!(VFS::default().active_ver(&db.key).is_err());
+        let fsopt = fs.fs_options(&db.key);
 
-        let mut a = fs.open("a", opt.clone(), &mut opt)?;
+        let mut a = fs.open("a", Some(&db), opt.clone(), &mut opt)?;
 
         assert_eq!(fsopt.pagesz, 8);
 
         write_read(&mut a, nitems, fsopt.pagesz as u64, |n| n)?;
         assert_eq!(a.filesize()?, (fsopt.pagesz as u64) * nitems as u64);
         a.close()?;
-        fs.commit()?;
-        assert_eq!(fs.active_ver(), 2);
+        fs.commit(&db.key)?;
+        assert_eq!(fs.active_ver(&db.key)?, 2);
 
-        let mut a = fs.open("a", opt.clone(), &mut opt)?;
+        let mut a = fs.open("a", Some(&db), opt.clone(), &mut opt)?;
         write_read(&mut a, nitems, fsopt.pagesz as u64, |n| n+10)?;
         assert_eq!(a.filesize()?, (fsopt.pagesz as u64) * nitems as u64);
         a.close()?;
-        fs.commit()?;
-        assert_eq!(fs.active_ver(), 3);
+        fs.commit(&db.key)?;
+        assert_eq!(fs.active_ver(&db.key)?, 3);
     }
 
     {
         let mut fs = VFS::default();
         opt.access = mojofs::OpenAccess::Read;
         fs_uri_opt.insert("ver".to_owned(), "1".to_owned());
-        fs.init(&fspath, &fs_uri_opt, opt.clone())?;
-        let fsopt = fs.fs_options();
-        let mut a = fs.open("a", opt.clone(), &mut opt)?;
+        let db = fs.init(&fspath, &fs_uri_opt, opt.clone())?;
+        let fsopt = fs.fs_options(&db.key);
+        let mut a = fs.open("a", Some(&db), opt.clone(), &mut opt)?;
 
         read_test(&mut a, nitems, fsopt.pagesz as u64, |n| n)?;
     }
@@ -103,12 +110,994 @@ fn rw_same_version() -> Result<(), Error> {
         let mut fs = VFS::default();
         opt.access = mojofs::OpenAccess::Read;
         fs_uri_opt.insert("ver".to_owned(), "2".to_owned());
-        fs.init(&fspath, &fs_uri_opt, opt.clone())?;
-        let fsopt = fs.fs_options();
-        let mut a = fs.open("a", opt.clone(), &mut opt)?;
+        let db = fs.init(&fspath, &fs_uri_opt, opt.clone())?;
+        let fsopt = fs.fs_options(&db.key);
+        let mut a = fs.open("a", Some(&db), opt.clone(), &mut opt)?;
 
         read_test(&mut a, nitems, fsopt.pagesz as u64, |n| n+10)?;
     }
 
     Ok(())
-}
\ No newline at end of file
+}
+
+#[test]
+fn fetch_old_version() -> Result<(), Error> {
+    let _ = env_logger::try_init();
+    let fspath = setup_at("fetch_old_version")?;
+    let fs_uri_opt = default_params(8);
+    let mut opt = mojofs::OpenOptions::from_flags(326).unwrap();
+    let nitems = 4;
+
+    {
+        let mut fs = VFS::default();
+        let db = fs.init(&fspath, &fs_uri_opt, opt.clone())?;
+        let mut a = fs.open("a", Some(&db), opt.clone(), &mut opt)?;
+        write_test(&mut a, nitems, 8, |n| n+100)?;
+
+        // Pages of the active version are never mapped
+        assert!(a.fetch(0, 8)?.is_none());
+        a.close()?;
+        fs.commit(&db.key)?;
+    }
+
+    let mut fs = VFS::default();
+    opt.access = mojofs::OpenAccess::Read;
+    let db = fs.init(&fspath, &fs_uri_opt, opt.clone())?;
+    let mut a = fs.open("a", Some(&db), opt.clone(), &mut opt)?;
+
+    for i in 0..nitems {
+        let off = i as u64 * 8;
+        let ptr = a.fetch(off, 8)?.expect("page of committed version should be mapped");
+        let page = unsafe { std::slice::from_raw_parts(ptr, 8) };
+        assert_eq!(i+100, usize::from_be_bytes(page.try_into()?));
+        a.unfetch(off, ptr)?;
+    }
+
+    assert!(a.fetch(4, 8)?.is_none());
+    a.close()?;
+
+    Ok(())
+}
+
+#[test]
+fn multi_page_rw() -> Result<(), Error> {
+    let _ = env_logger::try_init();
+    let fspath = setup_at("multi_page_rw")?;
+    let fs_uri_opt = default_params(8);
+    let mut opt = mojofs::OpenOptions::from_flags(326).unwrap();
+
+    let mut fs = VFS::default();
+    let db = fs.init(&fspath, &fs_uri_opt, opt.clone())?;
+    let mut a = fs.open("a", Some(&db), opt.clone(), &mut opt)?;
+
+    let buf: Vec<u8> = (0..64u8).collect();
+    a.pwrite(8, &buf)?;
+    assert_eq!(a.filesize()?, 72);
+
+    let mut out = vec![0u8; 64];
+    a.pread(8, &mut out)?;
+    assert_eq!(out, buf);
+
+    let mut out = vec![0u8; 72];
+    a.pread(0, &mut out)?;
+    assert_eq!(&out[..8], &[0u8; 8]);
+    assert_eq!(&out[8..], buf.as_slice());
+    a.close()?;
+
+    Ok(())
+}
+
+#[test]
+fn bucket_paths() -> Result<(), Error> {
+    let _ = env_logger::try_init();
+    let fspath = setup_at("bucket_paths")?;
+    let base = Path::new(&fspath).parent().unwrap().canonicalize()?;
+    let fs_uri_opt = default_params(8);
+    let mut opt = mojofs::OpenOptions::from_flags(326).unwrap();
+
+    let mut fs = VFS::default();
+    let db = fs.init(&fspath, &fs_uri_opt, opt.clone())?;
+
+    // Files of the same name in different directories are different buckets
+    let x = base.join("x/f");
+    let mut a = fs.open(x.to_str().unwrap(), Some(&db), opt.clone(), &mut opt)?;
+    a.pwrite(0, &[1u8; 8])?;
+    let mut b = fs.open("y/./f", Some(&db), opt.clone(), &mut opt)?;
+    b.pwrite(0, &[2u8; 8])?;
+
+    let mut out = [0u8; 8];
+    a.pread(0, &mut out)?;
+    assert_eq!(out, [1u8; 8]);
+    b.pread(0, &mut out)?;
+    assert_eq!(out, [2u8; 8]);
+    a.close()?;
+    b.close()?;
+
+    let store = mojokv::Store::writable(Path::new(&fspath), false, None, None)?;
+    assert!(store.exists("x/f") && store.exists("y/f"));
+    assert!(Path::new(&fspath).join("x%2Ff_d.1").exists());
+
+    // Files are found through the path their main db is opened at
+    assert!(fs.access(&x, AccessCheck::Exists)?);
+    assert!(fs.access(Path::new("y/f"), AccessCheck::Exists)?);
+    assert!(!fs.access(&base.join("y/f"), AccessCheck::Exists)?);
+    fs.delete(Path::new("y/f"))?;
+    assert!(!fs.access(Path::new("y/f"), AccessCheck::Exists)?);
+
+    // Files outside the directory of the store have no bucket
+    let outside = base.parent().unwrap().join("f");
+    assert!(!fs.access(&outside, AccessCheck::Exists)?);
+    let err = fs.open(outside.to_str().unwrap(), Some(&db), opt.clone(), &mut opt).err().unwrap();
+    assert_eq!(err.code, mojofs::MOJOFS_ERR_PATH_OUTSIDE_STORE);
+    let err = fs.open("../f", Some(&db), opt.clone(), &mut opt).err().unwrap();
+    assert_eq!(err.code, mojofs::MOJOFS_ERR_PATH_OUTSIDE_STORE);
+
+    Ok(())
+}
+
+#[test]
+fn transient_files_not_versioned() -> Result<(), Error> {
+    use libsqlite3_sys as ffi;
+
+    let _ = env_logger::try_init();
+    let fspath = setup_at("transient_files_not_versioned")?;
+    let fs_uri_opt = default_params(8);
+    let mut opt = mojofs::OpenOptions::from_flags(326).unwrap();
+
+    let mut fs = VFS::default();
+    let db = fs.init(&fspath, &fs_uri_opt, opt.clone())?;
+    let a = fs.open("a", Some(&db), opt.clone(), &mut opt)?;
+
+    // Rollback journal goes next to the store as a regular file
+    let journal_path = std::env::current_dir()?.join(format!("{}-journal", fspath));
+    let _ = std::fs::remove_file(&journal_path);
+    let jflags = ffi::SQLITE_OPEN_MAIN_JOURNAL | ffi::SQLITE_OPEN_CREATE | ffi::SQLITE_OPEN_READWRITE;
+    let mut jopt = mojofs::OpenOptions::from_flags(jflags).unwrap();
+    let mut j = fs.open(journal_path.to_str().unwrap(), Some(&db), jopt.clone(), &mut jopt)?;
+    j.pwrite(3, b"journal")?;
+    j.sync(0)?;
+    j.close()?;
+
+    assert!(journal_path.exists());
+    assert!(fs.access(&journal_path, AccessCheck::Exists)?);
+    fs.delete(&journal_path)?;
+    assert!(!journal_path.exists());
+    assert!(!fs.access(&journal_path, AccessCheck::Exists)?);
+
+    // Temp files stay in memory
+    let tflags = ffi::SQLITE_OPEN_TEMP_JOURNAL | ffi::SQLITE_OPEN_CREATE | ffi::SQLITE_OPEN_READWRITE
+        | ffi::SQLITE_OPEN_EXCLUSIVE | ffi::SQLITE_OPEN_DELETEONCLOSE;
+    let mut topt = mojofs::OpenOptions::from_flags(tflags).unwrap();
+    let mut t = fs.open("", Some(&db), topt.clone(), &mut topt)?;
+    t.pwrite(5, b"temp")?;
+    let mut out = [0u8; 9];
+    assert_eq!(t.pread(0, &mut out)?, 9);
+    assert_eq!(&out, b"\0\0\0\0\0temp");
+    fs.close(*t)?;
+
+    let names: Vec<String> = std::fs::read_dir(&fspath)?
+        .map(|e| e.unwrap().file_name().to_str().unwrap().to_owned())
+        .collect();
+    assert!(names.iter().all(|n| !n.contains("journal") && !n.contains("tmp")), "{:?}", names);
+
+    a.close()?;
+    Ok(())
+}
+
+#[test]
+fn page_size_from_header() -> Result<(), Error> {
+    let _ = env_logger::try_init();
+    let fspath = setup_at("page_size_from_header")?;
+    let mut params = default_params(512);
+    params.remove("pagesz");
+    let mut opt = mojofs::OpenOptions::from_flags(326).unwrap();
+
+    let mut page = vec![0u8; 512];
+    page[..16].copy_from_slice(b"SQLite format 3\0");
+    page[16..18].copy_from_slice(&512u16.to_be_bytes());
+
+    {
+        let mut fs = VFS::default();
+        let db = fs.init(&fspath, &params, opt.clone())?;
+        let mut a = fs.open("a", Some(&db), opt.clone(), &mut opt)?;
+        let mut b = fs.open("b", Some(&db), opt.clone(), &mut opt)?;
+        assert!(a.needs_page_size());
+        assert!(a.pwrite(512, &page).is_err());
+
+        fs.learn_page_size(&mut a, 0, &page)?;
+        assert!(!a.needs_page_size());
+        a.pwrite(0, &page)?;
+        a.sync(0)?;
+        assert_eq!(a.filesize()?, 512);
+
+        // Files of the store opened before see the learnt page size
+        assert!(!b.needs_page_size());
+        b.pwrite(0, &page)?;
+        assert_eq!(b.filesize()?, 512);
+        b.close()?;
+
+        // A header with another page size is rejected
+        let mut bad = page.clone();
+        bad[16..18].copy_from_slice(&1024u16.to_be_bytes());
+        assert!(a.pwrite(0, &bad).is_err());
+        a.close()?;
+    }
+
+    {
+        let mut fs = VFS::default();
+        let db = fs.init(&fspath, &params, opt.clone())?;
+        assert_eq!(fs.fs_options(&db.key).pagesz, 512);
+
+        let a = fs.open("a", Some(&db), opt.clone(), &mut opt)?;
+        let mut out = vec![0u8; 512];
+        a.pread(0, &mut out)?;
+        assert_eq!(out, page);
+        a.close()?;
+    }
+
+    let mut fs = VFS::default();
+    assert!(fs.init(&fspath, &default_params(1024), opt.clone()).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn multiple_stores() -> Result<(), Error> {
+    use libsqlite3_sys as ffi;
+
+    let _ = env_logger::try_init();
+    let path_a = setup_at("multiple_stores_a")?;
+    let path_b = setup_at("multiple_stores_b")?;
+    let mut params = default_params(8);
+    params.insert("journal".to_owned(), "kv".to_owned());
+    let mut opt = mojofs::OpenOptions::from_flags(326).unwrap();
+    let jflags = ffi::SQLITE_OPEN_MAIN_JOURNAL | ffi::SQLITE_OPEN_CREATE | ffi::SQLITE_OPEN_READWRITE;
+    let mut jopt = mojofs::OpenOptions::from_flags(jflags).unwrap();
+    let nitems = 4;
+
+    let mut fs = VFS::default();
+    let db_a = fs.init(&path_a, &params, opt.clone())?;
+    let db_b = fs.init(&path_b, &params, opt.clone())?;
+
+    // Each open goes to the store given to it, not to the last one opened
+    let mut a = fs.open("a", Some(&db_a), opt.clone(), &mut opt)?;
+    write_test(&mut a, nitems, 8, |n| n)?;
+    assert!(a.store_key().unwrap().root_path.ends_with("multiple_stores_a"));
+
+    let mut b = fs.open("b", Some(&db_b), opt.clone(), &mut opt)?;
+    write_test(&mut b, nitems, 8, |n| n+100)?;
+    assert!(fs.open("c", None, opt.clone(), &mut opt).is_err());
+
+    // Journals go to the store of their main db
+    let mut aj = fs.open("a-journal", Some(&db_b), jopt.clone(), &mut jopt)?;
+    aj.pwrite(0, b"journal")?;
+    assert!(aj.store_key().unwrap().root_path.ends_with("multiple_stores_a"));
+    fs.close(*aj)?;
+    assert!(fs.access(Path::new("a-journal"), AccessCheck::Exists)?);
+    assert!(!fs.access(Path::new("b-journal"), AccessCheck::Exists)?);
+
+    read_test(&mut a, nitems, 8, |n| n)?;
+    read_test(&mut b, nitems, 8, |n| n+100)?;
+    fs.close(*b)?;
+
+    // The same database at an older version next to the writable one
+    fs.close(*a)?;
+    let db = fs.init(&path_a, &params, opt.clone())?;
+    fs.commit(&db.key)?;
+    let mut a = fs.open("a", Some(&db), opt.clone(), &mut opt)?;
+    write_test(&mut a, nitems, 8, |n| n+10)?;
+
+    let mut ro_opt = opt.clone();
+    ro_opt.access = mojofs::OpenAccess::Read;
+    params.insert("ver".to_owned(), "1".to_owned());
+    let db = fs.init(&path_a, &params, ro_opt.clone())?;
+    let mut a1 = fs.open("a", Some(&db), ro_opt.clone(), &mut ro_opt)?;
+    read_test(&mut a1, nitems, 8, |n| n)?;
+    read_test(&mut a, nitems, 8, |n| n+10)?;
+    // Store b was dropped with its last file
+    assert_eq!(fs.stores().count(), 2);
+
+    fs.close(*a1)?;
+    fs.close(*a)?;
+    Ok(())
+}
+
+#[test]
+fn store_released_on_close() -> Result<(), Error> {
+    let _ = env_logger::try_init();
+    let fspath = setup_at("store_released_on_close")?;
+    let params = default_params(8);
+    let mut opt = mojofs::OpenOptions::from_flags(326).unwrap();
+
+    let mut fs = VFS::default();
+    let db = fs.init(&fspath, &params, opt.clone())?;
+    let mut a = fs.open("a", Some(&db), opt.clone(), &mut opt)?;
+    write_test(&mut a, 4, 8, |n| n)?;
+
+    // An open store is not reused with other params
+    let mut other = params.clone();
+    other.insert("prealloc".to_owned(), "4096".to_owned());
+    let err = fs.init(&fspath, &other, opt.clone()).unwrap_err();
+    assert_eq!(err.code, mojofs::MOJOFS_ERR_ARG_CONFLICT);
+    assert_eq!(fs.init(&fspath, &params, opt.clone())?, db);
+
+    fs.close(*a)?;
+    assert_eq!(fs.stores().count(), 0);
+
+    // Once closed the store is opened again with the new params
+    let db = fs.init(&fspath, &other, opt.clone())?;
+    assert_eq!(fs.fs_options(&db.key).prealloc, 4096);
+    let a = fs.open("a", Some(&db), opt.clone(), &mut opt)?;
+    fs.close(*a)?;
+    assert_eq!(fs.stores().count(), 0);
+
+    Ok(())
+}
+
+#[test]
+fn attach_versions() -> Result<(), Error> {
+    let _ = env_logger::try_init();
+    let fspath = setup_at("attach_versions")?;
+    let mut params = default_params(8);
+    params.insert("journal".to_owned(), "kv".to_owned());
+    let mut opt = mojofs::OpenOptions::from_flags(326).unwrap();
+    let nitems = 4;
+
+    let mut fs = VFS::default();
+    for v in 0..3 {
+        let db = fs.init(&fspath, &params, opt.clone())?;
+        let mut a = fs.open("a", Some(&db), opt.clone(), &mut opt)?;
+        write_test(&mut a, nitems, 8, [|n| n, |n| n+10, |n| n+20][v])?;
+        fs.close(*a)?;
+        let db = fs.init(&fspath, &params, opt.clone())?;
+        fs.commit(&db.key)?;
+    }
+
+    let db = fs.init(&fspath, &params, opt.clone())?;
+    let a = fs.open("a", Some(&db), opt.clone(), &mut opt)?;
+    assert!(!a.is_immutable());
+
+    let mut ro_opt = opt.clone();
+    ro_opt.access = mojofs::OpenAccess::Read;
+    let mut olds = Vec::new();
+    for ver in 1..=3u32 {
+        params.insert("ver".to_owned(), ver.to_string());
+        let db = fs.init(&fspath, &params, ro_opt.clone())?;
+        let f = fs.open("a", Some(&db), ro_opt.clone(), &mut ro_opt)?;
+        assert!(f.is_immutable());
+        assert_eq!(f.device_char()?, libsqlite3_sys::SQLITE_IOCAP_IMMUTABLE);
+        olds.push(f);
+    }
+
+    read_test(&mut olds[0], nitems, 8, |n| n)?;
+    read_test(&mut olds[1], nitems, 8, |n| n+10)?;
+    read_test(&mut olds[2], nitems, 8, |n| n+20)?;
+
+    // All the versions read through one cache of open version files
+    let cached: Vec<usize> = olds.iter().map(|f| f.store().unwrap().cached_files()).collect();
+    assert!(cached[0] >= 3);
+    assert!(cached.iter().all(|n| *n == cached[0]));
+
+    // The journal of the db belongs to the writable store
+    let jflags = libsqlite3_sys::SQLITE_OPEN_MAIN_JOURNAL | libsqlite3_sys::SQLITE_OPEN_CREATE | libsqlite3_sys::SQLITE_OPEN_READWRITE;
+    let mut jopt = mojofs::OpenOptions::from_flags(jflags).unwrap();
+    let j = fs.open("a-journal", Some(&db), jopt.clone(), &mut jopt)?;
+    assert_eq!(j.store_key().unwrap().ver, None);
+    fs.close(*j)?;
+
+    for f in olds {
+        fs.close(*f)?;
+    }
+    fs.close(*a)?;
+    Ok(())
+}
+
+#[test]
+fn commit_pragma() -> Result<(), Error> {
+    let _ = env_logger::try_init();
+    let fspath = setup_at("commit_pragma")?;
+    let mut params = default_params(8);
+    let mut opt = mojofs::OpenOptions::from_flags(326).unwrap();
+    let nitems = 4;
+
+    let mut fs = VFS::default();
+    let db = fs.init(&fspath, &params, opt.clone())?;
+    let mut a = fs.open("a", Some(&db), opt.clone(), &mut opt)?;
+    write_test(&mut a, nitems, 8, |n| n)?;
+
+    assert_eq!(a.pragma("page_size", None)?, None);
+    assert_eq!(a.pragma("mojo_version", None)?.as_deref(), Some("1"));
+
+    // Refused while in a transaction, before the pages written in it are
+    // synced
+    a.lock(1)?;
+    a.lock(2)?;
+    a.pwrite(nitems as u64 * 8, &nitems.to_be_bytes())?;
+    assert!(a.pragma("mojo_commit", None).is_err());
+    let other = mojokv::Store::writable(Path::new(&fspath), false, None, None)?;
+    let index = other.get_index_at("a", 1)?.expect("bucket a at version 1");
+    assert_eq!(index.max_key(), nitems as isize - 1);
+    a.unlock(0)?;
+
+    assert_eq!(a.pragma("mojo_commit", None)?.as_deref(), Some("2"));
+    assert_eq!(a.pragma("MOJO_VERSION", None)?.as_deref(), Some("2"));
+    assert_eq!(a.pragma("mojo_versions", None)?.as_deref(), Some("1,2"));
+
+    // The open file keeps writing, now into version 2
+    write_test(&mut a, nitems, 8, |n| n+10)?;
+    read_test(&mut a, nitems, 8, |n| n+10)?;
+    fs.close(*a)?;
+
+    let mut ro_opt = opt.clone();
+    ro_opt.access = mojofs::OpenAccess::Read;
+    for (ver, f) in [(1u32, (|n| n) as fn(usize) -> usize), (2, |n| n+10)] {
+        params.insert("ver".to_owned(), ver.to_string());
+        let db = fs.init(&fspath, &params, ro_opt.clone())?;
+        let mut r = fs.open("a", Some(&db), ro_opt.clone(), &mut ro_opt)?;
+        read_test(&mut r, nitems, 8, f)?;
+        assert_eq!(r.pragma("mojo_version", None)?, Some(ver.to_string()));
+        assert!(r.pragma("mojo_commit", None).is_err());
+        fs.close(*r)?;
+    }
+
+    Ok(())
+}
+
+fn register_vfs() {
+    static INIT: std::sync::Once = std::sync::Once::new();
+    INIT.call_once(|| {
+        mojofs::register("mojo", &HashMap::new()).unwrap();
+    });
+}
+
+fn sqlite_open(path: &str, params: &str) -> Result<rusqlite::Connection, Error> {
+    use rusqlite::OpenFlags;
+
+    register_vfs();
+    let uri = format!("file:{}?vfs=mojo&{}", path, params);
+    let conn = rusqlite::Connection::open_with_flags(&uri, OpenFlags::SQLITE_OPEN_READ_WRITE
+        | OpenFlags::SQLITE_OPEN_CREATE | OpenFlags::SQLITE_OPEN_URI)?;
+    Ok(conn)
+}
+
+
+#[test]
+fn concurrent_opens() -> Result<(), Error> {
+    let _ = env_logger::try_init();
+    let fspath = setup_at("concurrent_opens")?;
+    std::fs::create_dir_all(&fspath)?;
+
+    // Connections opened and closed on several threads at once, each to a
+    // db of its own
+    let threads: Vec<_> = (0..4).map(|t| {
+        let dbpath = format!("{}/t{}.db", fspath, t);
+        std::thread::spawn(move || -> Result<(), Error> {
+            for i in 0..10 {
+                let conn = sqlite_open(&dbpath, "pagesz=4096")?;
+                conn.execute_batch("pragma page_size=4096; create table if not exists t(a);")?;
+                conn.execute("insert into t values(?)", [i])?;
+                if i % 3 == 0 {
+                    conn.query_row("pragma mojo_commit", [], |r| r.get::<_, String>(0))?;
+                }
+            }
+            Ok(())
+        })
+    }).collect();
+
+    for t in threads {
+        t.join().unwrap()?;
+    }
+
+    for t in 0..4 {
+        let conn = sqlite_open(&format!("{}/t{}.db", fspath, t), "")?;
+        let n: u32 = conn.query_row("select sum(a) from t", [], |r| r.get(0))?;
+        assert_eq!(n, 45);
+    }
+
+    Ok(())
+}
+
+#[test]
+fn commit_pragma_syncs_other_files() -> Result<(), Error> {
+    let _ = env_logger::try_init();
+    let fspath = setup_at("commit_pragma_syncs_other_files")?;
+    let mut params = default_params(8);
+    let mut opt = mojofs::OpenOptions::from_flags(326).unwrap();
+    let nitems = 4;
+
+    let mut fs = VFS::default();
+    let db = fs.init(&fspath, &params, opt.clone())?;
+    let mut a = fs.open("a", Some(&db), opt.clone(), &mut opt)?;
+    write_test(&mut a, nitems, 8, |n| n)?;
+
+    // Written but not synced when a commits
+    let db = fs.init(&fspath, &params, opt.clone())?;
+    let mut b = fs.open("b", Some(&db), opt.clone(), &mut opt)?;
+    for i in 0..nitems {
+        b.pwrite(i as u64 * 8, &(i+100).to_be_bytes())?;
+    }
+
+    assert_eq!(a.pragma("mojo_commit", None)?.as_deref(), Some("2"));
+    fs.close(*a)?;
+    fs.close(*b)?;
+
+    let mut ro_opt = opt.clone();
+    ro_opt.access = mojofs::OpenAccess::Read;
+    params.insert("ver".to_owned(), "1".to_owned());
+    let db = fs.init(&fspath, &params, ro_opt.clone())?;
+    let mut b = fs.open("b", Some(&db), ro_opt.clone(), &mut ro_opt)?;
+    read_test(&mut b, nitems, 8, |n| n+100)?;
+    fs.close(*b)?;
+
+    Ok(())
+}
+#[test]
+fn introspection_vtabs() -> Result<(), Error> {
+    let _ = env_logger::try_init();
+    let fspath = setup_at("introspection_vtabs")?;
+    std::fs::create_dir_all(&fspath)?;
+    let dbpath = format!("{}/a.db", fspath);
+
+    let conn = sqlite_open(&dbpath, "pagesz=4096")?;
+
+    conn.execute_batch("pragma page_size=4096; create table t(a, b); create table u(a); insert into t values(1, 'one');")?;
+    let ver: String = conn.query_row("pragma mojo_commit", [], |r| r.get(0))?;
+    assert_eq!(ver, "2");
+    conn.execute_batch("insert into t values(2, 'two');")?;
+
+    let versions: Vec<(u32, u32, u32)> = conn.prepare("select version, buckets, pages from mojo_versions")?
+        .query_map([], |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?)))?
+        .collect::<Result<_, _>>()?;
+    assert_eq!(versions.len(), 2);
+    assert_eq!((versions[0].0, versions[0].1), (1, 1));
+    assert_eq!((versions[1].0, versions[1].1), (2, 1));
+    assert!(versions[0].2 >= 3);
+    assert!(versions[1].2 >= 1);
+
+    let buckets: Vec<(u32, String)> = conn.prepare("select version, bucket from mojo_buckets")?
+        .query_map([], |r| Ok((r.get(0)?, r.get(1)?)))?
+        .collect::<Result<_, _>>()?;
+    assert_eq!(buckets.len(), 2);
+    assert_eq!(buckets[0].1, buckets[1].1);
+
+    // Pages of table u are not changed in version 2
+    let vers: Vec<u32> = conn.prepare("select distinct version from mojo_pages order by 1")?
+        .query_map([], |r| r.get(0))?
+        .collect::<Result<_, _>>()?;
+    assert_eq!(vers, vec![1, 2]);
+
+    let n: u32 = conn.query_row("select count(*) from mojo_pages(1) where version != 1", [], |r| r.get(0))?;
+    assert_eq!(n, 0);
+
+    assert!(conn.query_row("select count(*) from mojo_versions('temp')", [], |r| r.get::<_, u32>(0)).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn register_with_defaults() -> Result<(), Error> {
+    let _ = env_logger::try_init();
+    let fspath = setup_at("register_with_defaults")?;
+    std::fs::create_dir_all(&fspath)?;
+    let dbpath = format!("{}/a.db", fspath);
+
+    let mut defaults = HashMap::new();
+    defaults.insert("pagesz".to_owned(), "8192".to_owned());
+    defaults.insert("journal".to_owned(), "kv".to_owned());
+    mojofs::register("mojo_8k", &defaults)?;
+
+    // Relative paths are resolved against the working directory
+    let uri = format!("file:{}?vfs=mojo_8k", dbpath);
+    let conn = rusqlite::Connection::open_with_flags(&uri, rusqlite::OpenFlags::SQLITE_OPEN_READ_WRITE
+        | rusqlite::OpenFlags::SQLITE_OPEN_CREATE | rusqlite::OpenFlags::SQLITE_OPEN_URI)?;
+    assert_eq!(mojokv::Store::load_state(Path::new(&dbpath))?.page_size(), 8192);
+
+    // A persisted journal shows up as a bucket instead of a native file
+    conn.execute_batch("pragma page_size=8192; pragma journal_mode=persist; create table t(a); insert into t values(1);")?;
+    let buckets: Vec<String> = conn.prepare("select bucket from mojo_buckets")?
+        .query_map([], |r| r.get(0))?
+        .collect::<Result<_, _>>()?;
+    assert_eq!(buckets, vec!["a.db", "a.db-journal"]);
+    assert!(!Path::new(&format!("{}-journal", dbpath)).exists());
+    drop(conn);
+
+    // Params in the URI take precedence
+    let conn = rusqlite::Connection::open_with_flags(format!("{}&pagesz=4096", uri), rusqlite::OpenFlags::SQLITE_OPEN_READ_WRITE
+        | rusqlite::OpenFlags::SQLITE_OPEN_URI);
+    assert!(conn.is_err());
+
+    let conn = rusqlite::Connection::open_with_flags(&uri, rusqlite::OpenFlags::SQLITE_OPEN_READ_WRITE
+        | rusqlite::OpenFlags::SQLITE_OPEN_URI)?;
+    let n: u32 = conn.query_row("select sum(a) from t", [], |r| r.get(0))?;
+    assert_eq!(n, 1);
+
+    Ok(())
+}
+
+#[test]
+fn commit_at_txn_boundary() -> Result<(), Error> {
+    use std::time::Duration;
+
+    let _ = env_logger::try_init();
+    let fspath = setup_at("commit_at_txn_boundary")?;
+    std::fs::create_dir_all(&fspath)?;
+    let dbpath = format!("{}/a.db", fspath);
+
+    let conn = sqlite_open(&dbpath, "pagesz=4096&wal=native")?;
+    conn.execute_batch("pragma page_size=4096; create table t(a); insert into t values(1);")?;
+
+    // Stands in for mojo-cli, it has its own store object
+    let store = mojokv::Store::writable(Path::new(&dbpath), false, None, None)?;
+    let wait = Duration::from_millis(50);
+
+    conn.execute_batch("begin immediate; insert into t values(2);")?;
+    assert!(matches!(store.commit_txn(&mut store.db_lock()?, wait), Err(mojokv::Error::DbBusyErr)));
+    conn.execute_batch("commit;")?;
+    assert_eq!(store.commit_txn(&mut store.db_lock()?, wait)?, 2);
+
+    // The connection picks up the commit at its next transaction
+    conn.execute_batch("insert into t values(3);")?;
+    let ver: String = conn.query_row("pragma mojo_version", [], |r| r.get(0))?;
+    assert_eq!(ver, "2");
+
+    let old = sqlite_open(&dbpath, "ver=1&mode=ro")?;
+    let n: u32 = old.query_row("select sum(a) from t", [], |r| r.get(0))?;
+    assert_eq!(n, 3);
+    drop(old);
+
+    // Frames in the wal are not in the db yet
+    conn.execute_batch("pragma journal_mode=wal; insert into t values(4);")?;
+    assert!(matches!(store.commit_txn(&mut store.db_lock()?, wait), Err(mojokv::Error::WalNotEmptyErr(_))));
+    conn.execute_batch("pragma wal_checkpoint(truncate);")?;
+    assert_eq!(store.commit_txn(&mut store.db_lock()?, wait)?, 3);
+    conn.execute_batch("pragma journal_mode=delete;")?;
+    drop(conn);
+
+    // A journal left behind by a crash
+    let journal = format!("{}-journal", dbpath);
+    std::fs::write(&journal, b"\xd9\xd5\x05\xf9\x20\xa1\x63\xd7")?;
+    assert!(matches!(store.commit_txn(&mut store.db_lock()?, wait), Err(mojokv::Error::HotJournalErr(_))));
+    std::fs::remove_file(&journal)?;
+
+    let old = sqlite_open(&dbpath, "ver=2&mode=ro")?;
+    let n: u32 = old.query_row("select sum(a) from t", [], |r| r.get(0))?;
+    assert_eq!(n, 10);
+
+    Ok(())
+}
+
+#[test]
+fn autocommit_policies() -> Result<(), Error> {
+    let _ = env_logger::try_init();
+    let fspath = setup_at("autocommit_policies")?;
+    std::fs::create_dir_all(&fspath)?;
+
+    let version = |conn: &rusqlite::Connection| -> Result<u32, Error> {
+        let ver: String = conn.query_row("pragma mojo_version", [], |r| r.get(0))?;
+        Ok(ver.parse()?)
+    };
+
+    // Every 2 write transactions, reads do not count
+    let conn = sqlite_open(&format!("{}/txns.db", fspath), "pagesz=4096&autocommit_txns=2")?;
+    conn.execute_batch("pragma page_size=4096; create table t(a);")?;
+    assert_eq!(version(&conn)?, 1);
+    conn.query_row("select count(*) from t", [], |r| r.get::<_, u32>(0))?;
+    conn.execute_batch("insert into t values(1);")?;
+    assert_eq!(version(&conn)?, 2);
+    for i in 0..4 {
+        conn.execute("insert into t values(?)", [i])?;
+    }
+    assert_eq!(version(&conn)?, 4);
+
+    // Every version is a consistent db
+    let old = sqlite_open(&format!("{}/txns.db", fspath), "ver=2&mode=ro")?;
+    let n: u32 = old.query_row("select count(*) from t", [], |r| r.get(0))?;
+    assert_eq!(n, 3);
+    drop(old);
+
+    // After 10 pages written
+    let conn = sqlite_open(&format!("{}/pages.db", fspath), "pagesz=4096&autocommit_pages=10")?;
+    conn.execute_batch("pragma page_size=4096; create table t(a);")?;
+    assert_eq!(version(&conn)?, 1);
+    conn.execute("insert into t values(?)", [vec![0u8; 40000]])?;
+    assert_eq!(version(&conn)?, 2);
+
+    // Pages written again are counted once
+    conn.execute("insert into t values(1)", [])?;
+    for i in 0..20 {
+        conn.execute("update t set a = ? where rowid = 2", [i])?;
+    }
+    assert_eq!(version(&conn)?, 2);
+
+    // Time based, checked at the end of a transaction
+    let conn = sqlite_open(&format!("{}/secs.db", fspath), "pagesz=4096&autocommit_secs=1")?;
+    conn.execute_batch("pragma page_size=4096; create table t(a);")?;
+    assert_eq!(version(&conn)?, 1);
+    std::thread::sleep(std::time::Duration::from_millis(1100));
+    conn.execute_batch("insert into t values(1);")?;
+    assert_eq!(version(&conn)?, 2);
+
+    // Commits need an empty wal so wal mode is refused
+    assert!(conn.execute_batch("pragma journal_mode=wal;").is_err());
+    conn.execute_batch("insert into t values(2);")?;
+    drop(conn);
+
+    // As is autocommit on a db already in wal mode
+    let conn = sqlite_open(&format!("{}/wal.db", fspath), "pagesz=4096")?;
+    conn.execute_batch("pragma page_size=4096; pragma journal_mode=wal; create table t(a);")?;
+    drop(conn);
+    let conn = sqlite_open(&format!("{}/wal.db", fspath), "autocommit_txns=1")?;
+    assert!(conn.query_row("select count(*) from t", [], |r| r.get::<_, u32>(0)).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn txn_log_restore() -> Result<(), Error> {
+    let _ = env_logger::try_init();
+    let fspath = setup_at("txn_log_restore")?;
+    std::fs::create_dir_all(&fspath)?;
+    let dbpath = format!("{}/a.db", fspath);
+
+    let count = |params: &str| -> Result<(u32, i64), Error> {
+        let conn = sqlite_open(&dbpath, params)?;
+        let row = conn.query_row("select count(*), coalesce(sum(a), 0) from t", [], |r| Ok((r.get(0)?, r.get(1)?)))?;
+        Ok(row)
+    };
+
+    // txn 0 is the empty db, txn 1 creates the table
+    let conn = sqlite_open(&dbpath, "pagesz=4096&txnlog=1")?;
+    conn.execute_batch("pragma page_size=4096; create table t(a, b);")?;
+    for i in 1..=5 {
+        conn.execute("insert into t values(?, ?)", rusqlite::params![i, vec![i as u8; 6000]])?;
+    }
+
+    // Pages overwritten by later transactions keep their old contents
+    conn.execute("update t set a = a * 10", [])?;
+
+    for i in 1..=5u32 {
+        let n = count(&format!("ver=1&txn={}&mode=ro", i + 1))?;
+        assert_eq!(n, (i, (i * (i + 1) / 2) as i64));
+    }
+    assert_eq!(count("ver=1&txn=7&mode=ro")?, (5, 150));
+    assert!(sqlite_open(&dbpath, "ver=1&txn=8&mode=ro").is_err());
+
+    // Transactions of the next version start from the committed one
+    let ver: String = conn.query_row("pragma mojo_commit", [], |r| r.get(0))?;
+    assert_eq!(ver, "2");
+    conn.execute("delete from t where a > 20", [])?;
+    conn.execute("insert into t values(7, null)", [])?;
+    assert_eq!(count("ver=2&txn=0&mode=ro")?, (5, 150));
+    assert_eq!(count("ver=2&txn=1&mode=ro")?, (2, 30));
+    assert_eq!(count("ver=2&txn=2&mode=ro")?, (3, 37));
+    drop(conn);
+
+    // Restoring a transaction into a new db
+    let restored = format!("{}/restored.db", fspath);
+    let old = sqlite_open(&dbpath, "ver=1&txn=3&mode=ro")?;
+    old.execute("vacuum into ?", [format!("file:{}?vfs=unix", restored)])?;
+    drop(old);
+
+    let conn = rusqlite::Connection::open(&restored)?;
+    let n: u32 = conn.query_row("select count(*) from t", [], |r| r.get(0))?;
+    assert_eq!(n, 2);
+
+    Ok(())
+}
+
+#[test]
+fn batch_atomic_write() -> Result<(), Error> {
+    let _ = env_logger::try_init();
+    let fspath = setup_at("batch_atomic_write")?;
+    std::fs::create_dir_all(&fspath)?;
+    let dbpath = format!("{}/a.db", fspath);
+    let journal = format!("{}-journal", dbpath);
+
+    let conn = sqlite_open(&dbpath, "pagesz=4096")?;
+    // The first transaction of a db goes through the journal. From then on
+    // a persisted journal would be kept once created.
+    conn.execute_batch("pragma page_size=4096; pragma journal_mode=delete; create table t(a, b);")?;
+    conn.execute_batch("pragma journal_mode=persist;")?;
+    assert!(!std::path::Path::new(&journal).exists());
+
+    // The transactions are written without a journal
+    for i in 0..20 {
+        conn.execute("insert into t values(?, ?)", rusqlite::params![i, vec![i as u8; 5000]])?;
+    }
+    conn.execute("update t set a = a + 1", [])?;
+    assert!(!std::path::Path::new(&journal).exists());
+
+    let sum: i64 = conn.query_row("select sum(a) from t", [], |r| r.get(0))?;
+    assert_eq!(sum, 210);
+    drop(conn);
+
+    // Pages are published in the index at commit
+    let conn = sqlite_open(&dbpath, "")?;
+    let (n, sum): (u32, i64) = conn.query_row("select count(*), sum(a) from t", [], |r| Ok((r.get(0)?, r.get(1)?)))?;
+    assert_eq!((n, sum), (20, 210));
+    let ok: String = conn.query_row("pragma integrity_check", [], |r| r.get(0))?;
+    assert_eq!(ok, "ok");
+    drop(conn);
+
+    // atomic=0 goes back to the journal
+    let conn = sqlite_open(&dbpath, "atomic=0")?;
+    conn.execute_batch("begin; insert into t values(100, null);")?;
+    assert!(std::path::Path::new(&journal).exists());
+    conn.execute_batch("commit;")?;
+
+    Ok(())
+}
+
+#[test]
+fn sqlite_error_codes() -> Result<(), Error> {
+    let _ = env_logger::try_init();
+    let fspath = setup_at("sqlite_error_codes")?;
+    std::fs::create_dir_all(&fspath)?;
+    let dbpath = format!("{}/a.db", fspath);
+
+    fn code<T>(res: rusqlite::Result<T>) -> Option<rusqlite::ErrorCode> {
+        match res {
+            Err(rusqlite::Error::SqliteFailure(e, _)) => Some(e.code),
+            _ => None,
+        }
+    }
+
+    let conn = sqlite_open(&dbpath, "pagesz=4096")?;
+    conn.execute_batch("pragma page_size=4096; create table t(a);")?;
+    drop(conn);
+
+    // Missing version, the message is kept for xGetLastError
+    let res = rusqlite::Connection::open_with_flags(format!("file:{}?vfs=mojo&ver=9&mode=ro", dbpath),
+        rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY | rusqlite::OpenFlags::SQLITE_OPEN_URI);
+    assert_eq!(code(res), Some(rusqlite::ErrorCode::CannotOpen));
+    let (_, msg) = mojofs::last_error().unwrap();
+    assert!(msg.contains("Version 9 not found"), "{}", msg);
+
+    // Commit of a read-only version
+    let old = sqlite_open(&dbpath, "ver=1&mode=ro")?;
+    let res = old.query_row("pragma mojo_commit", [], |r| r.get::<_, String>(0));
+    assert_eq!(code(res), Some(rusqlite::ErrorCode::ReadOnly));
+    drop(old);
+
+    // A damaged index
+    std::fs::write(format!("{}/a.db_i.1", dbpath), b"mojo")?;
+    let res = rusqlite::Connection::open_with_flags(format!("file:{}?vfs=mojo", dbpath),
+        rusqlite::OpenFlags::SQLITE_OPEN_READ_WRITE | rusqlite::OpenFlags::SQLITE_OPEN_URI);
+    assert_eq!(code(res), Some(rusqlite::ErrorCode::DatabaseCorrupt));
+
+    Ok(())
+}
+
+#[test]
+fn ffi_bad_args() -> Result<(), Error> {
+    let _ = env_logger::try_init();
+    register_vfs();
+
+    let vfs = unsafe {libsqlite3_sys::sqlite3_vfs_find(c"mojo".as_ptr())};
+    assert!(!vfs.is_null());
+    let vfs_ref = unsafe {&*vfs};
+
+    // Open flags without a file kind
+    let mut file = vec![0u8; vfs_ref.szOsFile as usize];
+    let sfile = file.as_mut_ptr() as *mut libsqlite3_sys::sqlite3_file;
+    let rc = unsafe {(vfs_ref.xOpen.unwrap())(vfs, c"a.db".as_ptr(), sfile, 0, std::ptr::null_mut())};
+    assert_eq!(rc, libsqlite3_sys::SQLITE_CANTOPEN);
+    assert!(unsafe {(*sfile).pMethods.is_null()});
+    let (_, msg) = mojofs::last_error().unwrap();
+    assert!(msg.contains("unexpected open flags"), "{}", msg);
+
+    // Null paths
+    let mut res = 0;
+    let rc = unsafe {(vfs_ref.xAccess.unwrap())(vfs, std::ptr::null(), 0, &mut res)};
+    assert_eq!(rc, libsqlite3_sys::SQLITE_IOERR_CONVPATH);
+
+    let mut out = [0i8; 64];
+    let rc = unsafe {(vfs_ref.xFullPathname.unwrap())(vfs, std::ptr::null(), out.len() as i32, out.as_mut_ptr())};
+    assert_eq!(rc, libsqlite3_sys::SQLITE_CANTOPEN);
+
+    Ok(())
+}
+
+// Page size recorded for bucket `name` in the active version
+fn bucket_page_size(dbpath: &str, name: &str) -> Result<Option<u32>, Error> {
+    let ver = mojokv::Store::load_state(Path::new(dbpath))?.active_ver();
+    let bmap = mojokv::Store::readonly(Path::new(dbpath), ver)?.bucket_map(ver)?;
+    Ok(bmap.page_size(name))
+}
+
+#[test]
+fn kv_journal_and_wal() -> Result<(), Error> {
+    let _ = env_logger::try_init();
+    let fspath = setup_at("kv_journal_and_wal")?;
+    std::fs::create_dir_all(&fspath)?;
+    let dbpath = format!("{}/a.db", fspath);
+
+    // Journal records are not page aligned. The journal of the first
+    // transaction is opened before the page size of the db is known.
+    let conn = sqlite_open(&dbpath, "journal=kv&atomic=0")?;
+    conn.execute_batch("pragma page_size=8192; create table t(a); insert into t values (randomblob(3000));")?;
+    conn.execute_batch("begin; update t set a = randomblob(5000); insert into t values (1); rollback;")?;
+    let n: i64 = conn.query_row("select count(*) from t where length(a) = 3000", [], |r| r.get(0))?;
+    assert_eq!(n, 1);
+
+    // A block of the journal holds a record
+    conn.execute_batch("pragma journal_mode=persist; insert into t values (0); delete from t where a = 0;")?;
+    assert_eq!(bucket_page_size(&dbpath, "a.db")?, None);
+    assert_eq!(bucket_page_size(&dbpath, "a.db-journal")?, Some(8192 + mojofs::JOURNAL_RECORD_HDR_SZ));
+
+    // And a block of the wal a frame
+    conn.execute_batch("pragma journal_mode=wal;")?;
+    for i in 0..20 {
+        conn.execute("insert into t values (?)", [i])?;
+    }
+    let n: i64 = conn.query_row("select count(*) from t", [], |r| r.get(0))?;
+    assert_eq!(n, 21);
+    assert_eq!(bucket_page_size(&dbpath, "a.db-wal")?, Some(8192 + mojofs::WAL_FRAME_HDR_SZ));
+    conn.execute_batch("pragma wal_checkpoint(TRUNCATE);")?;
+    drop(conn);
+
+    let conn = sqlite_open(&dbpath, "")?;
+    let n: i64 = conn.query_row("select count(*) from t", [], |r| r.get(0))?;
+    assert_eq!(n, 21);
+    let ok: String = conn.query_row("pragma integrity_check", [], |r| r.get(0))?;
+    assert_eq!(ok, "ok");
+
+    Ok(())
+}
+
+#[test]
+fn multiple_dbs_in_store() -> Result<(), Error> {
+    let _ = env_logger::try_init();
+    let fspath = setup_at("multiple_dbs_in_store")?;
+    std::fs::create_dir_all(&fspath)?;
+    let store = format!("{}/app.mojo", fspath);
+    let main = format!("{}/main.db", fspath);
+    // The store is next to the dbs
+    let params = |db: &str, extra: &str| format!("store=app.mojo&db={}&pagesz=4096&journal=kv{}", db, extra);
+    let attach = |conn: &rusqlite::Connection, extra: &str| {
+        conn.execute(&format!("attach 'file:{}/aux.db?vfs=mojo&{}' as aux", fspath, params("aux", extra)), [])
+    };
+    let counts = |conn: &rusqlite::Connection| -> Result<(i64, i64), Error> {
+        Ok(conn.query_row("select (select count(*) from t), (select count(*) from aux.u)", [], |r| Ok((r.get(0)?, r.get(1)?)))?)
+    };
+
+    let conn = sqlite_open(&main, &params("main", ""))?;
+    attach(&conn, "")?;
+    conn.execute_batch("pragma page_size=4096; pragma aux.page_size=4096;
+        create table t(a); create table aux.u(a);
+        begin; insert into t values (1); insert into aux.u values (1); commit;")?;
+
+    let ver: String = conn.query_row("pragma mojo_commit", [], |r| r.get(0))?;
+    assert_eq!(ver, "2");
+
+    // A write transaction in any db of the store holds off the commit
+    conn.execute_batch("begin; insert into t values (2); insert into aux.u values (2);")?;
+    let other = sqlite_open(&main, &params("main", ""))?;
+    assert!(other.query_row("pragma mojo_commit", [], |r| r.get::<_, String>(0)).is_err());
+    drop(other);
+    conn.execute_batch("commit;")?;
+
+    // Each db has its own wal and shm
+    conn.execute_batch("pragma journal_mode=wal; pragma aux.journal_mode=wal;
+        insert into t values (3); insert into aux.u values (3);
+        pragma wal_checkpoint(TRUNCATE); pragma aux.wal_checkpoint(TRUNCATE);")?;
+    assert!(Path::new(&store).join("mojo.shm-main").exists());
+    assert!(Path::new(&store).join("mojo.shm-aux").exists());
+    let ver: String = conn.query_row("pragma mojo_commit", [], |r| r.get(0))?;
+    assert_eq!(ver, "3");
+    drop(conn);
+
+    let st = mojokv::Store::readonly(Path::new(&store), 2)?;
+    let bmap = st.bucket_map(2)?;
+    assert!(bmap.exists("main") && bmap.exists("aux"));
+    assert!(bmap.exists("main-wal") && bmap.exists("aux-wal"));
+
+    // Every version is consistent across the dbs
+    for (ver, n) in [(1, 1), (2, 3)] {
+        let old = sqlite_open(&main, &params("main", &format!("&ver={}&mode=ro", ver)))?;
+        attach(&old, &format!("&ver={}&mode=ro", ver))?;
+        assert_eq!(counts(&old)?, (n, n));
+    }
+
+    Ok(())
+}
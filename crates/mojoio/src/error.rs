@@ -18,4 +18,15 @@ pub enum Error {
 
     #[error("Unknown error")]
     Unknown,
-}
\ No newline at end of file
+}
+
+impl Error {
+    /// True if the file does not exist
+    pub fn is_not_found(&self) -> bool {
+        match self {
+            Error::IoErr(err) => err.kind() == std::io::ErrorKind::NotFound,
+            Error::NixErr(err) => *err == nix::errno::Errno::ENOENT,
+            _ => false,
+        }
+    }
+}
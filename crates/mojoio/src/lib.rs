@@ -1,4 +1,6 @@
 pub mod nix;
+pub mod mmap;
+pub mod lock;
 mod error;
 
 pub use error::Error;
@@ -0,0 +1,69 @@
+use nix::fcntl::{self, FcntlArg};
+use nix::errno::Errno;
+use nix::libc;
+
+use crate::Error;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LockKind {
+    Read,
+    Write,
+    Unlock,
+}
+
+impl LockKind {
+    fn to_libc(self) -> libc::c_short {
+        match self {
+            LockKind::Read => libc::F_RDLCK as libc::c_short,
+            LockKind::Write => libc::F_WRLCK as libc::c_short,
+            LockKind::Unlock => libc::F_UNLCK as libc::c_short,
+        }
+    }
+}
+
+fn flock(kind: LockKind, start: u64, len: u64) -> libc::flock {
+    let mut fl: libc::flock = unsafe { std::mem::zeroed() };
+    fl.l_type = kind.to_libc();
+    fl.l_whence = libc::SEEK_SET as libc::c_short;
+    fl.l_start = start as libc::off_t;
+    fl.l_len = len as libc::off_t;
+    fl
+}
+
+/// Sets a posix byte range lock without blocking. Returns false if the
+/// range is locked by another process.
+///
+/// Posix locks are owned by the process, so callers have to do their own
+/// bookkeeping for multiple users within the same process.
+pub fn try_lock_range(fd: i32, kind: LockKind, start: u64, len: u64) -> Result<bool, Error> {
+    let fl = flock(kind, start, len);
+
+    log::debug!("lock range fd={} kind={:?} start={} len={}", fd, kind, start, len);
+    match fcntl::fcntl(fd, FcntlArg::F_SETLK(&fl)) {
+        Ok(_) => Ok(true),
+        Err(Errno::EAGAIN) | Err(Errno::EACCES) => Ok(false),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Sets a posix byte range lock, waiting for conflicting locks to go away
+pub fn lock_range_wait(fd: i32, kind: LockKind, start: u64, len: u64) -> Result<(), Error> {
+    let fl = flock(kind, start, len);
+
+    log::debug!("lock range wait fd={} kind={:?} start={} len={}", fd, kind, start, len);
+    loop {
+        match fcntl::fcntl(fd, FcntlArg::F_SETLKW(&fl)) {
+            Ok(_) => return Ok(()),
+            Err(Errno::EINTR) => continue,
+            Err(err) => return Err(err.into()),
+        }
+    }
+}
+
+/// Returns true if another process holds a lock on the range which
+/// conflicts with `kind`
+pub fn is_range_locked(fd: i32, kind: LockKind, start: u64, len: u64) -> Result<bool, Error> {
+    let mut fl = flock(kind, start, len);
+    fcntl::fcntl(fd, FcntlArg::F_GETLK(&mut fl))?;
+    Ok(fl.l_type != libc::F_UNLCK as libc::c_short)
+}
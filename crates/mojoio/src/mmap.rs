@@ -0,0 +1,98 @@
+use std::ffi::c_void;
+use nix::sys::mman::{self, MapFlags, ProtFlags};
+
+use crate::Error;
+
+/// Read-only memory map of an immutable file
+pub struct Mmap {
+    ptr: *mut c_void,
+    len: usize,
+}
+
+// The mapping is read-only and never changes once created
+unsafe impl Send for Mmap {}
+unsafe impl Sync for Mmap {}
+
+impl Mmap {
+    pub fn map_readonly(fd: i32, len: usize) -> Result<Self, Error> {
+        if len == 0 {
+            return Err(Error::UnknownStr("cannot map empty file".to_owned()));
+        }
+
+        let ptr = unsafe {
+            mman::mmap(std::ptr::null_mut(), len, ProtFlags::PROT_READ, MapFlags::MAP_SHARED, fd, 0)?
+        };
+
+        log::debug!("mmap fd={} len={} ptr={:?}", fd, len, ptr);
+        Ok(Mmap { ptr, len })
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.ptr as *const u8, self.len) }
+    }
+}
+
+impl Drop for Mmap {
+    fn drop(&mut self) {
+        log::debug!("munmap ptr={:?} len={}", self.ptr, self.len);
+        if let Err(err) = unsafe { mman::munmap(self.ptr, self.len) } {
+            log::error!("munmap failed err={:?}", err);
+        }
+    }
+}
+
+/// Writable shared memory map of a region of a file
+pub struct MmapMut {
+    ptr: *mut c_void,
+    len: usize,
+}
+
+// Concurrent access to the mapped memory is coordinated by the users of
+// the map (e.g. sqlite's shm locks)
+unsafe impl Send for MmapMut {}
+unsafe impl Sync for MmapMut {}
+
+impl MmapMut {
+    pub fn map_shared(fd: i32, off: u64, len: usize) -> Result<Self, Error> {
+        if len == 0 {
+            return Err(Error::UnknownStr("cannot map empty region".to_owned()));
+        }
+
+        let ptr = unsafe {
+            mman::mmap(std::ptr::null_mut(), len, ProtFlags::PROT_READ|ProtFlags::PROT_WRITE,
+                MapFlags::MAP_SHARED, fd, off as i64)?
+        };
+
+        log::debug!("mmap shared fd={} off={} len={} ptr={:?}", fd, off, len, ptr);
+        Ok(MmapMut { ptr, len })
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn as_mut_ptr(&self) -> *mut u8 {
+        self.ptr as *mut u8
+    }
+}
+
+impl Drop for MmapMut {
+    fn drop(&mut self) {
+        log::debug!("munmap shared ptr={:?} len={}", self.ptr, self.len);
+        if let Err(err) = unsafe { mman::munmap(self.ptr, self.len) } {
+            log::error!("munmap failed err={:?}", err);
+        }
+    }
+}
@@ -6,9 +6,14 @@ use nix::sys::stat::Mode;
 //use crate::value;
 use crate::Error;
 
+// Keeps the iovec count of a vectored call well below IOV_MAX
+const MAX_IOV_BLOCKS: usize = 256;
+
 pub struct NixFile {
     file_fd: i32,
     curr_off: u64,
+    alloc_off: u64,
+    prealloc_chunk: u64,
     page_header_buf: [u8; crate::PAGE_HEADER_LEN],
     page_header: PageHeader,
 }
@@ -26,14 +31,37 @@ impl NixFile {
         Ok(NixFile {
             file_fd,
             curr_off,
+            alloc_off: curr_off,
+            prealloc_chunk: 0,
             page_header_buf: [0; crate::PAGE_HEADER_LEN],
             page_header: PageHeader::new(), 
         })
     }
 
+    pub fn open_readonly(filepath: &Path) -> Result<Self, Error> {
+        let file_fd = fcntl::open(filepath, OFlag::O_RDONLY, Mode::empty())?;
+
+        log::debug!("open readonly path={:?} fd={}", filepath, file_fd);
+
+        Ok(NixFile {
+            file_fd,
+            curr_off: 0,
+            alloc_off: 0,
+            prealloc_chunk: 0,
+            page_header_buf: [0; crate::PAGE_HEADER_LEN],
+            page_header: PageHeader::new(),
+        })
+    }
+
     pub fn close(&mut self) -> Result<(), Error> {
+        if self.file_fd < 0 {
+            return Ok(());
+        }
+
         log::debug!("close fd={}", self.file_fd);
-        nix::unistd::close(self.file_fd)?;
+        let fd = self.file_fd;
+        self.file_fd = -1;
+        nix::unistd::close(fd)?;
         Ok(())
     }
 
@@ -55,20 +83,164 @@ impl NixFile {
         Ok(())
     }
 
-    pub fn write_buf(&mut self, block_no: u32, poff: u64, buf: &[u8]) -> Result<u64, Error> {
+    /// Writes consecutive blocks, each a header followed by the buffer,
+    /// starting at `off` using vectored writes.
+    pub fn write_blocks_at(&mut self, off: u64, blocks: &[(u32, &[u8])]) -> Result<(), Error> {
+        let mut off = off;
+        for chunk in blocks.chunks(MAX_IOV_BLOCKS) {
+            let headers: Vec<[u8; crate::PAGE_HEADER_LEN]> = chunk.iter().map(|(block_no, _)| {
+                let mut h = [0u8; crate::PAGE_HEADER_LEN];
+                self.page_header.block_no = *block_no;
+                self.page_header.encode(&mut h);
+                h
+            }).collect();
+
+            let mut io_bufs = Vec::with_capacity(chunk.len() * 2);
+            let mut total = 0;
+            for (h, (_, buf)) in headers.iter().zip(chunk) {
+                io_bufs.push(std::io::IoSlice::new(h));
+                io_bufs.push(std::io::IoSlice::new(buf));
+                total += h.len() + buf.len();
+            }
+
+            log::debug!("file write blocks at fd={} off={} blocks={} {}", self.file_fd, off, chunk.len(), total);
+            let n = nix::sys::uio::pwritev(self.file_fd, &io_bufs, off as i64)?;
+            if n < total {
+                return Err(Error::UnknownStr("vectored write did not write all data".to_owned()));
+            }
+            off += total as u64;
+        }
+
+        Ok(())
+    }
+
+    /// Appends the blocks at the end and returns the offset of the first
+    pub fn append_blocks(&mut self, blocks: &[(u32, &[u8])]) -> Result<u64, Error> {
+        let total: u64 = blocks.iter().map(|(_, b)| (b.len() + NixFile::header_len()) as u64).sum();
+        self.reserve(total)?;
+
+        let off = self.curr_off;
+        self.write_blocks_at(off, blocks)?;
+        self.curr_off += total;
+
+        Ok(off)
+    }
+
+    /// Reads consecutive blocks starting at `off`. The block headers are
+    /// skipped and only the buffers are filled. Returns the number of
+    /// buffer bytes read.
+    pub fn read_blocks_at(&self, off: u64, bufs: &mut [&mut [u8]]) -> Result<usize, Error> {
+        let mut off = off;
+        let mut nread = 0;
+
+        for chunk in bufs.chunks_mut(MAX_IOV_BLOCKS) {
+            let mut headers = vec![[0u8; crate::PAGE_HEADER_LEN]; chunk.len()];
+            let buf_lens: Vec<usize> = chunk.iter().map(|b| b.len()).collect();
+            let total: usize = buf_lens.iter().map(|l| l + NixFile::header_len()).sum();
+
+            let mut io_bufs = Vec::with_capacity(chunk.len() * 2);
+            for (h, buf) in headers.iter_mut().zip(chunk.iter_mut()) {
+                io_bufs.push(std::io::IoSliceMut::new(h));
+                io_bufs.push(std::io::IoSliceMut::new(buf));
+            }
+
+            log::debug!("file read blocks at fd={} off={} blocks={} {}", self.file_fd, off, buf_lens.len(), total);
+            let n = nix::sys::uio::preadv(self.file_fd, &mut io_bufs, off as i64)?;
+
+            let mut rem = n;
+            for len in buf_lens {
+                rem -= rem.min(NixFile::header_len());
+                let b = rem.min(len);
+                nread += b;
+                rem -= b;
+            }
+
+            if n < total {
+                break;
+            }
+            off += total as u64;
+        }
+
+        Ok(nread)
+    }
+
+    /// Appends grow the file in chunks of `chunk` bytes. The space is
+    /// allocated beyond the end of file, so the file size keeps tracking
+    /// the append offset. Zero disables the preallocation.
+    pub fn set_prealloc_chunk(&mut self, chunk: u64) {
+        self.prealloc_chunk = chunk;
+    }
+
+    pub fn curr_off(&self) -> u64 {
+        self.curr_off
+    }
+
+    pub fn alloc_off(&self) -> u64 {
+        self.alloc_off
+    }
+
+    fn reserve(&mut self, len: u64) -> Result<(), Error> {
+        let end = self.curr_off + len;
+        if self.prealloc_chunk == 0 || end <= self.alloc_off {
+            return Ok(());
+        }
+
+        let new_alloc = end.div_ceil(self.prealloc_chunk) * self.prealloc_chunk;
+        log::debug!("preallocating fd={} off={} len={}", self.file_fd, self.alloc_off, new_alloc - self.alloc_off);
+
+        let alloc_from = self.alloc_off.max(self.curr_off);
+        match fallocate_keep_size(self.file_fd, alloc_from, new_alloc - alloc_from) {
+            Ok(_) => {
+                self.alloc_off = new_alloc;
+            },
+            Err(nix::errno::Errno::EOPNOTSUPP) => {
+                log::debug!("fallocate not supported fd={}. Disabling preallocation", self.file_fd);
+                self.prealloc_chunk = 0;
+            },
+            Err(err) => return Err(err.into()),
+        }
+
+        Ok(())
+    }
+
+    /// Releases the space of the given range without changing the file size
+    pub fn punch_hole(&self, off: u64, len: u64) -> Result<(), Error> {
+        log::debug!("punch hole fd={} off={} len={}", self.file_fd, off, len);
+        match punch_hole(self.file_fd, off, len) {
+            Ok(_) | Err(nix::errno::Errno::EOPNOTSUPP) => Ok(()),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Appends a block holding the buffer and returns its offset
+    pub fn write_buf(&mut self, block_no: u32, buf: &[u8]) -> Result<u64, Error> {
+        self.reserve(buf.len() as u64 + NixFile::header_len() as u64)?;
         self.write_buf_at(self.curr_off, block_no, buf)?;
 
         let page_off = self.curr_off;
-        //let page_off = self.curr_off;
         self.curr_off += buf.len() as u64 + NixFile::header_len() as u64;
-        self.curr_off += poff;
-        //self.curr_off += buf.len() as u64;
 
         Ok(page_off)
     }
 
+    /// Writes the buffer at `off` without a block header, used to update
+    /// part of a block in place
+    pub fn write_at(&mut self, off: u64, buf: &[u8]) -> Result<(), Error> {
+        log::debug!("file write data at fd={} off={} {}", self.file_fd, off, buf.len());
+        let mut i = 0;
+        while i < buf.len() {
+            let n = nix::sys::uio::pwrite(self.file_fd, &buf[i..], off as i64 + i as i64)?;
+            if n == 0 {
+                return Err(Error::UnknownStr("write did not write any data".to_owned()));
+            }
+            i += n;
+        }
+
+        Ok(())
+    }
+
     pub fn header_len() -> usize {
-        return crate::PAGE_HEADER_LEN;
+        crate::PAGE_HEADER_LEN
     }
 
     fn read_all_at(&self, off: u64, buf: &mut [u8]) -> Result<usize, Error> {
@@ -90,6 +262,15 @@ impl NixFile {
         Ok(n)
     }
 
+    pub fn fd(&self) -> i32 {
+        self.file_fd
+    }
+
+    pub fn file_size(&self) -> Result<u64, Error> {
+        let st = nix::sys::stat::fstat(self.file_fd)?;
+        Ok(st.st_size as u64)
+    }
+
     pub fn sync(&self) -> Result<(), Error> {
         log::debug!("sync fd={}", self.file_fd);
         nix::unistd::fsync(self.file_fd)?;
@@ -97,6 +278,34 @@ impl NixFile {
     }
 }
 
+#[cfg(target_os = "linux")]
+fn fallocate_keep_size(fd: i32, off: u64, len: u64) -> nix::Result<()> {
+    fcntl::fallocate(fd, fcntl::FallocateFlags::FALLOC_FL_KEEP_SIZE, off as i64, len as i64)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn fallocate_keep_size(_fd: i32, _off: u64, _len: u64) -> nix::Result<()> {
+    Err(nix::errno::Errno::EOPNOTSUPP)
+}
+
+#[cfg(target_os = "linux")]
+fn punch_hole(fd: i32, off: u64, len: u64) -> nix::Result<()> {
+    let mode = fcntl::FallocateFlags::FALLOC_FL_PUNCH_HOLE | fcntl::FallocateFlags::FALLOC_FL_KEEP_SIZE;
+    fcntl::fallocate(fd, mode, off as i64, len as i64)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn punch_hole(_fd: i32, _off: u64, _len: u64) -> nix::Result<()> {
+    Err(nix::errno::Errno::EOPNOTSUPP)
+}
+
+impl Drop for NixFile {
+    fn drop(&mut self) {
+        if let Err(err) = self.close() {
+            log::error!("close on drop failed err={:?}", err);
+        }
+    }
+}
 
 struct PageHeader {
     magic: &'static [u8],
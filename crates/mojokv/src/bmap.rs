@@ -1,15 +1,22 @@
 
+use std::borrow::Cow;
 use std::path::{Path, PathBuf};
 use std::collections::HashMap;
 use std::sync::Arc;
 use crate::bucket::Bucket;
+use crate::fcache::FileCache;
 use crate::Error;
 use parking_lot::RwLock;
 use serde::{Serialize, Deserialize};
 
+/// Version of each bucket. Buckets are named by a normalized path relative
+/// to the directory of the store, e.g. `a.db` or `logs/a.db-wal`.
 #[derive(Clone, Default, Debug, Serialize, Deserialize)]
 pub struct BucketMap {
     map: Arc<RwLock<HashMap<String, u32>>>,
+    // Page size of the buckets which do not use the one of the store
+    #[serde(default)]
+    page_sizes: Arc<RwLock<HashMap<String, u32>>>,
 }
 
 impl BucketMap {
@@ -25,19 +32,47 @@ impl BucketMap {
         map.contains_key(name)
     } 
 
+    /// True if another bucket has the files `name` would have, e.g. `a%2Fb`
+    /// and `a/b`
+    pub fn stem_taken(&self, name: &str) -> bool {
+        let stem = file_stem(name);
+        self.map.read().keys().any(|k| k != name && file_stem(k) == stem)
+    }
+
     pub fn get(&self, name: &str) -> Option<u32>{
         log::debug!("get name={}", name);
         let map = self.map.read();
-        map.get(name).map(|v| *v)
+        map.get(name).copied()
     }
 
-    pub fn delete(&self, root_path: &Path, name: &str, ver: u32) -> Result<(), Error> {
+    /// Records the page size of a bucket, None if it uses the one of the
+    /// store and 0 if it has one of its own not known yet
+    pub fn set_page_size(&self, name: &str, page_sz: Option<u32>) {
+        log::debug!("set page size name={} page_sz={:?}", name, page_sz);
+        let mut page_sizes = self.page_sizes.write();
+        match page_sz {
+            Some(sz) => page_sizes.insert(name.to_owned(), sz),
+            None => page_sizes.remove(name),
+        };
+    }
+
+    /// Page size of the bucket, None if it uses the one of the store
+    pub fn page_size(&self, name: &str) -> Option<u32> {
+        self.page_sizes.read().get(name).copied()
+    }
+
+    pub fn delete(&self, root_path: &Path, name: &str, ver: u32, fcache: &FileCache) -> Result<(), Error> {
         log::debug!("delete name={} {:?}", name, self.map);
         let mut map = self.map.write();
 
-        map.remove(name);
+        let bver = map.remove(name);
+        self.page_sizes.write().remove(name);
 
-        Bucket::delete_ver(root_path, name, ver)?;
+        // A bucket not written since the last commit has no files in the
+        // active version, the ones of older versions stay
+        if bver == Some(ver) {
+            Bucket::delete_ver(root_path, name, ver, fcache)?;
+        }
 
         Ok(())
     }
@@ -48,6 +83,17 @@ impl BucketMap {
         Ok(map.clone())
     }
 
+    /// Replaces the entries with the ones of `other`. Clones of this map
+    /// see the change.
+    pub fn update_from(&self, other: &BucketMap) {
+        let src = other.map.read().clone();
+        let mut map = self.map.write();
+        *map = src;
+
+        let src = other.page_sizes.read().clone();
+        *self.page_sizes.write() = src;
+    }
+
     pub fn serialize_to_path(&self, path: &Path) -> Result<(), Error> {
         let buf = serde_json::to_vec(&self)?;
         log::debug!("serializing bmap={:?}", std::str::from_utf8(&buf));
@@ -55,23 +101,43 @@ impl BucketMap {
         Ok(())
     }
 
-    pub fn deserialize_from_path(path: &Path) -> Result<Self, Error> {
+    pub fn deserialize_from_path(path: &Path, fcache: &FileCache) -> Result<Self, Error> {
         let mut buf = Vec::new();
-        crate::utils::load_file(path, &mut buf)?;
+        fcache.load_file(path, &mut buf)?;
 
         let map = serde_json::from_slice(&buf)?;
         Ok(map)
     }
 
     fn bmap_path(root_path: &Path, ver: u32) -> PathBuf {
-        root_path.join(&format!("mojo.bmap.{}", ver))
+        root_path.join(format!("mojo.bmap.{}", ver))
     }
 
-    pub fn load(root_path: &Path, ver: u32) -> Result<Self, Error> {
+    pub fn load(root_path: &Path, ver: u32, fcache: &FileCache) -> Result<Self, Error> {
         let bmap_path = Self::bmap_path(root_path, ver);
         log::debug!("loading bmap from path={:?}", bmap_path);
-        let bmap = Self::deserialize_from_path(&bmap_path)?;
+        let bmap = Self::deserialize_from_path(&bmap_path, fcache)?;
 
         Ok(bmap)
     }
-}
\ No newline at end of file
+}
+
+/// Checks that `name` is a normalized relative path: '/' separated, with no
+/// empty, `.` or `..` components.
+pub fn check_name(name: &str) -> Result<(), Error> {
+    let bad = name.split('/').any(|c| c.is_empty() || c == "." || c == "..");
+    if bad {
+        return Err(Error::BucketNameErr(name.to_owned()));
+    }
+    Ok(())
+}
+
+/// Stem of the files of bucket `name`. In a nested name '%' and '/' are
+/// escaped so every file stays in the store directory. Any other name is
+/// its own stem, as it was before names could be nested.
+pub fn file_stem(name: &str) -> Cow<'_, str> {
+    if !name.contains('/') {
+        return Cow::Borrowed(name);
+    }
+    Cow::Owned(name.replace('%', "%25").replace('/', "%2F"))
+}
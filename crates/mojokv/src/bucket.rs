@@ -1,22 +1,27 @@
 
-use std::collections::HashSet;
 use std::path::{Path, PathBuf};
 use crate::{Error, BucketMap};
 use mojoio::nix::NixFile;
 use crate::index::mem::MemIndex;
 use crate::value::Value;
 use crate::state::State;
+use crate::fcache::{FileCache, FileRef, PageRef};
+use crate::txnlog::TxnLog;
+use crate::bmap::file_stem;
 
 pub struct BucketInner {
     name: String,
     root_path: PathBuf,
     index: MemIndex,
+    page_sz: u32,
     file_page_sz: usize,
     fmap: FileMap,
     is_dirty: bool,
     is_modified: bool,
     is_closed: bool,
     active_ver: u32,
+    txn_log: Option<TxnLog>,
+    released: Vec<u32>,
 }
 
 impl BucketInner {
@@ -24,16 +29,64 @@ impl BucketInner {
         self.fmap.file_mut(ver)
     }
 
-    fn sync_index(&mut self, ver: u32) -> Result<(), Error> {
+    // Blocks of the active version can be overwritten unless an earlier
+    // transaction in the txn log refers to them
+    fn can_overwrite(&self, key: u32) -> bool {
+        self.txn_log.as_ref().map(|l| l.is_touched(key)).unwrap_or(true)
+    }
+
+    fn touch(&mut self, key: u32) {
+        if let Some(l) = self.txn_log.as_mut() {
+            l.touch(key);
+        }
+    }
+
+    // An atomic sync writes the index to a temp file which replaces the
+    // index, so a crash leaves either the old or the new index
+    fn sync_index(&mut self, ver: u32, atomic: bool) -> Result<(), Error> {
         let non_ref_vers =self.index.update_min_max_ver();
 
         log::debug!("closing versions={:?} as they are no longer referenced", non_ref_vers);
         self.fmap.close_versions(&non_ref_vers, self.active_ver)?;
 
         let index_path = Bucket::index_path(&self.root_path, self.name.as_str(), ver);
-        log::debug!("syncing index ver={} {:?}", ver, index_path);
-        self.index.serialize_to_path(&index_path)?;
+        log::debug!("syncing index ver={} {:?} atomic={}", ver, index_path, atomic);
+        if atomic {
+            let tmp_path = self.root_path.join(format!("{}_i.{}.tmp", file_stem(&self.name), ver));
+            self.index.serialize_to_path(&tmp_path)?;
+            std::fs::rename(&tmp_path, &index_path)?;
+            crate::utils::sync_dir(&self.root_path)?;
+        }else{
+            self.index.serialize_to_path(&index_path)?;
+        }
         log::debug!("syncing index ver={} done", ver);
+
+        self.punch_released()
+    }
+
+    // Released blocks are punched only once an index without them is
+    // persisted, before that a crash leaves the old index referring to
+    // them. Contiguous blocks are punched together as the block size is
+    // not fs block aligned.
+    fn punch_released(&mut self) -> Result<(), Error> {
+        let mut released = std::mem::take(&mut self.released);
+        released.sort_unstable();
+        released.dedup();
+
+        let file_page_sz = self.file_page_sz as u64;
+        let mut i = 0;
+        while i < released.len() {
+            let mut j = i + 1;
+            while j < released.len() && released[j] == released[j-1] + 1 {
+                j += 1;
+            }
+
+            let off = released[i] as u64 * file_page_sz;
+            let len = (j - i) as u64 * file_page_sz;
+            self.fmap.active.punch_hole(off, len)?;
+            i = j;
+        }
+
         Ok(())
     }
 }
@@ -61,20 +114,20 @@ impl Bucket {
         self.is_write = true
     }
 
-    pub fn readonly(root_path: &Path, name: &str, ver: u32, state: State, bmap: BucketMap) -> Result<Bucket, Error> {
+    pub fn readonly(root_path: &Path, name: &str, ver: u32, state: State, bmap: BucketMap, fcache: FileCache) -> Result<Bucket, Error> {
         log::debug!("bucket name={} readonly at ver={}", name, ver);
 
-        let b = Self::load(root_path, name,  state, bmap, ver)?;
+        let b = Self::load(root_path, name, state, bmap, fcache, ver)?;
         Ok(b)
     }
 
     fn index_path(rootpath: &Path, name: &str, ver: u32) -> PathBuf {
-        rootpath.join(&format!("{}_i.{}", name, ver))
+        rootpath.join(format!("{}_i.{}", file_stem(name), ver))
     }
 
     pub fn get_key(&self, key: u32) -> Result<Option<Value>, Error> {
         //let inner = self.inner.read();
-        Ok(self.inner.index.get(key)?.map(|v| v.clone()))
+        Ok(self.inner.index.get(key)?.copied())
     }
 
     pub fn max_key(&self) ->  isize {
@@ -87,18 +140,27 @@ impl Bucket {
         self.inner.is_modified
     }
 
-    pub fn writable(root_path: &Path, name: &str, state: State, bmap: BucketMap, load_ver: u32) -> Result<Bucket, Error> {
+    pub fn is_write(&self) -> bool {
+        self.is_write
+    }
+
+    /// True if pages were written since the last sync
+    pub fn is_dirty(&self) -> bool {
+        self.inner.is_dirty
+    }
+
+    pub fn writable(root_path: &Path, name: &str, state: State, bmap: BucketMap, fcache: FileCache, load_ver: u32) -> Result<Bucket, Error> {
         log::debug!("mojo initing bucket pps={}", state.pps());
 
         let aver = state.active_ver();
         let index_path = Self::index_path(root_path, name, load_ver);
 
-        let mut b = if index_path.exists() {
+        let mut b = if fcache.file_exists(&index_path)? {
             log::debug!("bucket index for version={} exists", load_ver);
-            Self::load(root_path, name, state, bmap, aver)?
+            Self::load(root_path, name, state, bmap, fcache, aver)?
         }else{
             log::debug!("creating new bucket at ver={}", aver);
-            let mut b = Self::new(root_path, name, state, bmap)?;
+            let mut b = Self::new(root_path, name, state, bmap, fcache)?;
             b.sync()?;
             b
         };
@@ -110,66 +172,72 @@ impl Bucket {
         Ok(b)
     }
 
-    pub fn load(root_path: &Path, name: &str, state: State, bmap: BucketMap, ver: u32) -> Result<Self, Error> {
+    pub fn load(root_path: &Path, name: &str, state: State, bmap: BucketMap, fcache: FileCache, ver: u32) -> Result<Self, Error> {
         log::debug!("loading bucket={} version={}", name, ver);
 
         if ver < state.min_ver() || ver > state.active_ver() {
             return Err(Error::VersionNotFoundErr(ver));
         }
 
-        let (_, _, mut index) = Self::load_index(root_path, name, ver)?;
-        let fmap = FileMap::init(root_path, name, &index.header().vset, state.active_ver())?;
+        let (_, _, mut index) = Self::load_index(root_path, name, ver, &fcache)?;
+        let fmap = FileMap::init(root_path, name, state.active_ver(), state.prealloc_sz(), fcache)?;
         index.set_active_ver(state.active_ver());
 
-        let file_page_sz = state.page_size() as usize + NixFile::header_len();
+        let page_sz = Self::page_size_of(name, &state, &bmap);
 
         let inner = BucketInner {
             name: name.to_owned(),
             root_path: root_path.to_owned(),
             index,
-            file_page_sz,
+            page_sz,
+            file_page_sz: page_sz as usize + NixFile::header_len(),
             fmap,
             is_dirty: false,
             is_modified: false,
             is_closed: false,
             active_ver: state.active_ver(),
+            txn_log: None,
+            released: Vec::new(),
         };
 
         log::debug!("mojo load version done");
         Ok(Bucket::with_inner(state, inner, bmap))
     }
 
-    pub fn load_index(root_path: &Path, name: &str, ver: u32) -> Result<(usize, usize, MemIndex), Error> {
+    pub fn load_index(root_path: &Path, name: &str, ver: u32, fcache: &FileCache) -> Result<(usize, usize, MemIndex), Error> {
         let index_path = Self::index_path(root_path, name, ver);
 
         log::debug!("loading index={:?} for name={} at ver={}", index_path, name, ver);
-        if !index_path.exists() {
-            return Err(Error::BucketNotAtVerErr(name.to_owned(), ver));
-        }
-
-        let index = MemIndex::deserialize_from_path(&index_path)?;
+        let index = match MemIndex::deserialize_from_path(&index_path, fcache) {
+            Err(err) if err.is_not_found() => return Err(Error::BucketNotAtVerErr(name.to_owned(), ver)),
+            ret => ret?,
+        };
 
         Ok(index)
     }
 
-    pub fn new(root_path: &Path, name: &str, state: State, bmap: BucketMap) -> Result<Self, Error> {
+    pub fn new(root_path: &Path, name: &str, state: State, bmap: BucketMap, fcache: FileCache) -> Result<Self, Error> {
         log::debug!("creating new bucket name={} at ver={}", name, state.active_ver());
 
-        let _ = std::fs::create_dir_all(root_path)?;
+        std::fs::create_dir_all(root_path)?;
 
         let index = MemIndex::new(state.pps() as usize);
-        let fmap =  FileMap::init(root_path, name, &index.header().vset, state.active_ver())?;
+        let fmap = FileMap::init(root_path, name, state.active_ver(), state.prealloc_sz(), fcache)?;
+        let page_sz = Self::page_size_of(name, &state, &bmap);
 
         let mut inner = BucketInner {
             name: name.to_owned(),
             root_path: root_path.to_owned(),
             index,
-            file_page_sz: state.page_size() as usize + NixFile::header_len(),
+            page_sz,
+            file_page_sz: page_sz as usize + NixFile::header_len(),
             fmap,
             is_dirty: false,
             is_modified: false,
             is_closed: false,
             active_ver: state.active_ver(),
+            txn_log: None,
+            released: Vec::new(),
         };
 
         inner.index.set_active_ver(state.active_ver());
@@ -177,9 +245,65 @@ impl Bucket {
         Ok(b)
     }
 
+    // Buckets with a page size of their own have it in the bucket map
+    /// Byte size of bucket `name` at `ver`, with the start of its first
+    /// page read into `buf` (zeroed if the page was never written). Only
+    /// reads the index and data files, nothing is created or opened for
+    /// writing.
+    pub(crate) fn peek(root_path: &Path, name: &str, ver: u32, state: &State, bmap: &BucketMap, fcache: &FileCache, buf: &mut [u8]) -> Result<u64, Error> {
+        let (_, _, index) = Self::load_index(root_path, name, ver, fcache)?;
+        let page_sz = Self::page_size_of(name, state, bmap);
+        let pages_sz = (page_sz as isize * (index.max_key() + 1)) as u64;
+        let size = index.size().unwrap_or(pages_sz);
+
+        buf.fill(0);
+        let value = match index.get(0)? {
+            Some(v) if v.is_allocated() && size > 0 => *v,
+            _ => return Ok(size),
+        };
+
+        let off = (value.get_off() as u64) * (page_sz as u64 + NixFile::header_len() as u64) + NixFile::header_len() as u64;
+        let data_path = FileMap::data_path(root_path, name, value.get_ver());
+
+        // The version file can be active and still growing, so it is only
+        // read through the file cache once it has been packed
+        match NixFile::open_readonly(&data_path).map_err(Error::from) {
+            Ok(f) => { f.read_buf_at(off, buf)?; },
+            Err(err) if err.is_not_found() => { fcache.get(&data_path)?.read_buf_at(off, buf)?; },
+            Err(err) => return Err(err),
+        }
+
+        Ok(size)
+    }
+
+    fn page_size_of(name: &str, state: &State, bmap: &BucketMap) -> u32 {
+        bmap.page_size(name).unwrap_or_else(|| state.page_size())
+    }
+
+    /// Page size of the bucket, zero while the store has not learnt it. A
+    /// page size learnt after the bucket was opened is seen here.
+    pub fn page_size(&self) -> u32 {
+        match self.inner.page_sz {
+            0 => Self::page_size_of(&self.inner.name, &self.state, &self.bmap),
+            sz => sz,
+        }
+    }
+
+    // Picks up the page size the store learnt after the bucket was opened.
+    // Nothing can be written without one, so the bucket is still empty.
+    fn learn_page_size(&mut self) {
+        if self.inner.page_sz == 0 {
+            self.inner.page_sz = Self::page_size_of(&self.inner.name, &self.state, &self.bmap);
+            self.inner.file_page_sz = self.inner.page_sz as usize + NixFile::header_len();
+        }
+    }
+
+    /// Length in bytes of the data put in the bucket. Indexes written
+    /// before the length was kept report whole pages.
     pub fn logical_size(&self) -> u64 {
         //let inner = self.inner.read();
-        (self.state.page_size() as isize * (self.inner.index.max_key() + 1)) as u64
+        let pages_sz = (self.inner.page_sz as isize * (self.inner.index.max_key() + 1)) as u64;
+        self.inner.index.size().unwrap_or(pages_sz)
     }
 
     pub fn close(mut self) -> Result<(), Error> {
@@ -194,17 +318,51 @@ impl Bucket {
     }
 
     pub fn truncate(&mut self, new_sz: usize) -> Result<(), Error> {
-        let _ = self.state.commit_lock.read();
+        self.learn_page_size();
+        if self.inner.page_sz == 0 {
+            return Err(Error::PageSizeUnknownErr);
+        }
+        let page_sz = self.inner.page_sz as usize;
+
+        // The bytes past the end of a partial last page must read as zeros
+        // when the bucket grows again
+        let size = self.logical_size() as usize;
+        let from = new_sz.min(size);
+        if new_sz != size && !from.is_multiple_of(page_sz) && self.get_value_opt((from/page_sz) as u32)?.is_some() {
+            let tail = from % page_sz;
+            self.put((from/page_sz) as u32, tail as u64, &vec![0u8; page_sz - tail])?;
+        }
+
+        let commit_lock = self.state.commit_lock.clone();
+        let _commit_guard = commit_lock.read();
 
         //let mut inner = self.inner.write();
         log::debug!("truncate bucket={} new_sz={}", self.inner.name, new_sz);
-        let pages = new_sz/(self.state.page_size() as usize);
-        //let real_sz = pages * self.file_page_sz;
+        let pages = new_sz.div_ceil(page_sz);
+
+        let aver = self.inner.active_ver;
+        let mut released = Vec::new();
+        for key in pages as isize..=self.inner.index.max_key() {
+            if let Some(val) = self.inner.index.get(key as u32)? {
+                if val.is_allocated() && val.get_ver() == aver {
+                    released.push(val.get_off());
+                }
+            }
+        }
 
         self.inner.index.truncate(pages as u32)?;
+        self.inner.index.set_size(Some(new_sz as u64));
         self.inner.is_modified = true;
-        //TODO: Delete blocks from file
-        //self.active_file().truncate(real_sz)?;
+
+        // Earlier transactions in the log still refer to the blocks
+        if let Some(l) = self.inner.txn_log.as_mut() {
+            l.set_truncated();
+            return Ok(());
+        }
+
+        // Blocks of the active version are no longer referenced by any
+        // version, their space is given back on the next sync
+        self.inner.released.extend(released);
 
         Ok(())
     }
@@ -212,10 +370,16 @@ impl Bucket {
     fn put_at(&mut self, key: u32, page_off: u64, buf: &[u8], val: &Value) -> Result<(), Error> {
 
         let mut off = val.get_off() as u64;
-        off = off * self.inner.file_page_sz as u64;
-        off += page_off;
+        off *= self.inner.file_page_sz as u64;
+        let whole = page_off == 0 && buf.len() == self.inner.page_sz as usize;
         let file = self.inner.active_file(self.state.active_ver());
-        file.write_buf_at(off, key, buf)?;
+
+        // A partial write leaves the header and the rest of the block as is
+        if whole {
+            file.write_buf_at(off, key, buf)?;
+        }else{
+            file.write_at(off + NixFile::header_len() as u64 + page_off, buf)?;
+        }
 
         Ok(())
     }
@@ -229,23 +393,34 @@ impl Bucket {
             return Err(Error::VerNotWritable(self.inner.active_ver, self.state.active_ver()));
         }
 
-        let _ = self.state.commit_lock.read();
+        self.learn_page_size();
+        if self.inner.file_page_sz == NixFile::header_len() {
+            return Err(Error::PageSizeUnknownErr);
+        }
+
+        let commit_lock = self.state.commit_lock.clone();
+        let _commit_guard = commit_lock.read();
 
         log::debug!("store put aver={} key={}, buflen={}", self.state.active_ver(), key, buf.len());
 
-        let val_opt = self.get_value_opt(key)?.map(|v| v.clone());
+        let end = key as u64 * self.inner.page_sz as u64 + page_off + buf.len() as u64;
+        let size = self.logical_size().max(end);
+        let val_opt = self.get_value_opt(key)?;
+        let partial = page_off != 0 || buf.len() != self.inner.page_sz as usize;
 
         match val_opt {
             Some(val) => {
                 //let mut inner = self.inner.write();
 
                 log::debug!("store put value exists value={:?}", val);
-                if val.get_ver() == self.state.active_ver() {
+                if val.get_ver() == self.state.active_ver() && self.inner.can_overwrite(key) {
                     self.put_at(key, page_off, buf, &val)?;
                     self.inner.index.put(key, val.get_off())?;
+                }else if partial {
+                    self.copy_on_write(key, page_off, buf, Some(&val))?;
                 }else{
                     let file = self.inner.active_file(self.state.active_ver());
-                    let write_off = file.write_buf(key, page_off, buf)?;
+                    let write_off = file.write_buf(key, buf)?;
                     let block_no = (write_off/(self.inner.file_page_sz as u64)) as u32;
                     log::debug!("bucket put was done at block_no={} old value={:?}", block_no, val);
                     self.inner.index.put(key, block_no)?;
@@ -253,11 +428,16 @@ impl Bucket {
                 self.inner.is_dirty = true;
                 self.inner.is_modified = true;
             },
+            None if partial => {
+                self.copy_on_write(key, page_off, buf, None)?;
+                self.inner.is_dirty = true;
+                self.inner.is_modified = true;
+            },
             None => {
                 //let mut inner = self.inner.write();
                 
                 let file = self.inner.active_file(self.state.active_ver());
-                let write_off = file.write_buf(key, page_off, buf)?;
+                let write_off = file.write_buf(key, buf)?;
                 let block_no = (write_off/(self.inner.file_page_sz as u64)) as u32;
 
                 self.inner.index.put(key, block_no)?;
@@ -267,9 +447,227 @@ impl Bucket {
             }
         }
 
+        self.inner.index.set_size(Some(size));
+        self.inner.touch(key);
+        Ok(())
+    }
+
+    // Writes a partial page to a new block. The rest of the page is copied
+    // from the old block, which stays as it is for the earlier transactions
+    // and versions, or is zero for a new page.
+    fn copy_on_write(&mut self, key: u32, page_off: u64, buf: &[u8], val: Option<&Value>) -> Result<(), Error> {
+        let mut page = vec![0u8; self.inner.page_sz as usize];
+        if let Some(val) = val {
+            let read_off = val.get_off() as u64 * self.inner.file_page_sz as u64 + NixFile::header_len() as u64;
+            self.inner.fmap.read_at(val.get_ver(), read_off, &mut page)?;
+        }
+
+        let start = page_off as usize;
+        if start + buf.len() > page.len() {
+            return Err(Error::UnknownStr(format!("write of len={} at page off={} crosses the page", buf.len(), page_off)));
+        }
+        page[start..start+buf.len()].copy_from_slice(buf);
+
+        let aver = self.state.active_ver();
+        let file = self.inner.active_file(aver);
+        let write_off = file.append_blocks(&[(key, &page)])?;
+        let block_no = (write_off/(self.inner.file_page_sz as u64)) as u32;
+        log::debug!("bucket copy on write key={} block_no={} old value={:?}", key, block_no, val);
+
+        self.inner.index.put(key, block_no)?;
+        Ok(())
+    }
+
+    /// Writes full pages for the consecutive keys starting at `key`. Pages
+    /// whose blocks are contiguous in the active file are written with a
+    /// single vectored write, as are all the newly appended pages.
+    pub fn put_many(&mut self, key: u32, bufs: &[&[u8]]) -> Result<(), Error> {
+        if !self.is_write {
+            return Err(Error::BucketNotWritableErr);
+        }
+
+        if self.inner.active_ver < self.state.active_ver() {
+            return Err(Error::VerNotWritable(self.inner.active_ver, self.state.active_ver()));
+        }
+
+        self.learn_page_size();
+        if self.inner.file_page_sz == NixFile::header_len() {
+            return Err(Error::PageSizeUnknownErr);
+        }
+
+        let page_sz = self.inner.page_sz as usize;
+        if let Some(buf) = bufs.iter().find(|b| b.len() != page_sz) {
+            return Err(Error::UnknownStr(format!("put_many buf len={} is not page size={}", buf.len(), page_sz)));
+        }
+
+        let commit_lock = self.state.commit_lock.clone();
+        let _commit_guard = commit_lock.read();
+
+        let aver = self.state.active_ver();
+        log::debug!("store put_many aver={} key={}, pages={}", aver, key, bufs.len());
+
+        let size = self.logical_size().max((key as u64 + bufs.len() as u64) * page_sz as u64);
+
+        let mut inplace: Vec<(u32, (u32, &[u8]))> = Vec::new();
+        let mut append: Vec<(u32, &[u8])> = Vec::new();
+        for (i, buf) in bufs.iter().enumerate() {
+            let k = key + i as u32;
+            match self.get_value_opt(k)? {
+                Some(val) if val.get_ver() == aver && self.inner.can_overwrite(k) => inplace.push((val.get_off(), (k, *buf))),
+                _ => append.push((k, *buf)),
+            }
+        }
+
+        let file_page_sz = self.inner.file_page_sz as u64;
+        inplace.sort_by_key(|(block_no, _)| *block_no);
+
+        let mut i = 0;
+        while i < inplace.len() {
+            let mut j = i + 1;
+            while j < inplace.len() && inplace[j].0 == inplace[j-1].0 + 1 {
+                j += 1;
+            }
+
+            let blocks: Vec<(u32, &[u8])> = inplace[i..j].iter().map(|(_, b)| *b).collect();
+            let file = self.inner.active_file(aver);
+            file.write_blocks_at(inplace[i].0 as u64 * file_page_sz, &blocks)?;
+            i = j;
+        }
+
+        if !append.is_empty() {
+            let file = self.inner.active_file(aver);
+            let write_off = file.append_blocks(&append)?;
+            let first_block = (write_off/file_page_sz) as u32;
+
+            for (i, (k, _)) in append.iter().enumerate() {
+                self.inner.index.put(*k, first_block + i as u32)?;
+            }
+        }
+
+        for i in 0..bufs.len() {
+            self.inner.touch(key + i as u32);
+        }
+
+        self.inner.index.set_size(Some(size));
+        self.inner.is_dirty = true;
+        self.inner.is_modified = true;
+
         Ok(())
     }
 
+    /// Publishes a batch of full page writes at once. The pages go to new
+    /// blocks and become visible with a single atomic write of the index,
+    /// a crash leaves the bucket as it was before the batch.
+    pub fn put_batch(&mut self, pages: &[(u32, &[u8])]) -> Result<(), Error> {
+        if !self.is_write {
+            return Err(Error::BucketNotWritableErr);
+        }
+
+        if self.inner.active_ver < self.state.active_ver() {
+            return Err(Error::VerNotWritable(self.inner.active_ver, self.state.active_ver()));
+        }
+
+        self.learn_page_size();
+        if self.inner.file_page_sz == NixFile::header_len() {
+            return Err(Error::PageSizeUnknownErr);
+        }
+
+        let page_sz = self.inner.page_sz as usize;
+        if let Some((_, buf)) = pages.iter().find(|(_, b)| b.len() != page_sz) {
+            return Err(Error::UnknownStr(format!("put_batch buf len={} is not page size={}", buf.len(), page_sz)));
+        }
+
+        let commit_lock = self.state.commit_lock.clone();
+        let _commit_guard = commit_lock.read();
+
+        let aver = self.state.active_ver();
+        log::debug!("store put_batch aver={} pages={}", aver, pages.len());
+
+        let end = pages.iter().map(|(key, _)| (*key as u64 + 1) * page_sz as u64).max().unwrap_or(0);
+        let size = self.logical_size().max(end);
+
+        // Blocks of the active version replaced by the batch
+        let mut released = Vec::new();
+        for (key, _) in pages.iter() {
+            if let Some(val) = self.get_value_opt(*key)? {
+                if val.get_ver() == aver {
+                    released.push(val.get_off());
+                }
+            }
+        }
+
+        let file_page_sz = self.inner.file_page_sz as u64;
+        let file = self.inner.active_file(aver);
+        let write_off = if pages.is_empty() { 0 } else { file.append_blocks(pages)? };
+        file.sync()?;
+
+        let first_block = (write_off/file_page_sz) as u32;
+        for (i, (key, _)) in pages.iter().enumerate() {
+            self.inner.index.put(*key, first_block + i as u32)?;
+            self.inner.touch(*key);
+        }
+        self.inner.index.set_size(Some(size));
+
+        // Earlier transactions in the log still refer to the old blocks
+        if self.inner.txn_log.is_none() {
+            self.inner.released.extend(released);
+        }
+
+        self.bmap.add(&self.inner.name, aver);
+        self.inner.sync_index(aver, true)?;
+        self.inner.is_modified = true;
+
+        Ok(())
+    }
+
+    /// Reads full pages for the consecutive keys starting at `key`. Runs of
+    /// pages contiguous in the active file are read with a single vectored
+    /// read. Missing pages are zero filled and do not count as read.
+    pub fn get_many(&self, key: u32, bufs: &mut [&mut [u8]]) -> Result<usize, Error> {
+        let mut values = Vec::with_capacity(bufs.len());
+        for i in 0..bufs.len() {
+            values.push(self.get_value_opt(key + i as u32)?);
+        }
+
+        let file_page_sz = self.inner.file_page_sz as u64;
+        let header_len = NixFile::header_len() as u64;
+        let aver = self.inner.active_ver;
+        let mut nread = 0;
+        let mut i = 0;
+
+        while i < bufs.len() {
+            let val = match values[i] {
+                Some(v) => v,
+                None => {
+                    bufs[i].fill(0);
+                    i += 1;
+                    continue;
+                }
+            };
+
+            let off = val.get_off() as u64 * file_page_sz;
+            if val.get_ver() != aver {
+                nread += self.inner.fmap.read_at(val.get_ver(), off + header_len, bufs[i])?;
+                i += 1;
+                continue;
+            }
+
+            let mut j = i + 1;
+            while j < bufs.len() {
+                match values[j] {
+                    Some(v) if v.get_ver() == aver && v.get_off() == val.get_off() + (j - i) as u32 => j += 1,
+                    _ => break,
+                }
+            }
+
+            log::debug!("get_many name={} key={} run={}", self.inner.name, key + i as u32, j - i);
+            nread += self.inner.fmap.active.read_blocks_at(off, &mut bufs[i..j])?;
+            i = j;
+        }
+
+        Ok(nread)
+    }
+
     pub fn get(&self, key: u32, page_off: u64, out_buf: &mut [u8]) -> Result<usize, Error> {
         //let inner = self.inner.read();
 
@@ -280,27 +678,45 @@ impl Bucket {
         let read_ver = value.get_ver();
 
         log::debug!("get name={} key={} value: {:?}", self.inner.name, key, value);
-        let file = self.inner.fmap.file(read_ver);
-        let n = file.read_buf_at(read_off, out_buf)?;
+        let n = self.inner.fmap.read_at(read_ver, read_off, out_buf)?;
         log::debug!("get name={} key={} n={}", self.inner.name, key, n);
 
         Ok(n)
     }
 
+    /// Zero-copy access to a page stored in a committed version. Returns
+    /// None when the page is in the active version or cannot be mapped.
+    pub fn fetch(&self, key: u32, page_off: u64, len: usize) -> Result<Option<PageRef>, Error> {
+        let value = match self.get_value_opt(key)? {
+            Some(v) => v,
+            None => return Ok(None),
+        };
+
+        if value.get_ver() == self.inner.active_ver {
+            return Ok(None);
+        }
+
+        let mut off = (value.get_off() as u64) * (self.inner.file_page_sz as u64);
+        off += NixFile::header_len() as u64 + page_off;
+
+        let file = self.inner.fmap.version_file(value.get_ver())?;
+        Ok(file.page_ref(off, len))
+    }
+
     fn get_value_opt(&self, key: u32) -> Result<Option<Value>, Error> {
         //let inner = self.inner.read();
 
         match self.inner.index.get(key)? {
             None => {
                 log::debug!("get_value_opt no slot key={}", key);
-                return Ok(None)
+                Ok(None)
             }
             Some(val) => {
                 if !val.is_allocated() {
                     log::debug!("get_value_opt allocated key={}", key);
-                    return Ok(None)
+                    Ok(None)
                 }else{
-                    return Ok(Some(val.clone()))
+                    Ok(Some(*val))
                 }
             }
         }
@@ -321,7 +737,7 @@ impl Bucket {
 
         self.bmap.add(&self.inner.name, self.state.active_ver());
         self.inner.active_file(self.state.active_ver()).sync()?;
-        self.inner.sync_index(self.state.active_ver())?;
+        self.inner.sync_index(self.state.active_ver(), false)?;
         self.inner.is_dirty = false;
 
         log::debug!("syncing done");
@@ -329,22 +745,202 @@ impl Bucket {
     }
 
     pub fn sync(&mut self) -> Result<(), Error> {
-        let _ = self.state.commit_lock.read();
+        let commit_lock = self.state.commit_lock.clone();
+        let _commit_guard = commit_lock.read();
 
         self.sync_no_commit_lock()
     }
 
-    pub fn delete_ver(root_path: &Path, name: &str, ver: u32) -> Result<(), Error> {
+    /// Records every write transaction of the active version in the txn
+    /// log. Pages written by an earlier transaction are no longer
+    /// overwritten so that each transaction can be opened on its own.
+    pub fn enable_txn_log(&mut self) -> Result<(), Error> {
+        if !self.is_write {
+            return Err(Error::BucketNotWritableErr);
+        }
+
+        if self.inner.txn_log.is_some() {
+            return Ok(());
+        }
+
+        let aver = self.inner.active_ver;
+        let (log, created) = TxnLog::open(&self.inner.root_path, &self.inner.name, aver)?;
+        self.inner.txn_log = Some(log);
+
+        // Pages written before the log existed make up transaction 0
+        if created {
+            self.append_txn(true)?;
+        }
+
+        Ok(())
+    }
+
+    /// Sequence number of the last logged transaction
+    pub fn txn_seq(&self) -> Option<u32> {
+        self.inner.txn_log.as_ref().and_then(|l| l.seq())
+    }
+
+    /// Logs the pages written since the last call as one transaction. The
+    /// pages are synced first. Returns the sequence number of the
+    /// transaction, None if nothing was written.
+    pub fn end_txn(&mut self) -> Result<Option<u32>, Error> {
+        match self.inner.txn_log.as_ref() {
+            Some(l) if !l.is_empty() => {},
+            _ => return Ok(None),
+        }
+
+        if self.inner.active_ver < self.state.active_ver() {
+            return Err(Error::VerNotWritable(self.inner.active_ver, self.state.active_ver()));
+        }
+
+        let commit_lock = self.state.commit_lock.clone();
+        let _commit_guard = commit_lock.read();
+
+        self.inner.active_file(self.inner.active_ver).sync()?;
+        self.append_txn(false)
+    }
+
+    fn append_txn(&mut self, all: bool) -> Result<Option<u32>, Error> {
+        let inner = &mut self.inner;
+        let aver = inner.active_ver;
+        let max_key = inner.index.max_key();
+        let size = inner.index.size();
+
+        let keys: Vec<u32> = match (all, inner.txn_log.as_ref()) {
+            (true, _) => (0..(max_key + 1) as u32).collect(),
+            (false, Some(l)) => l.touched().collect(),
+            (false, None) => return Ok(None),
+        };
+
+        let mut pages = Vec::new();
+        for key in keys {
+            if key as isize > max_key {
+                continue;
+            }
+
+            if let Some(val) = inner.index.get(key)? {
+                if val.is_allocated() && val.get_ver() == aver {
+                    pages.push((key, val.get_off()));
+                }
+            }
+        }
+
+        match inner.txn_log.as_mut() {
+            Some(l) => Ok(Some(l.append(max_key, size, pages)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Opens the bucket as it was at the end of transaction `txn` of
+    /// version `ver`. The index of the previous version is replayed with
+    /// the txn log of `ver`.
+    #[allow(clippy::too_many_arguments)]
+    pub fn load_at_txn(root_path: &Path, name: &str, state: State, bmap: BucketMap, fcache: FileCache, ver: u32, txn: u32) -> Result<Self, Error> {
+        log::debug!("loading bucket={} version={} txn={}", name, ver, txn);
+
+        if ver < state.min_ver() || ver > state.active_ver() {
+            return Err(Error::VersionNotFoundErr(ver));
+        }
+
+        let records = TxnLog::load(root_path, name, ver, &fcache)?;
+        let pos = records.iter().position(|r| r.seq == txn).ok_or(Error::TxnNotFoundErr(ver, txn))?;
+
+        let prev = if ver > 1 { BucketMap::load(root_path, ver - 1, &fcache)?.get(name) } else { None };
+        let mut index = match prev {
+            Some(v) => Self::load_index(root_path, name, v, &fcache)?.2,
+            None => MemIndex::new(state.pps() as usize),
+        };
+
+        index.set_active_ver(ver);
+        for rec in &records[..=pos] {
+            if rec.max_key < index.max_key() {
+                index.truncate((rec.max_key + 1) as u32)?;
+            }
+            for (key, off) in rec.pages.iter() {
+                index.put(*key, *off)?;
+            }
+            index.set_size(rec.size);
+        }
+
+        let fmap = FileMap::init(root_path, name, state.active_ver(), state.prealloc_sz(), fcache)?;
+        index.set_active_ver(state.active_ver());
+        let page_sz = Self::page_size_of(name, &state, &bmap);
+
+        let inner = BucketInner {
+            name: name.to_owned(),
+            root_path: root_path.to_owned(),
+            index,
+            page_sz,
+            file_page_sz: page_sz as usize + NixFile::header_len(),
+            fmap,
+            is_dirty: false,
+            is_modified: false,
+            is_closed: false,
+            active_ver: state.active_ver(),
+            txn_log: None,
+            released: Vec::new(),
+        };
+
+        Ok(Bucket::with_inner(state, inner, bmap))
+    }
+
+    /// True if the store was committed after the bucket was opened for write
+    pub fn is_stale(&self) -> bool {
+        self.is_write && self.inner.active_ver < self.state.active_ver()
+    }
+
+    /// Moves a writable bucket to the version created by a commit. New
+    /// writes go to the data file of the new version while the older pages
+    /// are read from the committed versions.
+    pub fn roll_forward(&mut self) -> Result<bool, Error> {
+        let commit_lock = self.state.commit_lock.clone();
+        let _commit_guard = commit_lock.read();
+
+        if !self.is_stale() {
+            return Ok(false);
+        }
+
+        let new_ver = self.state.active_ver();
+        log::debug!("rolling bucket={} forward from ver={} to ver={}", self.inner.name, self.inner.active_ver, new_ver);
+
+        let fcache = self.inner.fmap.fcache.clone();
+        let fmap = FileMap::init(&self.inner.root_path, &self.inner.name, new_ver, self.state.prealloc_sz(), fcache)?;
+        let mut old = std::mem::replace(&mut self.inner.fmap, fmap);
+        old.close()?;
+
+        self.inner.index.set_active_ver(new_ver);
+        self.inner.active_ver = new_ver;
+        self.sync_no_commit_lock()?;
+
+        if self.inner.txn_log.is_some() {
+            let (log, created) = TxnLog::open(&self.inner.root_path, &self.inner.name, new_ver)?;
+            self.inner.txn_log = Some(log);
+            if created {
+                self.append_txn(true)?;
+            }
+        }
+
+        Ok(true)
+    }
+
+    pub fn delete_ver(root_path: &Path, name: &str, ver: u32, fcache: &FileCache) -> Result<(), Error> {
         log::debug!("Deleting bucket name={} ver={}", name, ver);
 
         let index_path = Self::index_path(root_path, name, ver);
         log::debug!("removing index file={:?}", index_path);
         std::fs::remove_file(index_path)?;
 
-        let data_path = FileMap::data_path(&root_path, name, ver);
+        let data_path = FileMap::data_path(root_path, name, ver);
+        fcache.evict(&data_path);
         log::debug!("removing data file={:?}", data_path);
         std::fs::remove_file(data_path)?;
 
+        let log_path = TxnLog::log_path(root_path, name, ver);
+        if log_path.exists() {
+            log::debug!("removing txn log={:?}", log_path);
+            std::fs::remove_file(log_path)?;
+        }
+
         Ok(())
     }
 
@@ -352,33 +948,31 @@ impl Bucket {
 
 
 struct FileMap {
-    fmap: rustc_hash::FxHashMap<u32,NixFile>,
+    root_path: PathBuf,
+    name: String,
+    active_ver: u32,
+    active: NixFile,
+    fcache: FileCache,
 }
 
 impl FileMap {
-    fn init(root_path: &Path, name: &str, vset: &HashSet<u32>, aver: u32) -> Result<Self, Error> {
-        //let active_file = Self::open_active_file(root_path, name, active_ver)?;
-        log::debug!("fmap initing for name={} with vset={:?}", name, vset);
-
-        let mut fmap = FileMap {
-            fmap: rustc_hash::FxHashMap::default(),
-        };
-
-        for ver in vset.iter() {
-            if *ver != aver {
-                fmap.add_file(root_path, name, *ver)?;
-            }
-        }
+    fn init(root_path: &Path, name: &str, aver: u32, prealloc_sz: u64, fcache: FileCache) -> Result<Self, Error> {
+        log::debug!("fmap initing for name={} at aver={}", name, aver);
 
-        fmap.add_file(root_path, name, aver)?;
+        let mut active = NixFile::open(&Self::data_path(root_path, name, aver), aver)?;
+        active.set_prealloc_chunk(prealloc_sz);
 
-        Ok(fmap)
+        Ok(FileMap {
+            root_path: root_path.to_owned(),
+            name: name.to_owned(),
+            active_ver: aver,
+            active,
+            fcache,
+        })
     }
 
     fn close(&mut self) -> Result<(), Error> {
-        for (_v, f) in &mut self.fmap {
-            f.close()?;
-        }
+        self.active.close()?;
         Ok(())
     }
 
@@ -387,32 +981,31 @@ impl FileMap {
             if *v == aver {
                 continue
             }
-            if let Some(mut f) = self.fmap.remove(v) {
-                f.close()?;
-            }
+            self.fcache.evict(&Self::data_path(&self.root_path, &self.name, *v));
         }
         Ok(())
     }
 
     fn data_path(root_path: &Path, name: &str, ver: u32) -> PathBuf {
-        root_path.join(format!("{}_d.{}", name, ver))
+        root_path.join(format!("{}_d.{}", file_stem(name), ver))
     }
 
-    fn add_file(&mut self, root_path: &Path, name: &str, ver: u32) -> Result<(), Error> {
-        let ver_path = Self::data_path(root_path, name, ver);
-        log::debug!("adding new file: {:?}", ver_path);
-
-        let f = NixFile::open(&ver_path, ver)?;
-
-        self.fmap.insert(ver, f);
-        Ok(())
+    fn file_mut(&mut self, ver: u32) -> &mut NixFile {
+        if ver != self.active_ver {
+            panic!("write ver={} not active ver={}", ver, self.active_ver);
+        }
+        &mut self.active
     }
 
-    fn file_mut(&mut self, ver: u32) -> &mut NixFile {
-        self.fmap.get_mut(&ver).expect(&format!("write ver={} not found", ver))
+    fn read_at(&self, ver: u32, off: u64, buf: &mut [u8]) -> Result<usize, Error> {
+        if ver == self.active_ver {
+            return Ok(self.active.read_buf_at(off, buf)?);
+        }
+
+        self.version_file(ver)?.read_buf_at(off, buf)
     }
 
-    fn file(&self, ver: u32) -> &NixFile {
-        &self.fmap.get(&ver).expect(&format!("read ver={} not found", ver))
+    fn version_file(&self, ver: u32) -> Result<FileRef, Error> {
+        self.fcache.get(&Self::data_path(&self.root_path, &self.name, ver))
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,243 @@
+use std::collections::HashMap;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, OnceLock, Weak};
+use parking_lot::Mutex;
+use mojoio::lock::{self, LockKind};
+use crate::Error;
+
+// Byte ranges inside the lock file. The data itself lives in the version
+// files, so unlike sqlite's unix vfs the ranges start at zero.
+const PENDING_BYTE: u64 = 0;
+const RESERVED_BYTE: u64 = 1;
+const SHARED_FIRST: u64 = 2;
+const SHARED_SIZE: u64 = 510;
+
+/// Lock levels as defined by sqlite (SQLITE_LOCK_*)
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LockLevel {
+    None = 0,
+    Shared = 1,
+    Reserved = 2,
+    Pending = 3,
+    Exclusive = 4,
+}
+
+impl LockLevel {
+    pub fn from_i32(level: i32) -> Option<Self> {
+        let l = match level {
+            0 => LockLevel::None,
+            1 => LockLevel::Shared,
+            2 => LockLevel::Reserved,
+            3 => LockLevel::Pending,
+            4 => LockLevel::Exclusive,
+            _ => return None,
+        };
+        Some(l)
+    }
+}
+
+// Posix locks belong to the process and are all released when any fd of
+// the file is closed. So there is one lock file per path per process and
+// the connections of the process are tracked here.
+struct LockInode {
+    file: std::fs::File,
+    // Strongest lock held by the process
+    level: LockLevel,
+    n_shared: usize,
+}
+
+type InodeTable = Mutex<HashMap<PathBuf, Weak<Mutex<LockInode>>>>;
+
+fn inode_table() -> &'static InodeTable {
+    static TABLE: OnceLock<InodeTable> = OnceLock::new();
+    TABLE.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+impl LockInode {
+    fn open(path: &Path) -> Result<Arc<Mutex<LockInode>>, Error> {
+        let mut table = inode_table().lock();
+
+        if let Some(inode) = table.get(path).and_then(|w| w.upgrade()) {
+            return Ok(inode);
+        }
+
+        log::debug!("opening db lock file={:?}", path);
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+
+        let inode = Arc::new(Mutex::new(LockInode {
+            file,
+            level: LockLevel::None,
+            n_shared: 0,
+        }));
+
+        table.retain(|_, w| w.strong_count() > 0);
+        table.insert(path.to_owned(), Arc::downgrade(&inode));
+        Ok(inode)
+    }
+
+    fn try_lock(&self, kind: LockKind, start: u64, len: u64) -> Result<bool, Error> {
+        Ok(lock::try_lock_range(self.file.as_raw_fd(), kind, start, len)?)
+    }
+}
+
+/// Database lock of a single connection. Follows the locking protocol of
+/// sqlite's unix vfs using byte range locks on a lock file in the store
+/// directory, `mojo.dblock` for the db of a store opened without a db
+/// name. Lock calls return false when another connection holds a
+/// conflicting lock.
+pub struct DbLock {
+    inode: Arc<Mutex<LockInode>>,
+    level: LockLevel,
+    path: PathBuf,
+}
+
+impl DbLock {
+    pub fn open(root_path: &Path) -> Result<Self, Error> {
+        Self::open_file(root_path, "mojo.dblock")
+    }
+
+    /// Opens the lock of the db whose lock file is `file_name`
+    pub fn open_file(root_path: &Path, file_name: &str) -> Result<Self, Error> {
+        // The same store can be reached through different paths
+        let path = root_path.canonicalize()?.join(file_name);
+        let inode = LockInode::open(&path)?;
+        Ok(DbLock {
+            inode,
+            level: LockLevel::None,
+            path,
+        })
+    }
+
+    /// Path of the lock file
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn level(&self) -> LockLevel {
+        self.level
+    }
+
+    pub fn lock(&mut self, level: LockLevel) -> Result<bool, Error> {
+        if self.level >= level {
+            return Ok(true);
+        }
+
+        log::debug!("db lock curr={:?} req={:?}", self.level, level);
+
+        let mut inode = self.inode.lock();
+
+        // Another connection of this process holds a lock which conflicts
+        if inode.level != self.level && (inode.level >= LockLevel::Pending || level > LockLevel::Shared) {
+            return Ok(false);
+        }
+
+        // Shared lock when the process already holds shared or reserved
+        if level == LockLevel::Shared && (inode.level == LockLevel::Shared || inode.level == LockLevel::Reserved) {
+            inode.n_shared += 1;
+            self.level = LockLevel::Shared;
+            return Ok(true);
+        }
+
+        // The pending byte keeps new readers out while a writer waits for
+        // the exclusive lock
+        if level == LockLevel::Shared || (level == LockLevel::Exclusive && self.level < LockLevel::Pending) {
+            let kind = if level == LockLevel::Shared { LockKind::Read } else { LockKind::Write };
+            if !inode.try_lock(kind, PENDING_BYTE, 1)? {
+                return Ok(false);
+            }
+
+            if level == LockLevel::Exclusive {
+                self.level = LockLevel::Pending;
+                inode.level = LockLevel::Pending;
+            }
+        }
+
+        if level == LockLevel::Shared {
+            let ok = inode.try_lock(LockKind::Read, SHARED_FIRST, SHARED_SIZE)?;
+            inode.try_lock(LockKind::Unlock, PENDING_BYTE, 1)?;
+
+            if !ok {
+                return Ok(false);
+            }
+
+            inode.n_shared = 1;
+            inode.level = LockLevel::Shared;
+            self.level = LockLevel::Shared;
+            return Ok(true);
+        }
+
+        if level == LockLevel::Exclusive && inode.n_shared > 1 {
+            // Other connections of this process are still reading
+            return Ok(false);
+        }
+
+        let ok = if level == LockLevel::Reserved {
+            inode.try_lock(LockKind::Write, RESERVED_BYTE, 1)?
+        }else{
+            inode.try_lock(LockKind::Write, SHARED_FIRST, SHARED_SIZE)?
+        };
+
+        if ok {
+            inode.level = level;
+            self.level = level;
+        }
+
+        Ok(ok)
+    }
+
+    /// Lowers the lock to `level` which is either shared or none
+    pub fn unlock(&mut self, level: LockLevel) -> Result<(), Error> {
+        if self.level <= level {
+            return Ok(());
+        }
+
+        log::debug!("db unlock curr={:?} req={:?}", self.level, level);
+
+        let mut inode = self.inode.lock();
+
+        if self.level > LockLevel::Shared {
+            if level == LockLevel::Shared && !inode.try_lock(LockKind::Read, SHARED_FIRST, SHARED_SIZE)? {
+                return Err(Error::UnknownStr("failed to downgrade db lock to shared".to_owned()));
+            }
+
+            inode.try_lock(LockKind::Unlock, PENDING_BYTE, 2)?;
+            inode.level = LockLevel::Shared;
+        }
+
+        if level == LockLevel::None {
+            inode.n_shared -= 1;
+            if inode.n_shared == 0 {
+                inode.try_lock(LockKind::Unlock, 0, 0)?;
+                inode.level = LockLevel::None;
+            }
+        }
+
+        self.level = level;
+        Ok(())
+    }
+
+    /// Returns true if any connection holds a reserved or stronger lock
+    pub fn check_reserved(&self) -> Result<bool, Error> {
+        let inode = self.inode.lock();
+
+        if inode.level > LockLevel::Shared {
+            return Ok(true);
+        }
+
+        Ok(lock::is_range_locked(inode.file.as_raw_fd(), LockKind::Write, RESERVED_BYTE, 1)?)
+    }
+}
+
+impl Drop for DbLock {
+    fn drop(&mut self) {
+        if let Err(err) = self.unlock(LockLevel::None) {
+            log::error!("failed to release db lock err={:?}", err);
+        }
+    }
+}
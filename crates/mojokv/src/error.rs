@@ -10,6 +10,9 @@ pub enum Error {
     #[error("Bucket {0} not found at ver={1}")]
     BucketNotAtVerErr(String, u32),
 
+    #[error("Bucket name `{0}` is not a relative path")]
+    BucketNameErr(String),
+
     #[error("Bucket not writable")]
     BucketNotWritableErr,
 
@@ -28,6 +31,27 @@ pub enum Error {
     #[error("Commit lock could not be acquired")]
     CommitLockedErr,
 
+    #[error("Page size mismatch store={0} requested={1}")]
+    PageSizeMismatchErr(u32, u32),
+
+    #[error("Page size of the store is not known")]
+    PageSizeUnknownErr,
+
+    #[error("Shared memory file is locked")]
+    ShmBusyErr,
+
+    #[error("Database is locked by a writer")]
+    DbBusyErr,
+
+    #[error("Cannot commit inside a write transaction")]
+    TxnOpenErr,
+
+    #[error("Hot journal {0} has to be rolled back before commit")]
+    HotJournalErr(String),
+
+    #[error("Wal {0} has frames, checkpoint it with TRUNCATE before commit")]
+    WalNotEmptyErr(String),
+
     #[error("Only single version exists")]
     SingleVersionErr,
 
@@ -49,9 +73,15 @@ pub enum Error {
     #[error("Version {0} not found")]
     VersionNotFoundErr(u32),
 
+    #[error("Transaction {1} not found in the txn log of ver={0}")]
+    TxnNotFoundErr(u32, u32),
+
     #[error("Parse int error")]
     ParseIntErr(#[from] std::num::ParseIntError),
 
+    #[error("Corrupt file `{0}`")]
+    CorruptErr(String),
+
     #[error("Unknown error `{0}`")]
     UnknownStr(String),
 
@@ -61,3 +91,14 @@ pub enum Error {
     #[error("Unknown error")]
     Unknown,
 }
+
+impl Error {
+    /// True if the file does not exist
+    pub fn is_not_found(&self) -> bool {
+        match self {
+            Error::IoErr(err) => err.kind() == std::io::ErrorKind::NotFound,
+            Error::MojoFileErr(err) => err.is_not_found(),
+            _ => false,
+        }
+    }
+}
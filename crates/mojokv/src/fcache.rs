@@ -0,0 +1,290 @@
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use parking_lot::Mutex;
+use mojoio::nix::NixFile;
+use mojoio::mmap::Mmap;
+use crate::{Error, utils};
+use crate::pack::{PackSet, PackEntry};
+
+pub const DEFAULT_FD_LIMIT: usize = 256;
+
+struct CacheEntry {
+    file: Arc<CachedFile>,
+    last_used: u64,
+}
+
+// Version files are immutable, so they are mapped read-only when opened.
+// If the map cannot be created the reads fall back to pread.
+struct CachedFile {
+    file: NixFile,
+    map: Option<Mmap>,
+}
+
+impl CachedFile {
+    fn open(path: &Path) -> Result<Self, Error> {
+        let file = NixFile::open_readonly(path)?;
+        let len = file.file_size()? as usize;
+
+        let map = if len > 0 {
+            match Mmap::map_readonly(file.fd(), len) {
+                Ok(m) => Some(m),
+                Err(err) => {
+                    log::warn!("mmap of file={:?} failed err={:?}", path, err);
+                    None
+                }
+            }
+        }else{
+            None
+        };
+
+        Ok(CachedFile { file, map })
+    }
+}
+
+struct FileCacheInner {
+    limit: usize,
+    tick: u64,
+    files: rustc_hash::FxHashMap<PathBuf, CacheEntry>,
+    packs: Option<PackSet>,
+}
+
+// Read-only view of a version file. For packed versions `base` is the
+// offset of the version file inside the pack and `len` its length, so
+// reads never run into the next file of the pack.
+#[derive(Clone)]
+pub struct FileRef {
+    file: Arc<CachedFile>,
+    base: u64,
+    len: Option<u64>,
+}
+
+impl FileRef {
+    pub fn read_buf_at(&self, off: u64, buf: &mut [u8]) -> Result<usize, Error> {
+        match self.slice(off, buf.len()) {
+            Some(src) => {
+                buf[..src.len()].copy_from_slice(src);
+                Ok(src.len())
+            },
+            None => {
+                let n = self.clamp(off, buf.len());
+                Ok(self.file.file.read_buf_at(self.base + off, &mut buf[..n])?)
+            },
+        }
+    }
+
+    /// Returns the mapped bytes at `off`. The slice is shorter than `len`
+    /// if the file ends before. None if the file is not mapped.
+    pub fn slice(&self, off: u64, len: usize) -> Option<&[u8]> {
+        let map = self.file.map.as_ref()?.as_slice();
+        let len = self.clamp(off, len);
+        let start = ((self.base + off) as usize).min(map.len());
+        let end = (start + len).min(map.len());
+        Some(&map[start..end])
+    }
+
+    // Limits a read of `len` bytes at `off` to the end of a packed file
+    fn clamp(&self, off: u64, len: usize) -> usize {
+        match self.len {
+            Some(flen) => (flen.saturating_sub(off) as usize).min(len),
+            None => len,
+        }
+    }
+
+    pub fn is_mapped(&self) -> bool {
+        self.file.map.is_some()
+    }
+
+    pub fn page_ref(&self, off: u64, len: usize) -> Option<PageRef> {
+        if self.slice(off, len)?.len() < len {
+            return None;
+        }
+
+        Some(PageRef {
+            file: self.clone(),
+            off,
+            len,
+        })
+    }
+}
+
+// Store wide cache of read-only version files. Files are opened lazily on
+// first read and closed in LRU order once the limit is reached. The active
+// version file is owned by the bucket and never goes through the cache.
+#[derive(Clone)]
+pub struct FileCache {
+    inner: Arc<Mutex<FileCacheInner>>,
+}
+
+/// Zero-copy reference to the bytes of a mapped page. The mapping stays
+/// alive as long as the reference is held, even if the file is evicted.
+pub struct PageRef {
+    file: FileRef,
+    off: u64,
+    len: usize,
+}
+
+impl PageRef {
+    pub fn as_slice(&self) -> &[u8] {
+        self.file.slice(self.off, self.len).unwrap_or_default()
+    }
+
+    pub fn as_ptr(&self) -> *const u8 {
+        self.as_slice().as_ptr()
+    }
+}
+
+impl Default for FileCache {
+    fn default() -> Self {
+        FileCache::new(DEFAULT_FD_LIMIT)
+    }
+}
+
+impl FileCache {
+    pub fn new(limit: usize) -> Self {
+        let inner = FileCacheInner {
+            limit: limit.max(1),
+            tick: 0,
+            files: rustc_hash::FxHashMap::default(),
+            packs: None,
+        };
+
+        FileCache {
+            inner: Arc::new(Mutex::new(inner)),
+        }
+    }
+
+    pub fn limit(&self) -> usize {
+        let inner = self.inner.lock();
+        inner.limit
+    }
+
+    pub fn set_limit(&self, limit: usize) {
+        let mut inner = self.inner.lock();
+        let limit = limit.max(1);
+        inner.limit = limit;
+        inner.shrink_to(limit);
+    }
+
+    pub fn len(&self) -> usize {
+        let inner = self.inner.lock();
+        inner.files.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn get(&self, path: &Path) -> Result<FileRef, Error> {
+        let mut inner = self.inner.lock();
+
+        // The file can be packed at any time, so it is opened first and
+        // looked up in the packs only if it is gone
+        match inner.get_or_open(path) {
+            Ok(file) => return Ok(FileRef { file, base: 0, len: None }),
+            Err(err) if err.is_not_found() => {},
+            Err(err) => return Err(err),
+        }
+
+        let (pack_path, e) = inner.locate(path)?.ok_or_else(|| not_found(path))?;
+        let file = inner.get_or_open(&pack_path)?;
+        Ok(FileRef { file, base: e.off, len: Some(e.len) })
+    }
+
+    /// Reads the whole file either from the file system or, if it has been
+    /// packed, from the pack holding it.
+    pub fn load_file(&self, path: &Path, buf: &mut Vec<u8>) -> Result<(), Error> {
+        match utils::load_file(path, buf) {
+            Err(err) if err.is_not_found() => {},
+            ret => return ret,
+        }
+
+        let (pack_path, e) = self.inner.lock().locate(path)?.ok_or_else(|| not_found(path))?;
+
+        let mut f = std::fs::File::open(pack_path)?;
+        f.seek(SeekFrom::Start(e.off))?;
+        buf.resize(e.len as usize, 0);
+        f.read_exact(buf)?;
+
+        Ok(())
+    }
+
+    pub fn file_exists(&self, path: &Path) -> Result<bool, Error> {
+        if path.exists() {
+            return Ok(true);
+        }
+
+        Ok(self.inner.lock().locate(path)?.is_some())
+    }
+
+    pub fn evict(&self, path: &Path) {
+        let mut inner = self.inner.lock();
+        if inner.files.remove(path).is_some() {
+            log::debug!("fcache evicted file={:?}", path);
+        }
+    }
+
+    pub fn clear(&self) {
+        let mut inner = self.inner.lock();
+        inner.files.clear();
+        inner.packs = None;
+    }
+}
+
+impl FileCacheInner {
+    fn get_or_open(&mut self, path: &Path) -> Result<Arc<CachedFile>, Error> {
+        self.tick += 1;
+        let tick = self.tick;
+
+        if let Some(entry) = self.files.get_mut(path) {
+            entry.last_used = tick;
+            return Ok(entry.file.clone());
+        }
+
+        log::debug!("fcache opening file={:?} cached={}", path, self.files.len());
+        let file = Arc::new(CachedFile::open(path)?);
+
+        let limit = self.limit;
+        self.shrink_to(limit - 1);
+        self.files.insert(path.to_owned(), CacheEntry {
+            file: file.clone(),
+            last_used: tick,
+        });
+
+        Ok(file)
+    }
+
+    // The file may have been packed, by this or another process, after the
+    // pack set was loaded. A miss only reads the tocs of new packs.
+    fn locate(&mut self, path: &Path) -> Result<Option<(PathBuf, PackEntry)>, Error> {
+        let root_path = path.parent().unwrap_or_else(|| Path::new("."));
+        let file_name = path.file_name().and_then(|s| s.to_str()).unwrap_or_default();
+
+        let packs = self.packs.get_or_insert_with(PackSet::default);
+        if packs.locate(file_name).is_none() {
+            packs.refresh(root_path)?;
+        }
+
+        Ok(packs.locate(file_name).map(|(p, e)| (p.to_owned(), e)))
+    }
+
+    fn shrink_to(&mut self, n: usize) {
+        while self.files.len() > n {
+            let lru = self.files.iter()
+                .min_by_key(|(_, e)| e.last_used)
+                .map(|(p, _)| p.clone());
+
+            match lru {
+                Some(path) => {
+                    log::debug!("fcache closing lru file={:?}", path);
+                    self.files.remove(&path);
+                },
+                None => break,
+            }
+        }
+    }
+}
+
+fn not_found(path: &Path) -> Error {
+    std::io::Error::new(std::io::ErrorKind::NotFound, format!("{:?} not found", path)).into()
+}
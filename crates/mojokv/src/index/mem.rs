@@ -6,7 +6,7 @@ use serde::Serialize;
 use crate::value::Value;
 use crate::keymap::KeyMap;
 use crate::Error;
-use crate::utils;
+use crate::fcache::FileCache;
 use super::IndexHeader;
 
 
@@ -46,12 +46,21 @@ impl MemIndex {
         self.header.max_key
     }
 
+    /// Exact byte length of the bucket, None if the index predates it
+    pub fn size(&self) -> Option<u64> {
+        self.header.size
+    }
+
+    pub fn set_size(&mut self, size: Option<u64>) {
+        self.header.size = size;
+    }
+
     pub fn update_min_max_ver(&mut self) -> Vec<u32> {
         let (min_ver, max_ver, vset) = self.kmap.get_min_max_ver();
         self.header.min_ver = min_ver;
         self.header.max_ver = max_ver;
 
-        let non_ref_vers: Vec<u32> = self.header.vset.difference(&vset).map(|n| *n).collect();
+        let non_ref_vers: Vec<u32> = self.header.vset.difference(&vset).copied().collect();
         non_ref_vers
     }
 
@@ -72,7 +81,7 @@ impl MemIndex {
 
     pub fn truncate(&mut self, key: u32) -> Result<(), Error> {
         self.kmap.truncate(key);
-        self.header.max_key = key as isize -1;
+        self.header.max_key = self.header.max_key.min(key as isize - 1);
         Ok(())
     }
 
@@ -104,13 +113,17 @@ impl MemIndex {
         Ok(())    
     }
 
-    pub fn deserialize_from_path(filepath: &std::path::Path) -> Result<(usize, usize, MemIndex), Error> {
+    pub fn deserialize_from_path(filepath: &std::path::Path, fcache: &FileCache) -> Result<(usize, usize, MemIndex), Error> {
         let mut b = Vec::new();
-        utils::load_file(filepath, &mut b)?;
+        fcache.load_file(filepath, &mut b)?;
 
+        if b.len() < 8 {
+            return Err(Error::CorruptErr(format!("index {:?} is too short", filepath)));
+        }
         let cap = usize::from_le_bytes([b[0], b[1], b[2], b[3], b[4], b[5], b[6], b[7]]);
 
-        let buf = zstd::bulk::decompress(&b[8..], cap)?;
+        let buf = zstd::bulk::decompress(&b[8..], cap)
+            .map_err(|err| Error::CorruptErr(format!("index {:?}: {}", filepath, err)))?;
 
         let index = rmp_serde::from_slice(&buf)?;
         Ok((cap, b.len(), index))
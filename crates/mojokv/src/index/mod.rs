@@ -5,7 +5,7 @@ use crate::Error;
 use crate::value::Value;
 use serde::{Serialize, Deserialize};
 
-pub const MOJO_INDEX_MAGIC: &'static str = "mojo_index";
+pub const MOJO_INDEX_MAGIC: &str = "mojo_index";
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct IndexHeader {
@@ -17,6 +17,9 @@ pub struct IndexHeader {
     pub active_ver: u32,
     pub max_key: isize,
     pub pps: usize,
+    // Exact byte length, missing in indexes written before it was tracked
+    #[serde(default)]
+    pub size: Option<u64>,
 }
 
 impl IndexHeader {
@@ -33,6 +36,7 @@ impl IndexHeader {
             active_ver: 1,
             pps,
             max_key: -1,
+            size: Some(0),
         }
     }
 }
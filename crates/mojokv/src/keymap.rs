@@ -27,17 +27,15 @@ impl KeyMap {
         let mut set = HashSet::new();
         let (mut min_ver, mut max_ver) = (u32::MAX,0);
 
-        for slot in self.slot_map.iter() {
-            if let Some(slot) =  slot {
-                for val in slot.iter() {
-                    let v = val.get_ver();
-                    if v == 0 {
-                        break;
-                    }
-                    set.insert(v);
-                    min_ver = min_ver.min(v);
-                    max_ver = max_ver.max(v);
+        for slot in self.slot_map.iter().flatten() {
+            for val in slot.iter() {
+                let v = val.get_ver();
+                if v == 0 {
+                    break;
                 }
+                set.insert(v);
+                min_ver = min_ver.min(v);
+                max_ver = max_ver.max(v);
             }
         }
 
@@ -77,12 +75,16 @@ impl KeyMap {
 
     pub fn truncate(&mut self, key: u32) {
         let slot = key/self.pps as u32;
+        if slot as usize >= self.slot_map.len() {
+            return;
+        }
+
         self.slot_map.truncate((slot+1) as usize);
         let slot_key = key % (self.pps as u32);
 
         if let Some(slot_vec) = self.slot_map[slot as usize].as_mut() {
-            for i in slot_key as usize ..slot_vec.len() {
-                slot_vec[i].deallocate();
+            for val in slot_vec.iter_mut().skip(slot_key as usize) {
+                val.deallocate();
             }
         }
     }
@@ -9,13 +9,23 @@ mod keymap;
 mod utils;
 mod store;
 mod bmap;
+mod fcache;
+mod pack;
+mod dblock;
+mod shm;
+mod txnlog;
 
 pub use error::Error;
 pub use bucket::Bucket;
-pub use bmap::BucketMap;
+pub use bmap::{BucketMap, check_name as check_bucket_name};
 pub use keymap::KeyMap;
 pub use value::{Value, Slot};
-pub use store::{Store, BucketOpenMode};
+pub use store::{Store, BucketOpenMode, DEFAULT_COMMIT_WAIT};
+pub use fcache::{FileCache, FileRef, PageRef, DEFAULT_FD_LIMIT};
+pub use pack::{PackSet, PackToc, PackStats};
+pub use dblock::{DbLock, LockLevel};
+pub use shm::{Shm, SHM_NLOCK};
+pub use txnlog::TxnRecord;
 
 
 //TODO: Pass pps from single place
\ No newline at end of file
@@ -0,0 +1,212 @@
+use std::collections::HashMap;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use serde::{Serialize, Deserialize};
+use crate::{Error, utils};
+
+pub const PACK_MAGIC: &[u8; 8] = b"mojopack";
+const PACK_TRAILER_LEN: u64 = 24;
+const PACK_ALIGN: u64 = 4096;
+const PACK_PREFIX: &str = "mojo.pack.";
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct PackEntry {
+    pub off: u64,
+    pub len: u64,
+}
+
+// Table of contents stored at the end of the pack file. Maps the name of
+// the original file (e.g. `a.db_d.3`) to its location inside the pack.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct PackToc {
+    pub format_ver: u32,
+    pub min_ver: u32,
+    pub max_ver: u32,
+    pub entries: HashMap<String, PackEntry>,
+}
+
+#[derive(Debug, Default)]
+pub struct PackStats {
+    pub pack_path: Option<PathBuf>,
+    pub files: usize,
+    pub bytes: u64,
+}
+
+#[derive(Default)]
+pub struct PackSet {
+    packs: Vec<(PathBuf, PackToc)>,
+}
+
+impl PackSet {
+    pub fn load(root_path: &Path) -> Result<Self, Error> {
+        let mut packs = Vec::new();
+
+        for (_, path) in Self::list(root_path)? {
+            log::debug!("loading pack toc from {:?}", path);
+            let toc = Self::read_toc(&path)?;
+            packs.push((path, toc));
+        }
+
+        Ok(PackSet { packs })
+    }
+
+    /// Loads the packs created and drops the ones removed since the set
+    /// was loaded. Only the new tocs are read. Returns true if it changed.
+    pub fn refresh(&mut self, root_path: &Path) -> Result<bool, Error> {
+        let list = Self::list(root_path)?;
+        if list.len() == self.packs.len() && list.iter().zip(&self.packs).all(|((_, a), (b, _))| a == b) {
+            return Ok(false);
+        }
+
+        let mut packs = Vec::new();
+        for (_, path) in list {
+            let toc = match self.packs.iter().position(|(p, _)| *p == path) {
+                Some(i) => std::mem::take(&mut self.packs[i].1),
+                None => {
+                    log::debug!("loading pack toc from {:?}", path);
+                    Self::read_toc(&path)?
+                }
+            };
+            packs.push((path, toc));
+        }
+
+        self.packs = packs;
+        Ok(true)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.packs.is_empty()
+    }
+
+    /// Finds the pack which holds the given file name
+    pub fn locate(&self, file_name: &str) -> Option<(&Path, PackEntry)> {
+        for (path, toc) in self.packs.iter().rev() {
+            if let Some(e) = toc.entries.get(file_name) {
+                return Some((path.as_path(), *e));
+            }
+        }
+        None
+    }
+
+    pub fn packs(&self) -> impl Iterator<Item=(&Path, &PackToc)> {
+        self.packs.iter().map(|(p, t)| (p.as_path(), t))
+    }
+
+    fn list(root_path: &Path) -> Result<Vec<(u32, PathBuf)>, Error> {
+        let mut list = Vec::new();
+        for entry in std::fs::read_dir(root_path)? {
+            let entry = entry?;
+            let file_name = entry.file_name();
+            let file_name = match file_name.to_str() {
+                Some(s) => s,
+                None => continue,
+            };
+
+            if let Some(seq) = file_name.strip_prefix(PACK_PREFIX) {
+                if let Ok(seq) = seq.parse::<u32>() {
+                    list.push((seq, entry.path()));
+                }
+            }
+        }
+
+        list.sort_by_key(|(seq, _)| *seq);
+        Ok(list)
+    }
+
+    fn read_toc(path: &Path) -> Result<PackToc, Error> {
+        let mut f = std::fs::File::open(path)?;
+        let file_len = f.seek(SeekFrom::End(0))?;
+        if file_len < PACK_TRAILER_LEN {
+            return Err(Error::UnknownStr(format!("pack {:?} too small", path)));
+        }
+
+        let mut trailer = [0u8; PACK_TRAILER_LEN as usize];
+        f.seek(SeekFrom::Start(file_len - PACK_TRAILER_LEN))?;
+        f.read_exact(&mut trailer)?;
+
+        if &trailer[..8] != PACK_MAGIC {
+            return Err(Error::UnknownStr(format!("pack {:?} has invalid magic", path)));
+        }
+
+        let toc_off = u64::from_le_bytes(trailer[8..16].try_into().unwrap());
+        let toc_len = u64::from_le_bytes(trailer[16..24].try_into().unwrap());
+
+        let mut buf = vec![0u8; toc_len as usize];
+        f.seek(SeekFrom::Start(toc_off))?;
+        f.read_exact(&mut buf)?;
+
+        let toc = rmp_serde::from_slice(&buf)?;
+        Ok(toc)
+    }
+
+    /// Packs the given files into a new pack file in root_path. The files
+    /// are removed only after the pack is durable.
+    pub fn write_pack(root_path: &Path, files: &[(u32, PathBuf)]) -> Result<PackStats, Error> {
+        let mut stats = PackStats::default();
+        if files.is_empty() {
+            return Ok(stats);
+        }
+
+        let seq = Self::list(root_path)?.last().map(|(seq, _)| seq + 1).unwrap_or(1);
+        let pack_path = root_path.join(format!("{}{}", PACK_PREFIX, seq));
+        let tmp_path = root_path.join("mojo.pack.tmp");
+
+        let mut toc = PackToc {
+            format_ver: 1,
+            min_ver: u32::MAX,
+            max_ver: 0,
+            entries: HashMap::new(),
+        };
+
+        let mut out = std::fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&tmp_path)?;
+
+        let mut off = 0u64;
+        for (ver, path) in files {
+            let file_name = match path.file_name().and_then(|s| s.to_str()) {
+                Some(s) => s.to_owned(),
+                None => continue,
+            };
+
+            let pad = (PACK_ALIGN - off % PACK_ALIGN) % PACK_ALIGN;
+            if pad > 0 {
+                out.write_all(&vec![0u8; pad as usize])?;
+                off += pad;
+            }
+
+            let mut f = std::fs::File::open(path)?;
+            let len = std::io::copy(&mut f, &mut out)?;
+            log::debug!("packed file={} off={} len={}", file_name, off, len);
+
+            toc.entries.insert(file_name, PackEntry { off, len });
+            toc.min_ver = toc.min_ver.min(*ver);
+            toc.max_ver = toc.max_ver.max(*ver);
+            off += len;
+            stats.files += 1;
+            stats.bytes += len;
+        }
+
+        let toc_buf = rmp_serde::to_vec_named(&toc)?;
+        out.write_all(&toc_buf)?;
+
+        out.write_all(PACK_MAGIC)?;
+        out.write_all(&off.to_le_bytes())?;
+        out.write_all(&(toc_buf.len() as u64).to_le_bytes())?;
+        out.sync_all()?;
+        drop(out);
+
+        std::fs::rename(&tmp_path, &pack_path)?;
+        utils::sync_dir(root_path)?;
+
+        for (_, path) in files {
+            log::debug!("removing packed file={:?}", path);
+            std::fs::remove_file(path)?;
+        }
+
+        stats.pack_path = Some(pack_path);
+        Ok(stats)
+    }
+}
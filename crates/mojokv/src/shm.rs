@@ -0,0 +1,294 @@
+use std::collections::HashMap;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, OnceLock, Weak};
+use parking_lot::Mutex;
+use mojoio::lock::{self, LockKind};
+use mojoio::mmap::MmapMut;
+use crate::Error;
+
+/// Number of shm locks used by sqlite (SQLITE_SHM_NLOCK)
+pub const SHM_NLOCK: usize = 8;
+
+// Same layout as sqlite's unix vfs: the lock bytes follow the wal index
+// header and the dead man switch comes right after them.
+const SHM_BASE: u64 = 120;
+const SHM_DMS: u64 = SHM_BASE + SHM_NLOCK as u64;
+
+// Shared memory file of a database, one per path per process. `locks`
+// tracks the shm locks held by the connections of this process: -1 for
+// exclusive, otherwise the number of shared holders.
+struct ShmNode {
+    path: PathBuf,
+    file: std::fs::File,
+    regions: Vec<MmapMut>,
+    region_sz: usize,
+    locks: [i32; SHM_NLOCK],
+}
+
+type ShmTable = Mutex<HashMap<PathBuf, Weak<Mutex<ShmNode>>>>;
+
+fn shm_table() -> &'static ShmTable {
+    static TABLE: OnceLock<ShmTable> = OnceLock::new();
+    TABLE.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+impl ShmNode {
+    fn open(path: &Path) -> Result<Arc<Mutex<ShmNode>>, Error> {
+        let mut table = shm_table().lock();
+
+        if let Some(node) = table.get(path).and_then(|w| w.upgrade()) {
+            return Ok(node);
+        }
+
+        log::debug!("opening shm file={:?}", path);
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+
+        let node = ShmNode {
+            path: path.to_owned(),
+            file,
+            regions: Vec::new(),
+            region_sz: 0,
+            locks: [0; SHM_NLOCK],
+        };
+
+        // The first process to open the file resets it, the contents may be
+        // left over from a crash. The read lock on the dead man switch is
+        // held as long as the file is open.
+        if node.try_lock(LockKind::Write, SHM_DMS, 1)? {
+            log::debug!("resetting shm file={:?}", path);
+            node.file.set_len(0)?;
+        }
+
+        if !node.try_lock(LockKind::Read, SHM_DMS, 1)? {
+            return Err(Error::ShmBusyErr);
+        }
+
+        let node = Arc::new(Mutex::new(node));
+        table.retain(|_, w| w.strong_count() > 0);
+        table.insert(path.to_owned(), Arc::downgrade(&node));
+        Ok(node)
+    }
+
+    fn try_lock(&self, kind: LockKind, start: u64, len: u64) -> Result<bool, Error> {
+        Ok(lock::try_lock_range(self.file.as_raw_fd(), kind, start, len)?)
+    }
+}
+
+impl Drop for ShmNode {
+    fn drop(&mut self) {
+        log::debug!("closing shm file={:?}", self.path);
+        self.regions.clear();
+    }
+}
+
+/// Connection handle to the shared memory used by sqlite's wal mode. The
+/// shm file lives in the store directory and is not versioned.
+pub struct Shm {
+    node: Arc<Mutex<ShmNode>>,
+    shared_mask: u16,
+    excl_mask: u16,
+}
+
+impl Shm {
+    pub fn open(path: &Path) -> Result<Self, Error> {
+        // The same file can be reached through different paths
+        let path = match (path.parent(), path.file_name()) {
+            (Some(dir), Some(name)) if !dir.as_os_str().is_empty() => dir.canonicalize()?.join(name),
+            _ => path.to_owned(),
+        };
+        let node = ShmNode::open(&path)?;
+        Ok(Shm {
+            node,
+            shared_mask: 0,
+            excl_mask: 0,
+        })
+    }
+
+    /// Returns the address of region `region` of `region_sz` bytes. If the
+    /// file is too small it is grown when `extend` is set, otherwise None.
+    pub fn map(&mut self, region: usize, region_sz: usize, extend: bool) -> Result<Option<*mut u8>, Error> {
+        let mut node = self.node.lock();
+
+        if node.region_sz != 0 && node.region_sz != region_sz {
+            return Err(Error::UnknownStr(format!("shm region size changed from {} to {}", node.region_sz, region_sz)));
+        }
+        node.region_sz = region_sz;
+
+        if region < node.regions.len() {
+            return Ok(Some(node.regions[region].as_mut_ptr()));
+        }
+
+        let req_sz = ((region + 1) * region_sz) as u64;
+        let file_sz = node.file.metadata()?.len();
+        if file_sz < req_sz {
+            if !extend {
+                return Ok(None);
+            }
+
+            log::debug!("extending shm file={:?} from {} to {}", node.path, file_sz, req_sz);
+            node.file.set_len(req_sz)?;
+        }
+
+        while node.regions.len() <= region {
+            let off = (node.regions.len() * region_sz) as u64;
+            let map = MmapMut::map_shared(node.file.as_raw_fd(), off, region_sz)?;
+            node.regions.push(map);
+        }
+
+        Ok(Some(node.regions[region].as_mut_ptr()))
+    }
+
+    /// Takes `n` shm locks starting at `ofst`. Returns false if they are
+    /// held by another connection.
+    pub fn lock(&mut self, ofst: usize, n: usize, exclusive: bool) -> Result<bool, Error> {
+        let mask = Self::mask(ofst, n)?;
+        let mut node = self.node.lock();
+
+        if exclusive {
+            if self.excl_mask & mask == mask {
+                return Ok(true);
+            }
+
+            for i in ofst..ofst+n {
+                if self.excl_mask & (1 << i) == 0 && node.locks[i] != 0 {
+                    return Ok(false);
+                }
+            }
+
+            if !node.try_lock(LockKind::Write, SHM_BASE + ofst as u64, n as u64)? {
+                return Ok(false);
+            }
+
+            for i in ofst..ofst+n {
+                node.locks[i] = -1;
+            }
+            self.excl_mask |= mask;
+        }else{
+            if self.shared_mask & mask == mask {
+                return Ok(true);
+            }
+
+            for i in ofst..ofst+n {
+                if node.locks[i] < 0 {
+                    return Ok(false);
+                }
+            }
+
+            for i in ofst..ofst+n {
+                if node.locks[i] == 0 && !node.try_lock(LockKind::Read, SHM_BASE + i as u64, 1)? {
+                    // Undo the locks taken so far
+                    for j in ofst..i {
+                        node.locks[j] -= 1;
+                        if node.locks[j] == 0 {
+                            node.try_lock(LockKind::Unlock, SHM_BASE + j as u64, 1)?;
+                        }
+                    }
+                    return Ok(false);
+                }
+                node.locks[i] += 1;
+            }
+            self.shared_mask |= mask;
+        }
+
+        log::debug!("shm lock ofst={} n={} excl={} shared_mask={:#x} excl_mask={:#x}",
+            ofst, n, exclusive, self.shared_mask, self.excl_mask);
+        Ok(true)
+    }
+
+    pub fn unlock(&mut self, ofst: usize, n: usize, exclusive: bool) -> Result<(), Error> {
+        let mask = Self::mask(ofst, n)?;
+        let mut node = self.node.lock();
+
+        if exclusive {
+            if self.excl_mask & mask == 0 {
+                return Ok(());
+            }
+
+            node.try_lock(LockKind::Unlock, SHM_BASE + ofst as u64, n as u64)?;
+            for i in ofst..ofst+n {
+                node.locks[i] = 0;
+            }
+            self.excl_mask &= !mask;
+        }else{
+            for i in ofst..ofst+n {
+                if self.shared_mask & (1 << i) == 0 {
+                    continue;
+                }
+
+                node.locks[i] -= 1;
+                if node.locks[i] == 0 {
+                    node.try_lock(LockKind::Unlock, SHM_BASE + i as u64, 1)?;
+                }
+            }
+            self.shared_mask &= !mask;
+        }
+
+        log::debug!("shm unlock ofst={} n={} excl={} shared_mask={:#x} excl_mask={:#x}",
+            ofst, n, exclusive, self.shared_mask, self.excl_mask);
+        Ok(())
+    }
+
+    pub fn barrier(&self) {
+        std::sync::atomic::fence(std::sync::atomic::Ordering::SeqCst);
+        let _node = self.node.lock();
+    }
+
+    /// Releases the connection. The file is removed if `delete` is set and
+    /// no other connection in any process has it open.
+    pub fn unmap(mut self, delete: bool) -> Result<(), Error> {
+        self.release()?;
+
+        let node = self.node.clone();
+        drop(self);
+
+        // Hold the table lock so that no other connection opens the node
+        let table = shm_table().lock();
+        if let Ok(node) = Arc::try_unwrap(node) {
+            let node = node.into_inner();
+            if delete && node.try_lock(LockKind::Write, SHM_DMS, 1)? {
+                log::debug!("removing shm file={:?}", node.path);
+                std::fs::remove_file(&node.path)?;
+            }
+        }
+        drop(table);
+
+        Ok(())
+    }
+
+    fn release(&mut self) -> Result<(), Error> {
+        let excl = self.excl_mask;
+        let shared = self.shared_mask;
+
+        for i in 0..SHM_NLOCK {
+            if excl & (1 << i) != 0 {
+                self.unlock(i, 1, true)?;
+            }
+            if shared & (1 << i) != 0 {
+                self.unlock(i, 1, false)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn mask(ofst: usize, n: usize) -> Result<u16, Error> {
+        if n == 0 || ofst + n > SHM_NLOCK {
+            return Err(Error::UnknownStr(format!("invalid shm lock ofst={} n={}", ofst, n)));
+        }
+        Ok((((1u32 << n) - 1) << ofst) as u16)
+    }
+}
+
+impl Drop for Shm {
+    fn drop(&mut self) {
+        if let Err(err) = self.release() {
+            log::error!("failed to release shm locks err={:?}", err);
+        }
+    }
+}
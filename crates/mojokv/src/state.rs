@@ -3,10 +3,11 @@ use crate::Error;
 use mojoio::nix::NixFile;
 use crate::utils;
 use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
 use parking_lot::RwLock;
 use serde::{Serialize, Deserialize};
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct StateInner {
     pub format_ver: u32,
     pub min_ver: u32,
@@ -26,6 +27,9 @@ pub struct State {
 
     #[serde(skip)]
     pub commit_lock: Arc<RwLock<bool>>,
+
+    #[serde(skip)]
+    prealloc_sz: Arc<AtomicU64>,
 }
 
 impl State {
@@ -43,12 +47,11 @@ impl State {
             file_page_sz: page_sz + NixFile::header_len() as u32,
         };
 
-        let state = State {
+        State {
             inner: Arc::new(RwLock::new(inner)),
             commit_lock: Arc::new(RwLock::new(false)),
-        };
-
-        state
+            prealloc_sz: Arc::new(AtomicU64::new(0)),
+        }
     }
 
     pub fn format_ver(&self) -> u32 {
@@ -66,6 +69,19 @@ impl State {
         inner.page_sz
     }
 
+    /// Sets the page size of a store created without one. Zero means the
+    /// page size is not known yet.
+    pub fn set_page_size(&self, page_sz: u32) -> Result<(), Error> {
+        let mut inner = self.inner.write();
+        if inner.page_sz != 0 && inner.page_sz != page_sz {
+            return Err(Error::PageSizeMismatchErr(inner.page_sz, page_sz));
+        }
+
+        inner.page_sz = page_sz;
+        inner.file_page_sz = page_sz + inner.file_header_len;
+        Ok(())
+    }
+
     pub fn file_page_sz(&self) -> u32 {
         let inner = self.inner.read();
         inner.file_page_sz
@@ -86,6 +102,16 @@ impl State {
         inner.max_ver
     }
 
+    /// Chunk size in which the active data files are preallocated.
+    /// This is a runtime setting and is not persisted.
+    pub fn prealloc_sz(&self) -> u64 {
+        self.prealloc_sz.load(Ordering::Relaxed)
+    }
+
+    pub fn set_prealloc_sz(&self, sz: u64) {
+        self.prealloc_sz.store(sz, Ordering::Relaxed)
+    }
+
     pub fn advance_ver(&self) -> u32 {
         let mut inner = self.inner.write();
         inner.active_ver += 1;
@@ -94,6 +120,14 @@ impl State {
         inner.active_ver
     }
 
+    /// Takes over the persisted fields of `other`, e.g. a state reloaded
+    /// after another process committed. Clones of this state see the change.
+    pub fn update_from(&self, other: &State) {
+        let src = other.inner.read().clone();
+        let mut inner = self.inner.write();
+        *inner = src;
+    }
+
     pub fn serialize_to_path(&self, filepath: &std::path::Path) -> Result<(), Error> {
         let buf = rmp_serde::to_vec_named(&self)?;
 
@@ -1,19 +1,32 @@
+use std::io::Read;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 use crate::{Error, utils};
 use crate::state::State;
 use crate::bucket::Bucket;
-use crate::bmap::BucketMap;
+use crate::bmap::{BucketMap, file_stem};
+use crate::fcache::FileCache;
+use crate::pack::{PackSet, PackStats};
+use crate::dblock::{DbLock, LockLevel};
+use crate::shm::Shm;
 use crate::index::mem::MemIndex;
+use crate::txnlog::{TxnLog, TxnRecord};
 use parking_lot::RwLock;
 use fslock::LockFile;
 
+/// How long `Store::commit` waits for a write transaction to finish
+pub const DEFAULT_COMMIT_WAIT: Duration = Duration::from_secs(5);
+
 struct StoreInner {
     root_path: PathBuf,
     state: State,
     is_write: bool,
     bmap: BucketMap,
+    fcache: FileCache,
 }
+
+#[derive(Clone)]
 pub struct Store {
     inner: Arc<RwLock<StoreInner>>,
 }
@@ -25,6 +38,18 @@ impl Store {
     }
 
     pub fn open(&self, name: &str, mode: BucketOpenMode) -> Result<Bucket, Error> {
+        self.open_bucket(name, mode, None)
+    }
+
+    /// Opens bucket `name`, creating it with a page size of its own if it
+    /// does not exist. An existing bucket keeps the page size it was
+    /// created with. A page size of 0 is set later with
+    /// `set_bucket_page_size`.
+    pub fn open_with_page_size(&self, name: &str, mode: BucketOpenMode, page_sz: u32) -> Result<Bucket, Error> {
+        self.open_bucket(name, mode, Some(page_sz))
+    }
+
+    fn open_bucket(&self, name: &str, mode: BucketOpenMode, page_sz: Option<u32>) -> Result<Bucket, Error> {
         let mut inner = self.inner.write();
 
         log::debug!("store bucket open name={} mode writable={} store is write: {}", name, mode.is_write(), inner.is_write);
@@ -36,14 +61,19 @@ impl Store {
         let mut b = match inner.bmap.get(name) {
             Some(v) => {
                 log::debug!("Bucket name={} exists at ver={}", name, v);
-                Bucket::load(&inner.root_path, name, inner.state.clone(), inner.bmap.clone(), v)?
+                Bucket::load(&inner.root_path, name, inner.state.clone(), inner.bmap.clone(), inner.fcache.clone(), v)?
             },
             None => {
                 log::debug!("Bucket name={} does not exists", name);
                 if !inner.is_write {
                     return Err(Error::StoreNotWritableErr);
                 }
-                Bucket::new(&inner.root_path, name, inner.state.clone(), inner.bmap.clone())?
+                crate::bmap::check_name(name)?;
+                if inner.bmap.stem_taken(name) {
+                    return Err(Error::BucketNameErr(name.to_owned()));
+                }
+                inner.bmap.set_page_size(name, page_sz);
+                Bucket::new(&inner.root_path, name, inner.state.clone(), inner.bmap.clone(), inner.fcache.clone())?
             }
         };
 
@@ -60,20 +90,243 @@ impl Store {
         Ok(b)
     }
 
+    /// Moves a bucket opened before the last commit to the active version
+    pub fn roll_forward(&self, b: &mut Bucket) -> Result<bool, Error> {
+        let mut inner = self.inner.write();
+
+        if !b.roll_forward()? {
+            return Ok(false);
+        }
+
+        inner.sync_bmap()?;
+        Ok(true)
+    }
+
+    /// Versions which can be opened, oldest first
+    pub fn versions(&self) -> Vec<u32> {
+        let inner = self.inner.read();
+        (inner.state.min_ver()..=inner.state.active_ver()).collect()
+    }
+
+    /// Bucket map as of version `ver`
+    pub fn bucket_map(&self, ver: u32) -> Result<BucketMap, Error> {
+        let inner = self.inner.read();
+
+        if inner.is_write && ver == inner.state.active_ver() {
+            return Ok(inner.bmap.clone());
+        }
+
+        BucketMap::load(&inner.root_path, ver, &inner.fcache)
+    }
+
+    /// Index of bucket `name` as of version `ver`. Pages of the active
+    /// version are only seen once the bucket is synced.
+    pub fn get_index_at(&self, name: &str, ver: u32) -> Result<Option<MemIndex>, Error> {
+        let bmap = self.bucket_map(ver)?;
+        let inner = self.inner.read();
+
+        match bmap.get(name) {
+            Some(v) => {
+                let (_, _, index) = Bucket::load_index(&inner.root_path, name, v, &inner.fcache)?;
+                Ok(Some(index))
+            },
+            None => Ok(None),
+        }
+    }
+
+    /// Opens bucket `name` read-only as of the end of transaction `txn` of
+    /// version `ver`. The version must have been written with the txn log.
+    pub fn open_at_txn(&self, name: &str, ver: u32, txn: u32) -> Result<Bucket, Error> {
+        let inner = self.inner.read();
+        Bucket::load_at_txn(&inner.root_path, name, inner.state.clone(), inner.bmap.clone(), inner.fcache.clone(), ver, txn)
+    }
+
+    /// Transactions logged for bucket `name` in version `ver`
+    pub fn txns(&self, name: &str, ver: u32) -> Result<Vec<TxnRecord>, Error> {
+        let inner = self.inner.read();
+        TxnLog::load(&inner.root_path, name, ver, &inner.fcache)
+    }
+
     pub fn delete(&self, name: &str) -> Result<(), Error> {
         let mut inner = self.inner.write();
         let aver = inner.state.active_ver();
 
-        inner.bmap.delete(&inner.root_path, name, aver)?;
+        inner.bmap.delete(&inner.root_path, name, aver, &inner.fcache)?;
         inner.sync_bmap()
     }
 
+    /// Commits the store once no connection is writing to it, waiting up
+    /// to DEFAULT_COMMIT_WAIT for the current write transaction to finish
     pub fn commit(&self) -> Result<u32, Error> {
+        let mut dblock = self.db_lock()?;
+        self.commit_txn(&mut dblock, DEFAULT_COMMIT_WAIT)
+    }
+
+    /// Commits at a transaction boundary so that every version is a
+    /// consistent database. Holds the reserved lock through `dblock` and
+    /// the wal write lock while committing, and refuses to commit when a
+    /// hot journal or a non empty wal exists. The writers of the other dbs
+    /// of the store are locked out the same way, so the version is
+    /// consistent across all of them.
+    pub fn commit_txn(&self, dblock: &mut DbLock, timeout: Duration) -> Result<u32, Error> {
+        if !self.inner.read().is_write {
+            return Err(Error::StoreNotWritableErr);
+        }
+
+        let prev = dblock.level();
+        if prev > LockLevel::Shared {
+            return Err(Error::TxnOpenErr);
+        }
+
+        let mut others = self.other_db_locks(dblock)?;
+        let res = self.lock_writers(dblock, &mut others, timeout).and_then(|_shms| {
+            self.reload()?;
+            self.check_txn_boundary()?;
+            self.advance()
+        });
+
+        dblock.unlock(prev)?;
+        res
+    }
+
+    // Locks of the dbs of the store other than the one of `dblock`
+    fn other_db_locks(&self, dblock: &DbLock) -> Result<Vec<DbLock>, Error> {
+        let root_path = self.inner.read().root_path.clone();
+        let mut locks = Vec::new();
+
+        for entry in std::fs::read_dir(&root_path)? {
+            let entry = entry?;
+            let file_name = entry.file_name();
+            let file_name = match file_name.to_str() {
+                Some(s) => s,
+                None => continue,
+            };
+
+            let is_lock = file_name == "mojo.dblock" || file_name.starts_with("mojo.dblock-");
+            if is_lock && Some(entry.file_name().as_os_str()) != dblock.path().file_name() {
+                locks.push(DbLock::open_file(&root_path, file_name)?);
+            }
+        }
+
+        Ok(locks)
+    }
+
+    // Takes the reserved lock, which rollback journal writers hold, and the
+    // wal write lock of each db in wal mode
+    fn lock_writers(&self, dblock: &mut DbLock, others: &mut [DbLock], timeout: Duration) -> Result<Vec<Shm>, Error> {
+        let prev = dblock.level();
+        let start = Instant::now();
+
+        loop {
+            if let Some(shms) = Self::try_lock_writers(std::iter::once(&mut *dblock).chain(others.iter_mut()))? {
+                return Ok(shms);
+            }
+
+            dblock.unlock(prev)?;
+            for l in others.iter_mut() {
+                l.unlock(LockLevel::None)?;
+            }
+
+            if start.elapsed() >= timeout {
+                return Err(Error::DbBusyErr);
+            }
+
+            log::debug!("waiting for the writer to finish the transaction");
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    // Returns None if a writer holds any of the locks
+    fn try_lock_writers<'a>(locks: impl Iterator<Item=&'a mut DbLock>) -> Result<Option<Vec<Shm>>, Error> {
+        let mut shms = Vec::new();
+
+        for l in locks {
+            if !(l.lock(LockLevel::Shared)? && l.lock(LockLevel::Reserved)?) {
+                return Ok(None);
+            }
+
+            // The shm of a db is named after its lock file
+            let suffix = l.path().file_name()
+                .and_then(|n| n.to_str())
+                .and_then(|n| n.strip_prefix("mojo.dblock"))
+                .unwrap_or_default();
+            let shm_path = l.path().with_file_name(format!("mojo.shm{}", suffix));
+            if !shm_path.exists() {
+                continue;
+            }
+
+            let mut shm = Shm::open(&shm_path)?;
+            if !shm.lock(0, 1, true)? {
+                return Ok(None);
+            }
+            shms.push(shm);
+        }
+
+        Ok(Some(shms))
+    }
+
+    // A journal which is not zeroed while nobody holds the reserved lock is
+    // hot, sqlite rolls it back on the next open. Frames in the wal are not
+    // in the db yet.
+    fn check_txn_boundary(&self) -> Result<(), Error> {
+        let inner = self.inner.read();
+        let root_path = &inner.root_path;
+
+        // Buckets created by other processes are only in the bmap on disk
+        let bmap = BucketMap::load(root_path, inner.state.active_ver(), &inner.fcache)?;
+
+        for (name, ver) in bmap.map()? {
+            let is_journal = name.ends_with("-journal");
+            let is_wal = name.ends_with("-wal");
+            if !is_journal && !is_wal {
+                continue;
+            }
+
+            let mut hdr = [0u8; 8];
+            let size = Bucket::peek(root_path, &name, ver, &inner.state, &bmap, &inner.fcache, &mut hdr)?;
+            if size == 0 {
+                continue;
+            }
+
+            if is_wal {
+                return Err(Error::WalNotEmptyErr(name));
+            }
+
+            if hdr != [0u8; 8] {
+                return Err(Error::HotJournalErr(name));
+            }
+        }
+
+        // Native files live next to the store directory
+        let native = |suffix: &str| {
+            let mut p = root_path.clone().into_os_string();
+            p.push(suffix);
+            PathBuf::from(p)
+        };
+
+        let journal = native("-journal");
+        if let Ok(mut f) = std::fs::File::open(&journal) {
+            let mut hdr = [0u8; 8];
+            if f.read_exact(&mut hdr).is_ok() && hdr != [0u8; 8] {
+                return Err(Error::HotJournalErr(journal.display().to_string()));
+            }
+        }
+
+        let wal = native("-wal");
+        if wal.metadata().map(|m| m.len() > 0).unwrap_or(false) {
+            return Err(Error::WalNotEmptyErr(wal.display().to_string()));
+        }
+
+        Ok(())
+    }
+
+    fn advance(&self) -> Result<u32, Error> {
         let mut inner = self.inner.write();
 
         log::debug!("committing store ver={}", inner.state.active_ver());
 
-        let _ = inner.state.commit_lock.write();
+        let commit_lock = inner.state.commit_lock.clone();
+        let _commit_guard = commit_lock.write();
 
         log::debug!("about to acquire commit file lock ver={}", inner.state.active_ver());
         let mut commit_lock_file = Self::create_lock_file(&inner.root_path)?;
@@ -90,11 +343,216 @@ impl Store {
         Ok(new_ver)
     }
 
+    /// Picks up a commit made by another process. The buckets opened
+    /// before move to the new version on their next write. Returns true if
+    /// the active version changed.
+    pub fn refresh(&self) -> Result<bool, Error> {
+        let inner = self.inner.read();
+
+        if !inner.is_write {
+            return Ok(false);
+        }
+
+        let state = Self::load_state(&inner.root_path)?;
+        if state.active_ver() <= inner.state.active_ver() {
+            return Ok(false);
+        }
+
+        log::debug!("refreshing store ver={} to ver={}", inner.state.active_ver(), state.active_ver());
+        let bmap = BucketMap::load(&inner.root_path, state.active_ver(), &inner.fcache)?;
+        inner.bmap.update_from(&bmap);
+        inner.state.update_from(&state);
+
+        Ok(true)
+    }
+
+    // Replaces the state and bmap with the ones on disk. Other processes
+    // create buckets without changing the active version, so the bmap
+    // written by a commit has to start from the one on disk.
+    fn reload(&self) -> Result<(), Error> {
+        let inner = self.inner.read();
+
+        let state = Self::load_state(&inner.root_path)?;
+        let bmap = BucketMap::load(&inner.root_path, state.active_ver(), &inner.fcache)?;
+        log::debug!("reloading store ver={} disk ver={}", inner.state.active_ver(), state.active_ver());
+
+        inner.bmap.update_from(&bmap);
+        inner.state.update_from(&state);
+        Ok(())
+    }
+
+    /// Moves the data, index and bmap files of all the committed versions
+    /// into a new pack file. The active version is never packed.
+    pub fn pack(&self) -> Result<PackStats, Error> {
+        let inner = self.inner.read();
+
+        if !inner.is_write {
+            return Err(Error::StoreNotWritableErr);
+        }
+
+        let mut commit_lock_file = Self::create_lock_file(&inner.root_path)?;
+        if !commit_lock_file.try_lock_with_pid()? {
+            return Err(Error::CommitLockedErr);
+        }
+
+        let aver = inner.state.active_ver();
+        let mut files = Vec::new();
+
+        for entry in std::fs::read_dir(&inner.root_path)? {
+            let entry = entry?;
+            let file_name = entry.file_name();
+            let ver = match file_name.to_str().and_then(Self::packable_ver) {
+                Some(v) => v,
+                None => continue,
+            };
+
+            if ver < aver {
+                files.push((ver, entry.path()));
+            }
+        }
+
+        files.sort();
+        log::debug!("packing {} files below ver={}", files.len(), aver);
+
+        let stats = PackSet::write_pack(&inner.root_path, &files)?;
+        inner.fcache.clear();
+
+        Ok(stats)
+    }
+
+    fn packable_ver(file_name: &str) -> Option<u32> {
+        let (prefix, ver) = file_name.rsplit_once('.')?;
+        if prefix != "mojo.bmap" && !prefix.ends_with("_d") && !prefix.ends_with("_i") && !prefix.ends_with("_t") {
+            return None;
+        }
+        ver.parse().ok()
+    }
+
+    /// Opens a new connection level database lock for the store
+    pub fn db_lock(&self) -> Result<DbLock, Error> {
+        self.db_lock_of(None)
+    }
+
+    /// Opens a new connection level lock for db `db` of the store, None for
+    /// the db of a store opened without a db name
+    pub fn db_lock_of(&self, db: Option<&str>) -> Result<DbLock, Error> {
+        let inner = self.inner.read();
+        DbLock::open_file(&inner.root_path, &Self::db_file("mojo.dblock", db))
+    }
+
+    /// Opens the shared memory used by sqlite's wal mode for version `ver`.
+    /// Connections to the active version share `mojo.shm`.
+    pub fn shm(&self, ver: u32) -> Result<Shm, Error> {
+        self.shm_of(None, ver)
+    }
+
+    /// Opens the shared memory of db `db` for version `ver`, see `db_lock_of`
+    pub fn shm_of(&self, db: Option<&str>, ver: u32) -> Result<Shm, Error> {
+        let inner = self.inner.read();
+        let file_name = Self::db_file("mojo.shm", db);
+        let file_name = if ver >= inner.state.active_ver() {
+            file_name
+        }else{
+            format!("{}.{}", file_name, ver)
+        };
+        Shm::open(&inner.root_path.join(file_name))
+    }
+
+    // Files of a named db get the name as suffix
+    fn db_file(prefix: &str, db: Option<&str>) -> String {
+        match db {
+            Some(db) => format!("{}-{}", prefix, file_stem(db)),
+            None => prefix.to_owned(),
+        }
+    }
+
+    /// Page size of the store, zero if it is not known yet
+    pub fn page_size(&self) -> u32 {
+        let inner = self.inner.read();
+        inner.state.page_size()
+    }
+
+    /// Records the page size of a store created without one. Fails if the
+    /// store already has a different page size.
+    pub fn set_page_size(&self, page_sz: u32) -> Result<(), Error> {
+        let mut inner = self.inner.write();
+
+        if inner.state.page_size() == page_sz {
+            return Ok(());
+        }
+
+        if !inner.is_write {
+            return Err(Error::StoreNotWritableErr);
+        }
+
+        log::debug!("setting store page size={}", page_sz);
+        inner.state.set_page_size(page_sz)?;
+        inner.sync_state()
+    }
+
+    /// Records the page size of bucket `name`, created with a page size of
+    /// its own not known yet. Fails if the bucket already has a different
+    /// page size.
+    pub fn set_bucket_page_size(&self, name: &str, page_sz: u32) -> Result<(), Error> {
+        let mut inner = self.inner.write();
+
+        match inner.bmap.page_size(name) {
+            Some(sz) if sz == page_sz => return Ok(()),
+            Some(0) => {},
+            Some(sz) => return Err(Error::PageSizeMismatchErr(sz, page_sz)),
+            None => return Err(Error::PageSizeMismatchErr(inner.state.page_size(), page_sz)),
+        }
+
+        if !inner.is_write {
+            return Err(Error::StoreNotWritableErr);
+        }
+
+        log::debug!("setting bucket={} page size={}", name, page_sz);
+        inner.bmap.set_page_size(name, Some(page_sz));
+        inner.sync_bmap()
+    }
+
     pub fn active_ver(&self) -> u32 {
         let inner = self.inner.read();
         inner.state.active_ver()
     }
 
+    /// Sets the maximum number of read-only version files kept open across
+    /// all the buckets of the store. Active version files are not counted.
+    pub fn set_fd_limit(&self, limit: usize) {
+        let inner = self.inner.read();
+        inner.fcache.set_limit(limit);
+    }
+
+    pub fn fd_limit(&self) -> usize {
+        let inner = self.inner.read();
+        inner.fcache.limit()
+    }
+
+    /// Sets the chunk size in which the data file of the active version is
+    /// preallocated. Zero disables preallocation.
+    pub fn set_prealloc_sz(&self, sz: u64) {
+        let inner = self.inner.read();
+        inner.state.set_prealloc_sz(sz);
+    }
+
+    pub fn file_cache(&self) -> FileCache {
+        let inner = self.inner.read();
+        inner.fcache.clone()
+    }
+
+    /// Shares the cache of read-only version files with another store of
+    /// the same root. Buckets opened before keep the old cache.
+    pub fn set_file_cache(&self, fcache: FileCache) {
+        let mut inner = self.inner.write();
+        inner.fcache = fcache;
+    }
+
+    pub fn cached_files(&self) -> usize {
+        let inner = self.inner.read();
+        inner.fcache.len()
+    }
+
     pub fn load_state(rootpath: &Path) -> Result<State, Error> {
         let state_path = rootpath.join("mojo.state");
         log::debug!("loading state from {:?}", state_path);
@@ -105,14 +563,18 @@ impl Store {
     pub fn readonly(root_path: &Path, ver: u32) -> Result<Self, Error> {
         log::debug!("opening store in readonly mode at ver={}", ver);
         let state = Self::load_state(root_path)?;
+        if ver < state.min_ver() || ver > state.active_ver() {
+            return Err(Error::VersionNotFoundErr(ver));
+        }
         Self::load_store(root_path, state, ver)
     }
 
     pub fn writable(rootpath: &Path, create: bool, page_sz: Option<u32>, pps: Option<u32>) -> Result<Store, Error> {
         let init_path = rootpath.join("mojo.init");
 
-        if create && (page_sz.is_none() || pps.is_none()) {
-            log::debug!("Missing mandatory params page_sz:{:?} pps:{:?}", page_sz, pps);
+        // Without a page size the store learns it later, see set_page_size
+        if create && pps.is_none() {
+            log::debug!("Missing mandatory params pps:{:?}", pps);
             return Err(Error::MissingArgsErr);
         }
 
@@ -122,7 +584,7 @@ impl Store {
             }
 
             log::debug!("Store does not exists. Initing now");
-            let mut store = Store::new(rootpath, page_sz.unwrap(), pps.unwrap())?;
+            let mut store = Store::new(rootpath, page_sz.unwrap_or(0), pps.unwrap())?;
             store.init()?;
             log::debug!("Store init successfull");
             store
@@ -136,19 +598,25 @@ impl Store {
             let mut inner = store.inner.write();
             inner.is_write = true;
         }
+
+        if let Some(page_sz) = page_sz {
+            store.set_page_size(page_sz)?;
+        }
                
         Ok(store)
     }
 
     fn load_store(root_path: &Path, state: State, ver: u32) -> Result<Store, Error> {
         log::debug!("loading store at ver={}", ver);
-        let bmap = BucketMap::load(root_path, ver)?;
+        let fcache = FileCache::default();
+        let bmap = BucketMap::load(root_path, ver, &fcache)?;
 
         let inner = StoreInner {
             root_path: root_path.to_owned(),
             state,
             is_write: false,
             bmap,
+            fcache,
         };
 
         let store = Store {inner: Arc::new(RwLock::new(inner))};
@@ -161,12 +629,12 @@ impl Store {
 
         match inner.bmap.get(name) {
             Some(v) => {
-                let ret = Bucket::load_index(&inner.root_path, name, v)?;
+                let ret = Bucket::load_index(&inner.root_path, name, v, &inner.fcache)?;
                 Ok(Some(ret))
             },
             None => {
                 log::debug!("Bucket name={} does not exists", name);
-                return Ok(None)
+                Ok(None)
             }
         }
     }
@@ -179,6 +647,7 @@ impl Store {
             state,
             is_write: false,
             bmap: BucketMap::default(),
+            fcache: FileCache::default(),
         };
 
         let store = Store {
@@ -216,7 +685,7 @@ impl StoreInner {
     fn sync_bmap(&mut self) -> Result<(), Error> {
         log::debug!("syncing bmap at ver={}", self.state.active_ver());
 
-        let bmap_path = self.root_path.join(&format!("mojo.bmap.{}", self.state.active_ver()));
+        let bmap_path = self.root_path.join(format!("mojo.bmap.{}", self.state.active_ver()));
 
         self.bmap.serialize_to_path(&bmap_path)?;
 
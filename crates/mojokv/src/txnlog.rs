@@ -0,0 +1,174 @@
+use std::collections::BTreeSet;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use serde::{Serialize, Deserialize};
+use crate::Error;
+use crate::fcache::FileCache;
+
+/// Pages written by one write transaction inside a version. `pages` holds
+/// the block of every key touched by the transaction as it was at its end.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TxnRecord {
+    pub seq: u32,
+    pub time: u64,
+    pub max_key: isize,
+    pub pages: Vec<(u32, u32)>,
+    // Byte length of the bucket, None in logs written before it was kept
+    #[serde(default)]
+    pub size: Option<u64>,
+}
+
+// Log of the transactions of a bucket in one version, `<name>_t.<ver>`.
+// Each record is a little endian u32 length followed by the rmp encoded
+// TxnRecord. Record 0 is written when the log is created and holds the
+// pages of the version written before logging was turned on.
+pub struct TxnLog {
+    path: PathBuf,
+    // Last record and the length of the log as of the last read or append
+    seq: Option<u32>,
+    len: u64,
+    touched: BTreeSet<u32>,
+    truncated: bool,
+}
+
+impl TxnLog {
+    pub fn log_path(root_path: &Path, name: &str, ver: u32) -> PathBuf {
+        root_path.join(format!("{}_t.{}", crate::bmap::file_stem(name), ver))
+    }
+
+    /// Opens the log of the active version. Returns true if it has no
+    /// records yet.
+    pub fn open(root_path: &Path, name: &str, ver: u32) -> Result<(Self, bool), Error> {
+        let path = Self::log_path(root_path, name, ver);
+
+        let mut log = TxnLog {
+            path,
+            seq: None,
+            len: 0,
+            touched: BTreeSet::new(),
+            truncated: false,
+        };
+        log.reload()?;
+
+        log::debug!("txn log={:?} opened at seq={:?}", log.path, log.seq);
+        let created = log.seq.is_none();
+        Ok((log, created))
+    }
+
+    /// Sequence number of the last transaction in the log
+    pub fn seq(&self) -> Option<u32> {
+        self.seq
+    }
+
+    // Picks up the records appended by other connections
+    fn reload(&mut self) -> Result<(), Error> {
+        let file_len = match std::fs::metadata(&self.path) {
+            Ok(m) => m.len(),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => 0,
+            Err(err) => return Err(err.into()),
+        };
+
+        if file_len == self.len {
+            return Ok(());
+        }
+
+        let mut buf = Vec::new();
+        crate::utils::load_file(&self.path, &mut buf)?;
+        let (records, len) = Self::parse(&self.path, &buf)?;
+
+        self.seq = records.last().map(|r| r.seq);
+        self.len = len as u64;
+        Ok(())
+    }
+
+    pub fn is_touched(&self, key: u32) -> bool {
+        self.touched.contains(&key)
+    }
+
+    pub fn touch(&mut self, key: u32) {
+        self.touched.insert(key);
+    }
+
+    pub fn set_truncated(&mut self) {
+        self.truncated = true;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.touched.is_empty() && !self.truncated
+    }
+
+    /// Keys touched by the current transaction
+    pub fn touched(&self) -> impl Iterator<Item=u32> + '_ {
+        self.touched.iter().copied()
+    }
+
+    /// Appends the record of the current transaction and starts the next.
+    /// Returns its sequence number.
+    pub fn append(&mut self, max_key: isize, size: Option<u64>, pages: Vec<(u32, u32)>) -> Result<u32, Error> {
+        self.reload()?;
+        let seq = self.seq.map(|s| s + 1).unwrap_or(0);
+
+        let time = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        let rec = TxnRecord { seq, time, max_key, pages, size };
+
+        let buf = rmp_serde::to_vec(&rec)?;
+        let mut f = std::fs::OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(&self.path)?;
+
+        // Drop a record cut short by a crash
+        if f.metadata()?.len() > self.len {
+            log::debug!("txn log={:?} truncating partial record at off={}", self.path, self.len);
+            f.set_len(self.len)?;
+        }
+
+        f.write_all(&(buf.len() as u32).to_le_bytes())?;
+        f.write_all(&buf)?;
+        f.sync_data()?;
+
+        log::debug!("txn log={:?} appended seq={} pages={}", self.path, seq, rec.pages.len());
+        self.seq = Some(seq);
+        self.len += 4 + buf.len() as u64;
+        self.touched.clear();
+        self.truncated = false;
+        Ok(seq)
+    }
+
+    pub fn load(root_path: &Path, name: &str, ver: u32, fcache: &FileCache) -> Result<Vec<TxnRecord>, Error> {
+        let path = Self::log_path(root_path, name, ver);
+        let mut buf = Vec::new();
+        match fcache.load_file(&path, &mut buf) {
+            Err(err) if err.is_not_found() => return Ok(Vec::new()),
+            ret => ret?,
+        }
+
+        let (records, _) = Self::parse(&path, &buf)?;
+        Ok(records)
+    }
+
+    // Returns the records and the length they take. A record cut short by
+    // a crash is ignored, its transaction was not logged.
+    fn parse(path: &Path, buf: &[u8]) -> Result<(Vec<TxnRecord>, usize), Error> {
+        let mut records = Vec::new();
+        let mut off = 0;
+        while off < buf.len() {
+            if off + 4 > buf.len() {
+                log::debug!("txn log={:?} has a partial record at off={}", path, off);
+                break;
+            }
+
+            let len = u32::from_le_bytes([buf[off], buf[off+1], buf[off+2], buf[off+3]]) as usize;
+            if off + 4 + len > buf.len() {
+                log::debug!("txn log={:?} has a partial record at off={}", path, off);
+                break;
+            }
+
+            records.push(rmp_serde::from_slice(&buf[off+4..off+4+len])?);
+            off += 4 + len;
+        }
+
+        Ok((records, off))
+    }
+}
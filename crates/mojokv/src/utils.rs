@@ -22,6 +22,12 @@ pub fn write_file(path: &Path, buf: &[u8]) -> Result<(), Error> {
     Ok(())
 }
 
+pub fn sync_dir(path: &Path) -> Result<(), Error> {
+    let d = std::fs::File::open(path)?;
+    d.sync_all()?;
+    Ok(())
+}
+
 pub fn touch_file(path: &Path) -> Result<(), Error> {
     log::debug!("creating init file: {:?}", path);
     let _ = std::fs::File::create(path)?;
@@ -18,6 +18,12 @@ impl std::fmt::Debug for Value {
     }
 }
 
+impl Default for Value {
+    fn default() -> Self {
+        Value::new()
+    }
+}
+
 impl Value {
     pub fn is_allocated(&self) -> bool {
         self.ver() > 0
@@ -41,7 +47,7 @@ impl Value {
     }
 
     pub fn get_ver(&self) -> u32 {
-        self.ver() as u32
+        self.ver()
     }
 
 }
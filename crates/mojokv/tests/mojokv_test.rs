@@ -0,0 +1,545 @@
+use std::path::Path;
+use anyhow::Error;
+use mojokv::{Store, BucketOpenMode, LockLevel};
+
+fn setup(name: &str) -> Result<std::path::PathBuf, Error> {
+    let path = Path::new("./testkv").join(name);
+    if let Err(err) = std::fs::remove_dir_all(&path) {
+        if err.kind() != std::io::ErrorKind::NotFound {
+            return Err(err.into());
+        }
+    }
+    Ok(path)
+}
+
+#[test]
+fn fd_cache_bounded() -> Result<(), Error> {
+    let _ = env_logger::try_init();
+    let path = setup("fd_cache_bounded")?;
+    let nvers = 6u32;
+    let pagesz = 8u32;
+
+    let st = Store::writable(&path, true, Some(pagesz), Some(16))?;
+    for ver in 1..=nvers {
+        let mut b = st.open("a", BucketOpenMode::Write)?;
+        b.put(ver, 0, &(ver as u64).to_be_bytes())?;
+        b.sync()?;
+        b.close()?;
+        st.commit()?;
+    }
+
+    let st = Store::readonly(&path, nvers)?;
+    st.set_fd_limit(2);
+    let b = st.open("a", BucketOpenMode::Read)?;
+
+    let mut buf = [0u8; 8];
+    for _ in 0..2 {
+        for ver in 1..=nvers {
+            b.get(ver, 0, &mut buf)?;
+            assert_eq!(ver as u64, u64::from_be_bytes(buf));
+            assert!(st.cached_files() <= 2);
+        }
+    }
+
+    Ok(())
+}
+
+#[test]
+fn read_through_pack() -> Result<(), Error> {
+    let _ = env_logger::try_init();
+    let path = setup("read_through_pack")?;
+    let nvers = 4u32;
+
+    let st = Store::writable(&path, true, Some(8), Some(16))?;
+    for ver in 1..=nvers {
+        let mut b = st.open("a", BucketOpenMode::Write)?;
+        b.put(ver, 0, &(ver as u64).to_be_bytes())?;
+        b.sync()?;
+        b.close()?;
+        st.commit()?;
+    }
+
+    let stats = st.pack()?;
+    assert!(stats.pack_path.is_some());
+    assert_eq!(stats.files, 3 * nvers as usize);
+    assert!(!path.join("a_d.1").exists());
+    assert!(!path.join("mojo.bmap.1").exists());
+
+    let mut buf = [0u8; 8];
+    for ver in 1..=nvers {
+        let st = Store::readonly(&path, ver)?;
+        let b = st.open("a", BucketOpenMode::Read)?;
+        for key in 1..=ver {
+            b.get(key, 0, &mut buf)?;
+            assert_eq!(key as u64, u64::from_be_bytes(buf));
+        }
+    }
+
+    Ok(())
+}
+
+#[test]
+fn read_stops_at_packed_file_end() -> Result<(), Error> {
+    let _ = env_logger::try_init();
+    let path = setup("read_stops_at_packed_file_end")?;
+    let nvers = 3u32;
+
+    let st = Store::writable(&path, true, Some(8), Some(16))?;
+    for ver in 1..=nvers {
+        let mut b = st.open("a", BucketOpenMode::Write)?;
+        b.put(ver, 0, &(ver as u64).to_be_bytes())?;
+        b.sync()?;
+        b.close()?;
+        st.commit()?;
+    }
+
+    let lens: Vec<u64> = (1..nvers)
+        .map(|ver| std::fs::metadata(path.join(format!("a_d.{}", ver))).map(|m| m.len()))
+        .collect::<Result<_, _>>()?;
+
+    let stats = st.pack()?;
+    assert!(stats.pack_path.is_some());
+
+    // Each version file is followed by the next one in the pack, reads
+    // past its end must not return bytes of the next file
+    let fcache = mojokv::FileCache::new(4);
+    for (ver, len) in (1..nvers).zip(lens) {
+        let file = fcache.get(&path.join(format!("a_d.{}", ver)))?;
+        let mut buf = vec![0u8; len as usize + 64];
+        assert_eq!(file.read_buf_at(0, &mut buf)?, len as usize);
+        assert_eq!(file.read_buf_at(len, &mut buf)?, 0);
+        if file.is_mapped() {
+            assert_eq!(file.slice(0, buf.len()).map(|s| s.len()), Some(len as usize));
+            assert!(file.page_ref(len - 4, 8).is_none());
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn prealloc_and_punch_hole() -> Result<(), Error> {
+    use std::os::unix::fs::MetadataExt;
+
+    let _ = env_logger::try_init();
+    let path = setup("prealloc_and_punch_hole")?;
+    let pagesz = 4096usize;
+    let npages = 16u32;
+    let prealloc = 1024 * 1024;
+
+    let st = Store::writable(&path, true, Some(pagesz as u32), Some(64))?;
+    st.set_prealloc_sz(prealloc);
+
+    let mut b = st.open("a", BucketOpenMode::Write)?;
+    let buf = vec![7u8; pagesz];
+    for key in 0..npages {
+        b.put(key, 0, &buf)?;
+    }
+    b.sync()?;
+
+    let data_path = path.join("a_d.1");
+    let md = std::fs::metadata(&data_path)?;
+    let written = npages as u64 * (pagesz as u64 + 8);
+    assert_eq!(md.len(), written);
+
+    // Some file systems have no fallocate or allocate lazily, the space
+    // checks only hold where the preallocation shows up
+    let allocated = md.blocks() * 512 >= prealloc;
+    if !allocated {
+        log::warn!("data file not preallocated blocks={}", md.blocks());
+    }
+
+    // The blocks are released only once the truncated index is synced
+    b.truncate(pagesz)?;
+    if allocated {
+        assert_eq!(std::fs::metadata(&data_path)?.blocks(), md.blocks());
+    }
+    b.sync()?;
+
+    let md_after = std::fs::metadata(&data_path)?;
+    assert_eq!(md_after.len(), written);
+    if allocated {
+        assert!(md_after.blocks() < md.blocks());
+    }
+
+    let mut out = vec![0u8; pagesz];
+    assert_eq!(b.get(0, 0, &mut out)?, pagesz);
+    assert_eq!(out, buf);
+
+    Ok(())
+}
+
+#[test]
+fn put_get_many() -> Result<(), Error> {
+    let _ = env_logger::try_init();
+    let path = setup("put_get_many")?;
+    let pagesz = 16usize;
+
+    let page = |key: u32, tag: u8| -> Vec<u8> {
+        let mut p = vec![tag; pagesz];
+        p[..4].copy_from_slice(&key.to_be_bytes());
+        p
+    };
+
+    let st = Store::writable(&path, true, Some(pagesz as u32), Some(4))?;
+    let mut b = st.open("a", BucketOpenMode::Write)?;
+
+    let v1: Vec<Vec<u8>> = (0..8).map(|k| page(k, 1)).collect();
+    b.put_many(0, &v1.iter().map(|p| p.as_slice()).collect::<Vec<_>>())?;
+
+    // In place rewrite of keys 2..8 and append of keys 8..10
+    let v1b: Vec<Vec<u8>> = (2..10).map(|k| page(k, 2)).collect();
+    b.put_many(2, &v1b.iter().map(|p| p.as_slice()).collect::<Vec<_>>())?;
+    b.sync()?;
+    b.close()?;
+    st.commit()?;
+
+    // Keys 4..6 are rewritten in ver=2, the rest come from ver=1
+    let mut b = st.open("a", BucketOpenMode::Write)?;
+    let v2: Vec<Vec<u8>> = (4..6).map(|k| page(k, 3)).collect();
+    b.put_many(4, &v2.iter().map(|p| p.as_slice()).collect::<Vec<_>>())?;
+
+    let mut out = vec![0u8; pagesz * 12];
+    let mut bufs: Vec<&mut [u8]> = out.chunks_mut(pagesz).collect();
+    let n = b.get_many(0, &mut bufs)?;
+    assert_eq!(n, pagesz * 10);
+
+    for (k, p) in out.chunks(pagesz).enumerate() {
+        let k = k as u32;
+        let expected = match k {
+            0..=1 => page(k, 1),
+            4..=5 => page(k, 3),
+            2..=9 => page(k, 2),
+            _ => vec![0u8; pagesz],
+        };
+        assert_eq!(p, expected.as_slice(), "key={}", k);
+    }
+
+    Ok(())
+}
+
+#[test]
+fn db_lock_conflicts() -> Result<(), Error> {
+    use mojokv::LockLevel;
+
+    let _ = env_logger::try_init();
+    let path = setup("db_lock_conflicts")?;
+
+    let st = Store::writable(&path, true, Some(8), Some(16))?;
+    let mut a = st.db_lock()?;
+    let mut b = st.db_lock()?;
+
+    assert!(a.lock(LockLevel::Shared)?);
+    assert!(b.lock(LockLevel::Shared)?);
+
+    // Only a single writer may reserve
+    assert!(a.lock(LockLevel::Reserved)?);
+    assert!(!b.lock(LockLevel::Reserved)?);
+    assert!(b.check_reserved()?);
+
+    // Exclusive waits in pending until the other reader goes away
+    assert!(!a.lock(LockLevel::Exclusive)?);
+    assert_eq!(a.level(), LockLevel::Pending);
+    b.unlock(LockLevel::None)?;
+    assert!(!b.lock(LockLevel::Shared)?);
+    assert!(a.lock(LockLevel::Exclusive)?);
+
+    a.unlock(LockLevel::Shared)?;
+    assert!(b.lock(LockLevel::Shared)?);
+    a.unlock(LockLevel::None)?;
+    b.unlock(LockLevel::None)?;
+    assert!(!a.check_reserved()?);
+
+    assert!(b.lock(LockLevel::Shared)?);
+    assert!(b.lock(LockLevel::Exclusive)?);
+
+    Ok(())
+}
+
+#[test]
+fn shm_map_and_lock() -> Result<(), Error> {
+    let _ = env_logger::try_init();
+    let path = setup("shm_map_and_lock")?;
+    let region_sz = 32 * 1024;
+
+    let st = Store::writable(&path, true, Some(8), Some(16))?;
+    let mut a = st.shm(st.active_ver())?;
+    let mut b = st.shm(st.active_ver())?;
+
+    assert!(b.map(0, region_sz, false)?.is_none());
+    let pa = a.map(1, region_sz, true)?.unwrap();
+    let pb = b.map(1, region_sz, false)?.unwrap();
+    unsafe {
+        *pa = 42;
+        assert_eq!(*pb, 42);
+    }
+
+    assert!(a.lock(0, 1, false)?);
+    assert!(b.lock(0, 1, false)?);
+    assert!(!b.lock(0, 1, true)?);
+    a.unlock(0, 1, false)?;
+    b.unlock(0, 1, false)?;
+
+    assert!(a.lock(1, 3, true)?);
+    assert!(!b.lock(2, 1, false)?);
+    a.unlock(1, 3, true)?;
+    assert!(b.lock(2, 1, false)?);
+
+    b.unmap(true)?;
+    assert!(path.join("mojo.shm").exists());
+    a.unmap(true)?;
+    assert!(!path.join("mojo.shm").exists());
+
+    Ok(())
+}
+
+#[test]
+fn exact_logical_size() -> Result<(), Error> {
+    let _ = env_logger::try_init();
+    let path = setup("exact_logical_size")?;
+    let pagesz = 16usize;
+
+    let st = Store::writable(&path, true, Some(pagesz as u32), Some(4))?;
+    let mut b = st.open("a", BucketOpenMode::Write)?;
+    assert_eq!(b.logical_size(), 0);
+
+    b.put(0, 0, &[1u8; 12])?;
+    assert_eq!(b.logical_size(), 12);
+    b.put(1, 2, &[2u8; 5])?;
+    assert_eq!(b.logical_size(), 23);
+
+    // A write inside the file does not change its length
+    b.put(0, 4, &[3u8; 4])?;
+    assert_eq!(b.logical_size(), 23);
+    b.sync()?;
+    b.close()?;
+
+    let mut b = st.open("a", BucketOpenMode::Write)?;
+    assert_eq!(b.logical_size(), 23);
+
+    // A partial last page is kept
+    b.truncate(19)?;
+    assert_eq!(b.logical_size(), 19);
+    assert_eq!(b.max_key(), 1);
+    b.put(2, 0, &[4u8; 1])?;
+    assert_eq!(b.logical_size(), 33);
+
+    // The bytes cut off by the truncate read as zeros
+    let mut page = vec![0u8; pagesz];
+    b.get(1, 0, &mut page)?;
+    assert!(page[3..].iter().all(|x| *x == 0));
+    b.sync()?;
+    b.close()?;
+    st.commit()?;
+
+    let st = Store::readonly(&path, 1)?;
+    let b = st.open("a", BucketOpenMode::Read)?;
+    assert_eq!(b.logical_size(), 33);
+
+    Ok(())
+}
+
+#[test]
+fn bucket_page_size() -> Result<(), Error> {
+    let _ = env_logger::try_init();
+    let path = setup("bucket_page_size")?;
+
+    let st = Store::writable(&path, true, Some(16), Some(4))?;
+    let mut b = st.open_with_page_size("log", BucketOpenMode::Write, 64)?;
+    assert_eq!(b.page_size(), 64);
+    b.put(0, 10, &[1u8; 50])?;
+    assert_eq!(b.logical_size(), 60);
+    b.sync()?;
+    b.close()?;
+
+    let b = st.open("a", BucketOpenMode::Write)?;
+    assert_eq!(b.page_size(), 16);
+    b.close()?;
+    st.commit()?;
+
+    // The page size is kept by the bucket map of later versions
+    let b = st.open("log", BucketOpenMode::Write)?;
+    assert_eq!(b.page_size(), 64);
+    b.close()?;
+
+    let st = Store::readonly(&path, 1)?;
+    assert_eq!(st.bucket_map(1)?.page_size("log"), Some(64));
+    assert_eq!(st.bucket_map(1)?.page_size("a"), None);
+
+    let b = st.open("log", BucketOpenMode::Read)?;
+    let mut out = vec![0u8; 64];
+    assert_eq!(b.get(0, 0, &mut out)?, 64);
+    assert_eq!(&out[..10], &[0u8; 10][..]);
+    assert_eq!(&out[10..60], &[1u8; 50][..]);
+
+    Ok(())
+}
+
+#[test]
+fn bucket_path_names() -> Result<(), Error> {
+    let _ = env_logger::try_init();
+    let path = setup("bucket_path_names")?;
+
+    let st = Store::writable(&path, true, Some(16), Some(4))?;
+    for name in ["/a", "a//b", "a/", "./a", "a/../b", ""] {
+        assert!(matches!(st.open(name, BucketOpenMode::Write), Err(mojokv::Error::BucketNameErr(_))), "{}", name);
+    }
+
+    // Files of nested names stay in the store directory
+    let mut b = st.open("x/a%b", BucketOpenMode::Write)?;
+    b.put(0, 0, &[1u8; 16])?;
+    b.sync()?;
+    b.close()?;
+    st.commit()?;
+
+    assert!(path.join("x%2Fa%25b_d.1").exists());
+    assert!(path.join("x%2Fa%25b_i.1").exists());
+    assert_eq!(st.bucket_map(1)?.get("x/a%b"), Some(1));
+
+    let b = st.open("x/a%b", BucketOpenMode::Read)?;
+    let mut out = [0u8; 16];
+    b.get(0, 0, &mut out)?;
+    assert_eq!(out, [1u8; 16]);
+
+    // Names which are not nested keep the stem they always had
+    let mut b = st.open("a%b", BucketOpenMode::Write)?;
+    b.sync()?;
+    b.close()?;
+    assert!(path.join("a%b_d.2").exists());
+
+    // A name cannot take the files of another
+    assert!(matches!(st.open("x%2Fa%25b", BucketOpenMode::Write), Err(mojokv::Error::BucketNameErr(_))));
+
+    Ok(())
+}
+
+#[test]
+fn delete_unchanged_bucket() -> Result<(), Error> {
+    let _ = env_logger::try_init();
+    let path = setup("delete_unchanged_bucket")?;
+
+    let st = Store::writable(&path, true, Some(16), Some(4))?;
+    let mut b = st.open("a", BucketOpenMode::Write)?;
+    b.put(0, 0, &[1u8; 16])?;
+    b.sync()?;
+    b.close()?;
+    st.commit()?;
+
+    // The bucket has no files at the active version, those of version 1
+    // belong to the committed version
+    st.delete("a")?;
+    assert_eq!(st.bucket_map(2)?.get("a"), None);
+    assert!(path.join("a_d.1").exists());
+    assert!(path.join("a_i.1").exists());
+
+    let st = Store::readonly(&path, 1)?;
+    let b = st.open("a", BucketOpenMode::Read)?;
+    let mut out = [0u8; 16];
+    b.get(0, 0, &mut out)?;
+    assert_eq!(out, [1u8; 16]);
+
+    Ok(())
+}
+
+#[test]
+fn commit_locks_every_db() -> Result<(), Error> {
+    let _ = env_logger::try_init();
+    let path = setup("commit_locks_every_db")?;
+
+    let st = Store::writable(&path, true, Some(16), Some(4))?;
+    let mut a = st.db_lock_of(Some("a"))?;
+    let mut b = st.db_lock_of(Some("b"))?;
+    assert!(a.lock(LockLevel::Shared)? && a.lock(LockLevel::Reserved)?);
+
+    // A writer of db a holds off a commit made through db b
+    let wait = std::time::Duration::from_millis(50);
+    assert!(matches!(st.commit_txn(&mut b, wait), Err(mojokv::Error::DbBusyErr)));
+
+    a.unlock(LockLevel::None)?;
+    assert_eq!(st.commit_txn(&mut b, wait)?, 2);
+    assert!(path.join("mojo.dblock-a").exists() && path.join("mojo.dblock-b").exists());
+
+    Ok(())
+}
+
+#[test]
+fn txn_boundary_check_reads_only() -> Result<(), Error> {
+    let _ = env_logger::try_init();
+    let path = setup("txn_boundary_check_reads_only")?;
+
+    let st = Store::writable(&path, true, Some(16), Some(4))?;
+    let mut j = st.open("a.db-journal", BucketOpenMode::Write)?;
+    j.put(0, 0, &[0u8; 16])?;
+    j.sync()?;
+    j.close()?;
+    assert_eq!(st.commit()?, 2);
+
+    // The journal bucket is only in version 1, checking it must not
+    // create a data file for it in the active version
+    assert_eq!(st.commit()?, 3);
+    assert!(path.join("a.db-journal_d.1").exists());
+    assert!(!path.join("a.db-journal_d.2").exists());
+    assert!(!path.join("a.db-journal_d.3").exists());
+
+    let mut j = st.open("a.db-journal", BucketOpenMode::Write)?;
+    j.put(0, 0, &[0xd9u8; 16])?;
+    j.sync()?;
+    j.close()?;
+    assert!(matches!(st.commit(), Err(mojokv::Error::HotJournalErr(_))));
+
+    Ok(())
+}
+
+// Store written to by `other_process_writer` when run as a child process
+const WRITER_STORE_ENV: &str = "MOJOKV_TEST_WRITER_STORE";
+
+#[test]
+fn other_process_writer() -> Result<(), Error> {
+    // Does nothing unless run by commit_keeps_other_process_buckets
+    let path = match std::env::var(WRITER_STORE_ENV) {
+        Ok(path) => path,
+        Err(_) => return Ok(()),
+    };
+
+    let st = Store::writable(Path::new(&path), false, None, None)?;
+    let mut b = st.open("b", BucketOpenMode::Write)?;
+    b.put(0, 0, &7u64.to_be_bytes())?;
+    b.sync()?;
+    b.close()?;
+
+    Ok(())
+}
+
+#[test]
+fn commit_keeps_other_process_buckets() -> Result<(), Error> {
+    let _ = env_logger::try_init();
+    let path = setup("commit_keeps_other_process_buckets")?;
+
+    let st = Store::writable(&path, true, Some(8), Some(16))?;
+    let mut a = st.open("a", BucketOpenMode::Write)?;
+    a.put(0, 0, &1u64.to_be_bytes())?;
+    a.sync()?;
+
+    // Bucket b is created by another process after the store was opened
+    let out = std::process::Command::new(std::env::current_exe()?)
+        .args(["--exact", "other_process_writer", "--test-threads=1"])
+        .env(WRITER_STORE_ENV, &path)
+        .output()?;
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stdout));
+
+    assert_eq!(st.commit()?, 2);
+    a.close()?;
+
+    let mut buf = [0u8; 8];
+    for ver in 1..=2 {
+        let ro = Store::readonly(&path, ver)?;
+        assert!(ro.exists("a") && ro.exists("b"));
+
+        let b = ro.open("b", BucketOpenMode::Read)?;
+        b.get(0, 0, &mut buf)?;
+        assert_eq!(u64::from_be_bytes(buf), 7);
+    }
+
+    Ok(())
+}
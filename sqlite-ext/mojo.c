@@ -16,39 +16,10 @@ SQLITE_EXTENSION_INIT1
 #include <mojofs.h>
 
 /*
-** The maximum pathname length supported by this VFS.
+** Registers the mojo_* virtual tables on every new connection.
 */
-#define MAXPATHNAME 512
-
-/*
-** Argument zPath points to a nul-terminated string containing a file path.
-** If zPath is an absolute path, then it is copied as is into the output 
-** buffer. Otherwise, if it is a relative path, then the equivalent full
-** path is written to the output buffer.
-**
-** This function assumes that paths are UNIX style. Specifically, that:
-**
-**   1. Path components are separated by a '/'. and 
-**   2. Full paths begin with a '/' character.
-*/
-static int mojo_fullpath_name(
-  sqlite3_vfs *pVfs,              /* VFS */
-  const char *zPath,              /* Input path (possibly a relative path) */
-  int nPathOut,                   /* Size of output buffer in bytes */
-  char *zPathOut                  /* Pointer to output buffer */
-){
-  char zDir[MAXPATHNAME+1];
-  if( zPath[0]=='/' ){
-    zDir[0] = '\0';
-  }else{
-    if( getcwd(zDir, sizeof(zDir))==0 ) return SQLITE_IOERR;
-  }
-  zDir[MAXPATHNAME] = '\0';
-
-  sqlite3_snprintf(nPathOut, zPathOut, "%s/%s", zDir, zPath);
-  zPathOut[nPathOut-1] = '\0';
-
-  return SQLITE_OK;
+static int mojo_vtab_init(sqlite3 *db, char **pzErrMsg, const sqlite3_api_routines *pApi){
+  return mojo_register_vtabs(db);
 }
 
 int sqlite3_mojo_init(sqlite3 *db, char **pzErrMsg, const sqlite3_api_routines *pApi){
@@ -57,9 +28,33 @@ int sqlite3_mojo_init(sqlite3 *db, char **pzErrMsg, const sqlite3_api_routines *
 
   mojofs_init_log();
 
+  /* The sqlite3_* names resolve to the routines of the host */
+  MojoApi api;
+  api.malloc64 = sqlite3_malloc64;
+  api.free = sqlite3_free;
+  api.create_module = sqlite3_create_module;
+  api.declare_vtab = sqlite3_declare_vtab;
+  api.result_null = sqlite3_result_null;
+  api.result_int64 = sqlite3_result_int64;
+  api.result_text = sqlite3_result_text;
+  api.value_type = sqlite3_value_type;
+  api.value_int64 = sqlite3_value_int64;
+  api.value_text = sqlite3_value_text;
+  api.file_control = sqlite3_file_control;
+  api.compileoption_used = sqlite3_compileoption_used;
+  rc = mojo_set_api(&api);
+  if( rc!=SQLITE_OK ){
+    return rc;
+  }
+
   sqlite3_vfs *vfs = mojo_create();
-  vfs->xFullPathname = mojo_fullpath_name;
   rc = sqlite3_vfs_register(vfs, 0);
+  if( rc==SQLITE_OK ){
+    rc = mojo_register_vtabs(db);
+  }
+  if( rc==SQLITE_OK ){
+    rc = sqlite3_auto_extension((void(*)(void))mojo_vtab_init);
+  }
   //printf("extension mojo called with rc=%d\n", rc);
   fflush(stdout);
   return rc;
@@ -8,7 +8,29 @@ typedef struct MojoFile {
     void* custom_file;
 } MojoFile;
 
+/*
+** Routines of the host sqlite used by mojofs
+*/
+typedef struct MojoApi {
+    void *(*malloc64)(sqlite3_uint64);
+    void (*free)(void*);
+    int (*create_module)(sqlite3*, const char*, const sqlite3_module*, void*);
+    int (*declare_vtab)(sqlite3*, const char*);
+    void (*result_null)(sqlite3_context*);
+    void (*result_int64)(sqlite3_context*, sqlite3_int64);
+    void (*result_text)(sqlite3_context*, const char*, int, void(*)(void*));
+    int (*value_type)(sqlite3_value*);
+    sqlite3_int64 (*value_int64)(sqlite3_value*);
+    const unsigned char *(*value_text)(sqlite3_value*);
+    int (*file_control)(sqlite3*, const char*, int, void*);
+    int (*compileoption_used)(const char*);
+} MojoApi;
+
+int mojo_set_api(const MojoApi *api);
+
 sqlite3_vfs* mojo_create();
 
 void mojofs_init_log();
+
+int mojo_register_vtabs(sqlite3 *db);
 #endif
\ No newline at end of file